@@ -0,0 +1,124 @@
+//! Detects mains voltage on the optocoupler sense inputs
+//!
+//! The optocouplers pull their input low while the mains voltage is high enough, so with AC present
+//! the inputs see a pulse train at the mains frequency (or a mostly low level, depending on the
+//! smoothing capacitor), and a constant high level without it.
+
+use std::time::Duration;
+
+/// Fraction of the samples that have to be low for AC to be present. The optocoupler conducts for
+/// roughly 40% of each period, so short glitches are ignored.
+const MIN_ACTIVE_RATIO: f32 = 0.1;
+
+/// Consecutive windows without AC before a supply is considered lost
+const SUPPLY_LOSS_WINDOWS: u32 = 2;
+
+/// Time AC has to be present before a lost supply is considered back, so a flaky feed doesn't
+/// switch phases back and forth
+const SUPPLY_RESTORE_DELAY: Duration = Duration::from_secs(30);
+
+/// Accumulates samples of a sense input over a window of at least a couple of mains periods
+#[derive(Default)]
+pub struct AcDetector {
+    samples: u32,
+    active: u32,
+}
+
+impl AcDetector {
+    pub fn sample(&mut self, high: bool) {
+        self.samples += 1;
+        if !high {
+            self.active += 1;
+        }
+    }
+
+    /// AC was present during the window
+    pub fn present(&self) -> bool {
+        self.samples > 0 && self.active as f32 >= self.samples as f32 * MIN_ACTIVE_RATIO
+    }
+}
+
+/// Tracks whether a supply is available from the result of successive `AcDetector` windows
+pub struct SupplyMonitor {
+    available: bool,
+    missed: u32,
+    present_since: Option<Duration>,
+}
+
+impl SupplyMonitor {
+    pub fn new(available: bool) -> Self {
+        Self {
+            available,
+            missed: 0,
+            present_since: None,
+        }
+    }
+
+    /// Accounts a window where AC was `present` or not. Returns whether availability changed.
+    pub fn update(&mut self, present: bool, now: Duration) -> bool {
+        let available = if present {
+            self.missed = 0;
+            let since = *self.present_since.get_or_insert(now);
+            self.available || now >= since + SUPPLY_RESTORE_DELAY
+        } else {
+            self.missed += 1;
+            self.present_since = None;
+            self.available && self.missed < SUPPLY_LOSS_WINDOWS
+        };
+        let changed = available != self.available;
+        self.available = available;
+        changed
+    }
+
+    pub fn available(&self) -> bool {
+        self.available
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Samples each ms for 3 periods of 50Hz
+    fn detect(input: impl Fn(u32) -> bool) -> bool {
+        let mut detector = AcDetector::default();
+        for ms in 0..60 {
+            detector.sample(input(ms));
+        }
+        detector.present()
+    }
+
+    #[test]
+    fn detects_pulse_trains() {
+        assert!(detect(|ms| ms % 20 >= 8));
+        // 60Hz
+        assert!(detect(|ms| (ms * 6 % 100) >= 40));
+        // Smoothed by the capacitor
+        assert!(detect(|_| false));
+    }
+
+    #[test]
+    fn ignores_glitches() {
+        assert!(!detect(|_| true));
+        assert!(!detect(|ms| ms != 13 && ms != 47));
+        assert!(!AcDetector::default().present());
+    }
+
+    #[test]
+    fn debounces_supply() {
+        let second = Duration::from_secs(1);
+        let mut monitor = SupplyMonitor::new(true);
+        assert!(!monitor.update(false, second));
+        assert!(!monitor.update(true, second * 2));
+        assert!(!monitor.update(false, second * 3));
+        assert!(monitor.update(false, second * 4));
+        assert!(!monitor.available());
+
+        assert!(!monitor.update(true, second * 5));
+        assert!(!monitor.update(false, second * 6));
+        assert!(!monitor.update(true, second * 7));
+        assert!(!monitor.update(true, second * 36));
+        assert!(monitor.update(true, second * 37));
+        assert!(monitor.available());
+    }
+}
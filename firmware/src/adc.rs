@@ -1,5 +1,8 @@
 use enum_map::Enum;
 
+/// Samples per second of each channel
+pub const SAMPLE_RATE_HZ: u32 = 10000;
+
 #[derive(Debug, Enum, PartialEq)]
 pub enum AdcChannel {
     CurrentL1,
@@ -0,0 +1,148 @@
+//! Detects how many phases the car draws current from
+//!
+//! Many cars have 1 or 2 phase onboard chargers, so with 3 phases the current is only drawn from
+//! some of them. A phase is loaded if it draws a good part of the most loaded one for a while. Loaded
+//! phases drawing very different currents point to a wiring problem and are flagged.
+
+use std::time::Duration;
+
+/// Current of the most loaded phase to tell which phases are loaded (mA)
+const MIN_DETECT_CURRENT: u32 = 3000;
+
+/// Part of the most loaded phase for another phase to count as loaded
+const LOADED_RATIO: f32 = 0.5;
+
+/// Time the same phases have to be loaded
+const DETECT_TIME: Duration = Duration::from_secs(10);
+
+/// Difference between loaded phases to flag an imbalance, relative to the most loaded one
+const IMBALANCE_RATIO: f32 = 0.2;
+
+/// Time the imbalance has to last
+const IMBALANCE_TIME: Duration = Duration::from_secs(30);
+
+#[derive(Default)]
+pub struct CarPhaseDetector {
+    phases: Option<u8>,
+    /// Phases loaded and since when
+    candidate: Option<(u8, Duration)>,
+    imbalance_since: Option<Duration>,
+    imbalance: bool,
+}
+
+impl CarPhaseDetector {
+    /// Accounts the current (mA) of each phase while charging, with `three_phase` contactors.
+    /// Returns whether the detected phases changed.
+    pub fn update(&mut self, currents: [u32; 3], three_phase: bool, now: Duration) -> bool {
+        let max = currents.into_iter().max().unwrap_or(0);
+        if !three_phase || max < MIN_DETECT_CURRENT {
+            // Nothing to tell with 1 phase or while ramping up
+            self.candidate = None;
+            self.imbalance_since = None;
+            return false;
+        }
+
+        let loaded = currents.map(|mamps| mamps as f32 >= max as f32 * LOADED_RATIO);
+        let min = currents
+            .into_iter()
+            .zip(loaded)
+            .filter_map(|(mamps, loaded)| loaded.then_some(mamps))
+            .min()
+            .unwrap_or(max);
+        if (max - min) as f32 > max as f32 * IMBALANCE_RATIO {
+            let since = *self.imbalance_since.get_or_insert(now);
+            if now >= since + IMBALANCE_TIME && !self.imbalance {
+                log::warn!("Phase imbalance drawing {currents:?} mA, check the wiring");
+                self.imbalance = true;
+            }
+        } else {
+            self.imbalance_since = None;
+        }
+
+        let phases = loaded.iter().filter(|&&l| l).count() as u8;
+        match self.candidate {
+            Some((candidate, since)) if candidate == phases => {
+                if now >= since + DETECT_TIME && self.phases != Some(phases) {
+                    self.phases = Some(phases);
+                    return true;
+                }
+            }
+            _ => self.candidate = Some((phases, now)),
+        }
+        false
+    }
+
+    /// Phases the car draws current from, once detected
+    pub fn phases(&self) -> Option<u8> {
+        self.phases
+    }
+
+    /// Loaded phases drew very different currents, stays set until reset
+    pub fn imbalance(&self) -> bool {
+        self.imbalance
+    }
+
+    /// Forgets the car, e.g: when unplugged
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    /// Feeds `currents` every second for `seconds`, returning the detected phases
+    fn run(
+        detector: &mut CarPhaseDetector,
+        currents: [u32; 3],
+        from: u64,
+        seconds: u64,
+    ) -> Option<u8> {
+        for t in from..from + seconds {
+            detector.update(currents, true, SECOND * t as u32);
+        }
+        detector.phases()
+    }
+
+    #[test]
+    fn detects_phases() {
+        let mut d = CarPhaseDetector::default();
+        assert_eq!(run(&mut d, [16000, 150, 120], 0, 5), None);
+        assert_eq!(run(&mut d, [16000, 150, 120], 5, 10), Some(1));
+
+        d.reset();
+        assert_eq!(run(&mut d, [10000, 9800, 0], 0, 15), Some(2));
+        assert_eq!(run(&mut d, [10000, 9800, 9900], 15, 15), Some(3));
+        assert!(!d.imbalance());
+    }
+
+    #[test]
+    fn needs_current() {
+        let mut d = CarPhaseDetector::default();
+        // Ramping up
+        assert_eq!(run(&mut d, [2000, 0, 0], 0, 30), None);
+        // With 1 phase every car looks like a 1 phase car
+        for t in 30..60 {
+            d.update([16000, 0, 0], false, SECOND * t);
+        }
+        assert_eq!(d.phases(), None);
+    }
+
+    #[test]
+    fn flags_imbalance() {
+        let mut d = CarPhaseDetector::default();
+        assert_eq!(run(&mut d, [16000, 11000, 16000], 0, 20), Some(3));
+        assert!(!d.imbalance());
+        run(&mut d, [16000, 11000, 16000], 20, 20);
+        assert!(d.imbalance());
+
+        // Until the car is unplugged
+        run(&mut d, [16000, 16000, 16000], 40, 60);
+        assert!(d.imbalance());
+        d.reset();
+        assert!(!d.imbalance());
+    }
+}
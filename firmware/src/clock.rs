@@ -0,0 +1,42 @@
+use std::{
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// Source of time for the controller, so it can run on virtual time in tests
+pub trait Clock {
+    /// Monotonic time since an arbitrary point (e.g: boot)
+    fn now(&self) -> Duration;
+    /// Seconds since the Unix epoch. Only meaningful once the time has been synced (e.g: NTP).
+    fn unix_time(&self) -> u64;
+    fn sleep(&self, duration: Duration);
+}
+
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    fn unix_time(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration)
+    }
+}
@@ -1,5 +1,12 @@
-use esp_idf_svc::nvs::{EspDefaultNvs, EspDefaultNvsPartition};
+use std::time::Duration;
+
+use esp_idf_svc::nvs::EspDefaultNvs;
 use esp_idf_sys::EspError;
+use phievse::driver::storage::nvs_partition;
+use phievse::settings::{
+    ControllerSettings, CurrentCalibration, DiodeCheck, DutyMapping, InstallationLimits,
+    MIN_LIMIT_CURRENT, MainsFrequency, PhaseCalibration, PilotDuty, PilotThresholds, VOLTAGE_RANGE,
+};
 
 #[derive(Debug)]
 pub struct PhiEvseConfig {
@@ -7,6 +14,7 @@ pub struct PhiEvseConfig {
     pub sta: Option<WifiConfig>,
     pub ap: WifiConfig,
     pub mqtt_uri: Option<String>,
+    pub controller: ControllerSettings,
 }
 
 #[derive(Debug)]
@@ -17,18 +25,22 @@ pub struct WifiConfig {
 
 impl PhiEvseConfig {
     pub fn load() -> Result<Self, anyhow::Error> {
-        let nvs = EspDefaultNvs::new(EspDefaultNvsPartition::take()?, "phievse", true)?;
+        let nvs = EspDefaultNvs::new(nvs_partition()?, "phievse", true)?;
 
         Ok(Self {
             hostname: get_string(&nvs, "hostname")?.unwrap_or("phievse".into()),
             sta: WifiConfig::load(&nvs, "sta")?,
-            ap: WifiConfig::load(&nvs, "ap")?.unwrap_or(WifiConfig { ssid: "phievse".into(), psk: None }),
+            ap: WifiConfig::load(&nvs, "ap")?.unwrap_or(WifiConfig {
+                ssid: "phievse".into(),
+                psk: None,
+            }),
             mqtt_uri: get_string(&nvs, "mqtt.uri")?,
+            controller: load_controller(&nvs)?,
         })
     }
 
     pub fn save(&self) -> Result<(), anyhow::Error> {
-        let mut nvs = EspDefaultNvs::new(EspDefaultNvsPartition::take()?, "phievse", true)?;
+        let mut nvs = EspDefaultNvs::new(nvs_partition()?, "phievse", true)?;
 
         set_string(&mut nvs, "hostname", Some(&self.hostname))?;
         if let Some(sta) = &self.sta {
@@ -38,6 +50,7 @@ impl PhiEvseConfig {
         }
         self.ap.save(&mut nvs, "ap")?;
         set_string(&mut nvs, "mqtt.uri", self.mqtt_uri.as_ref())?;
+        save_controller(&mut nvs, &self.controller)?;
 
         Ok(())
     }
@@ -49,7 +62,7 @@ impl WifiConfig {
         if let Some(ssid) = ssid {
             Ok(Some(WifiConfig {
                 ssid,
-                psk: get_string(nvs, &format!("{prefix}.psk"))?
+                psk: get_string(nvs, &format!("{prefix}.psk"))?,
             }))
         } else {
             Ok(None)
@@ -71,6 +84,225 @@ impl WifiConfig {
     }
 }
 
+fn load_controller(nvs: &EspDefaultNvs) -> Result<ControllerSettings, anyhow::Error> {
+    let default = ControllerSettings::default();
+    Ok(ControllerSettings {
+        installation: load_installation(nvs)?,
+        pilot: load_pilot(nvs)?,
+        pilot_duty: load_pilot_duty(nvs)?,
+        current_calibration: load_current_calibration(nvs)?,
+        phase_switch_rest: nvs
+            .get_u32("phase.rest")?
+            .map(|s| Duration::from_secs(s as u64))
+            .unwrap_or(default.phase_switch_rest),
+        three_phase_above: nvs
+            .get_u32("phase.up")?
+            .unwrap_or(default.three_phase_above),
+        one_phase_below: nvs
+            .get_u32("phase.down")?
+            .unwrap_or(default.one_phase_below),
+        phase_min_dwell: nvs
+            .get_u32("phase.dwell")?
+            .map(|s| Duration::from_secs(s as u64))
+            .unwrap_or(default.phase_min_dwell),
+        phase_max_daily_switches: nvs
+            .get_u32("phase.daily")?
+            .unwrap_or(default.phase_max_daily_switches),
+        charge_start_timeout: nvs
+            .get_u32("start.timeout")?
+            .map(|s| Duration::from_secs(s as u64))
+            .unwrap_or(default.charge_start_timeout),
+        wake_up_attempts: nvs
+            .get_u32("start.wakeups")?
+            .unwrap_or(default.wake_up_attempts),
+        diode_check: match nvs.get_u8("diode.check")? {
+            Some(1) => DiodeCheck::WarnOnly,
+            Some(_) => DiodeCheck::Enforce,
+            None => default.diode_check,
+        },
+        allow_ventilation: nvs
+            .get_u8("vent.allow")?
+            .map(|v| v != 0)
+            .unwrap_or(default.allow_ventilation),
+    })
+}
+
+fn save_controller(
+    nvs: &mut EspDefaultNvs,
+    settings: &ControllerSettings,
+) -> Result<(), anyhow::Error> {
+    save_installation(nvs, &settings.installation)?;
+    save_pilot(nvs, &settings.pilot)?;
+    save_pilot_duty(nvs, &settings.pilot_duty)?;
+    save_current_calibration(nvs, &settings.current_calibration)?;
+    nvs.set_u32("phase.rest", settings.phase_switch_rest.as_secs() as u32)?;
+    nvs.set_u32("phase.up", settings.three_phase_above)?;
+    nvs.set_u32("phase.down", settings.one_phase_below)?;
+    nvs.set_u32("phase.dwell", settings.phase_min_dwell.as_secs() as u32)?;
+    nvs.set_u32("phase.daily", settings.phase_max_daily_switches)?;
+    nvs.set_u32(
+        "start.timeout",
+        settings.charge_start_timeout.as_secs() as u32,
+    )?;
+    nvs.set_u32("start.wakeups", settings.wake_up_attempts)?;
+    nvs.set_u8(
+        "diode.check",
+        (settings.diode_check == DiodeCheck::WarnOnly) as u8,
+    )?;
+    nvs.set_u8("vent.allow", settings.allow_ventilation as u8)?;
+
+    Ok(())
+}
+
+fn load_installation(nvs: &EspDefaultNvs) -> Result<InstallationLimits, anyhow::Error> {
+    let default = InstallationLimits::default();
+    Ok(InstallationLimits {
+        voltage: nvs
+            .get_u32("inst.voltage")?
+            .filter(|v| VOLTAGE_RANGE.contains(v))
+            .unwrap_or(default.voltage),
+        breaker_current: nvs
+            .get_u32("inst.breaker")?
+            .filter(|&c| c >= MIN_LIMIT_CURRENT)
+            .unwrap_or(default.breaker_current),
+        cable_current: nvs
+            .get_u32("inst.cable")?
+            .filter(|&c| c >= MIN_LIMIT_CURRENT)
+            .unwrap_or(default.cable_current),
+        three_phase: nvs
+            .get_u8("inst.3p")?
+            .map(|v| v != 0)
+            .unwrap_or(default.three_phase),
+        supports_32a: nvs
+            .get_u8("inst.32a")?
+            .map(|v| v != 0)
+            .unwrap_or(default.supports_32a),
+        frequency: match nvs.get_u8("inst.freq")? {
+            Some(50) => MainsFrequency::Hz50,
+            Some(60) => MainsFrequency::Hz60,
+            Some(_) => MainsFrequency::Auto,
+            None => default.frequency,
+        },
+    })
+}
+
+fn save_installation(
+    nvs: &mut EspDefaultNvs,
+    installation: &InstallationLimits,
+) -> Result<(), anyhow::Error> {
+    nvs.set_u32("inst.voltage", installation.voltage)?;
+    nvs.set_u32("inst.breaker", installation.breaker_current)?;
+    nvs.set_u32("inst.cable", installation.cable_current)?;
+    nvs.set_u8("inst.3p", installation.three_phase as u8)?;
+    nvs.set_u8("inst.32a", installation.supports_32a as u8)?;
+    nvs.set_u8("inst.freq", installation.frequency.hz().unwrap_or(0) as u8)?;
+
+    Ok(())
+}
+
+fn load_pilot(nvs: &EspDefaultNvs) -> Result<PilotThresholds, anyhow::Error> {
+    let default = PilotThresholds::default();
+    let pilot = PilotThresholds {
+        connected: nvs.get_i32("cp.connected")?.unwrap_or(default.connected),
+        ready: nvs.get_i32("cp.ready")?.unwrap_or(default.ready),
+        ventilation: nvs.get_i32("cp.vent")?.unwrap_or(default.ventilation),
+        short_circuit: nvs.get_i32("cp.short")?.unwrap_or(default.short_circuit),
+        hysteresis: nvs.get_i32("cp.hyst")?.unwrap_or(default.hysteresis),
+        frames: nvs.get_u32("cp.frames")?.unwrap_or(default.frames),
+    };
+    if pilot.is_valid() {
+        Ok(pilot)
+    } else {
+        log::warn!("Invalid pilot thresholds {pilot:?}, using the defaults");
+        Ok(default)
+    }
+}
+
+fn save_pilot(nvs: &mut EspDefaultNvs, pilot: &PilotThresholds) -> Result<(), anyhow::Error> {
+    nvs.set_i32("cp.connected", pilot.connected)?;
+    nvs.set_i32("cp.ready", pilot.ready)?;
+    nvs.set_i32("cp.vent", pilot.ventilation)?;
+    nvs.set_i32("cp.short", pilot.short_circuit)?;
+    nvs.set_i32("cp.hyst", pilot.hysteresis)?;
+    nvs.set_u32("cp.frames", pilot.frames)?;
+
+    Ok(())
+}
+
+fn load_pilot_duty(nvs: &EspDefaultNvs) -> Result<PilotDuty, anyhow::Error> {
+    let default = PilotDuty::default();
+    Ok(PilotDuty {
+        mapping: match nvs.get_u8("cp.duty.std")? {
+            Some(1) => DutyMapping::Standard,
+            Some(_) => DutyMapping::Table,
+            None => default.mapping,
+        },
+        table: get_string(nvs, "cp.duty")?
+            .and_then(|table| PilotDuty::parse_table(&table))
+            .unwrap_or(default.table),
+    })
+}
+
+fn save_pilot_duty(nvs: &mut EspDefaultNvs, duty: &PilotDuty) -> Result<(), anyhow::Error> {
+    nvs.set_u8("cp.duty.std", (duty.mapping == DutyMapping::Standard) as u8)?;
+    set_string(nvs, "cp.duty", Some(&duty.table_string()))?;
+
+    Ok(())
+}
+
+fn load_current_calibration(nvs: &EspDefaultNvs) -> Result<CurrentCalibration, anyhow::Error> {
+    let default = CurrentCalibration::default();
+    let mut phases = default.phases;
+    for (n, phase) in phases.iter_mut().enumerate() {
+        // Gains are stored in 1/10000
+        *phase = PhaseCalibration {
+            gain: nvs
+                .get_u32(&format!("ct.gain{}", n + 1))?
+                .map(|g| g as f32 / 10000.0)
+                .unwrap_or(phase.gain),
+            offset: nvs
+                .get_i32(&format!("ct.offset{}", n + 1))?
+                .unwrap_or(phase.offset),
+        };
+    }
+    let calibration = CurrentCalibration {
+        ct_ratio: nvs.get_u32("ct.ratio")?.unwrap_or(default.ct_ratio),
+        phases,
+        fast_cycles: nvs.get_u32("ct.fast")?.unwrap_or(default.fast_cycles),
+        smoothed_window: nvs
+            .get_u32("ct.smoothed")?
+            .map(|ms| Duration::from_millis(ms as u64))
+            .unwrap_or(default.smoothed_window),
+    };
+    if calibration.is_valid() {
+        Ok(calibration)
+    } else {
+        log::warn!("Invalid current calibration {calibration:?}, using the defaults");
+        Ok(default)
+    }
+}
+
+fn save_current_calibration(
+    nvs: &mut EspDefaultNvs,
+    calibration: &CurrentCalibration,
+) -> Result<(), anyhow::Error> {
+    nvs.set_u32("ct.ratio", calibration.ct_ratio)?;
+    for (n, phase) in calibration.phases.iter().enumerate() {
+        nvs.set_u32(
+            &format!("ct.gain{}", n + 1),
+            (phase.gain * 10000.0).round() as u32,
+        )?;
+        nvs.set_i32(&format!("ct.offset{}", n + 1), phase.offset)?;
+    }
+    nvs.set_u32("ct.fast", calibration.fast_cycles)?;
+    nvs.set_u32(
+        "ct.smoothed",
+        calibration.smoothed_window.as_millis() as u32,
+    )?;
+
+    Ok(())
+}
+
 fn set_string(nvs: &mut EspDefaultNvs, key: &str, value: Option<&String>) -> Result<(), EspError> {
     if let Some(v) = value {
         nvs.set_str(key, v)
@@ -83,8 +315,8 @@ fn get_string(nvs: &EspDefaultNvs, key: &str) -> Result<Option<String>, anyhow::
     let len = nvs.str_len(key)?;
     if let Some(len) = len {
         let mut buf = vec![0u8; len];
-        Ok(nvs.get_str(key, &mut buf)?.map(|s| s[..len-1].into()))
+        Ok(nvs.get_str(key, &mut buf)?.map(|s| s[..len - 1].into()))
     } else {
         Ok(None)
     }
-}
\ No newline at end of file
+}
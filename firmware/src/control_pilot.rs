@@ -1,17 +1,39 @@
-use std::sync::atomic::{AtomicBool, AtomicI32};
+use std::sync::{
+    Mutex,
+    atomic::{AtomicBool, AtomicI32},
+};
 
 use embedded_hal::PwmPin;
 
+use crate::{
+    pilot_pwm::{DutyMeter, PilotPwm},
+    settings::{DutyMapping, PilotDuty, PilotThresholds},
+};
+
 pub enum ControlPilotSignal {
     Standby,
     Charge(u32),
+    /// Raw duty, to calibrate the duty mapping
+    Duty(u32),
     Error,
 }
 
-pub fn set_control_pilot(pin: &mut impl PwmPin<Duty = u32>, signal: ControlPilotSignal) {
+/// Duty at +12V
+pub(crate) const MAX_DUTY: u32 = 16383;
+
+/// Sets the pilot signal, with charging currents corrected by `trim` (mA)
+pub fn set_control_pilot(
+    pin: &mut impl PwmPin<Duty = u32>,
+    signal: ControlPilotSignal,
+    calibration: &PilotDuty,
+    trim: i32,
+) {
     let duty: u32 = match signal {
-        ControlPilotSignal::Standby => 16383,
-        ControlPilotSignal::Charge(mamps @ 6000..=32000) => current_to_duty(mamps),
+        ControlPilotSignal::Standby => MAX_DUTY,
+        ControlPilotSignal::Charge(mamps @ 6000..=32000) => {
+            current_to_duty(mamps.saturating_add_signed(trim), calibration)
+        }
+        ControlPilotSignal::Duty(duty) => duty.min(MAX_DUTY),
         ControlPilotSignal::Error => 0,
         _ => 0, // Invalid
     };
@@ -19,54 +41,197 @@ pub fn set_control_pilot(pin: &mut impl PwmPin<Duty = u32>, signal: ControlPilot
     pin.set_duty(duty);
 }
 
-fn current_to_duty(ma: u32) -> u32 {
-    match ma {
-        0..=5999 => 0,
-        6000..=10999 => 17 * ma / 1000 + 110,
-        11000..=13499 => 5051 + ma * ma / 100 * 368 / 100000 - 837 * ma / 1000,
-        13500..=32000 => 235 * ma / 1000 - 2713,
-        _ => 0,
+/// Duty for a current (mA), extrapolated outside of 6-32A
+pub(crate) fn current_to_duty(ma: u32, calibration: &PilotDuty) -> u32 {
+    match calibration.mapping {
+        DutyMapping::Standard => (ma * MAX_DUTY / 60000).min(MAX_DUTY),
+        DutyMapping::Table => interpolate(&calibration.table, ma),
     }
 }
 
-#[derive(Debug, PartialEq)]
+/// Linear interpolation between the points around `x`, extrapolating from the first or last two
+fn interpolate(points: &[(u32, u32)], x: u32) -> u32 {
+    let i = points
+        .iter()
+        .position(|&(px, _)| px > x)
+        .unwrap_or(points.len())
+        .clamp(1, points.len().saturating_sub(1).max(1));
+    let (Some(&(x0, y0)), Some(&(x1, y1))) = (points.get(i - 1), points.get(i)) else {
+        // Not enough points to interpolate
+        return points.first().map_or(0, |&(_, y)| y);
+    };
+    let y = y0 as i64 + (y1 as i64 - y0 as i64) * (x as i64 - x0 as i64) / (x1 as i64 - x0 as i64);
+    y.clamp(0, MAX_DUTY as i64) as u32
+}
+
+/// IEC 61851 pilot states
+#[derive(Debug, PartialEq, Copy, Clone)]
 pub enum ControlPilotMode {
+    /// A: +12V
     NotConnected,
+    /// B: +9V
     Connected,
+    /// C: +6V
     Ready,
-    Error,
+    /// D: +3V, ready but the car needs ventilation to charge
+    VentilationRequired,
+    /// E: 0V, pilot shorted to PE
+    ShortCircuit,
+    /// F: -12V, driven by the EVSE while it's not available
+    Unavailable,
+}
+
+/// States that can be read from the pilot level, ordered by reading
+const LEVELS: [ControlPilotMode; 5] = [
+    ControlPilotMode::NotConnected,
+    ControlPilotMode::Connected,
+    ControlPilotMode::Ready,
+    ControlPilotMode::VentilationRequired,
+    ControlPilotMode::ShortCircuit,
+];
+
+/// Classifies pilot readings into states. A new state has to be read in consecutive frames and
+/// past the hysteresis, otherwise the readings are counted as a glitch.
+pub struct PilotClassifier {
+    thresholds: PilotThresholds,
+    /// Index in `LEVELS`
+    level: usize,
+    candidate: usize,
+    candidate_frames: u32,
+    transitions: u32,
+    glitches: u32,
+}
+
+impl PilotClassifier {
+    pub fn new(thresholds: &PilotThresholds) -> Self {
+        Self {
+            thresholds: thresholds.clone(),
+            level: 0,
+            candidate: 0,
+            candidate_frames: 0,
+            transitions: 0,
+            glitches: 0,
+        }
+    }
+
+    /// Accounts the (maximum) reading of a frame
+    pub fn receive(&mut self, mv: i32) {
+        let level = self.classify(mv);
+        if level == self.level {
+            if self.candidate_frames > 0 {
+                self.glitches += 1;
+            }
+            self.candidate_frames = 0;
+            return;
+        }
+
+        if self.candidate_frames > 0 && level == self.candidate {
+            self.candidate_frames += 1;
+        } else {
+            if self.candidate_frames > 0 {
+                self.glitches += 1;
+            }
+            self.candidate = level;
+            self.candidate_frames = 1;
+        }
+        if self.candidate_frames >= self.thresholds.frames {
+            self.level = level;
+            self.candidate_frames = 0;
+            self.transitions += 1;
+        }
+    }
+
+    fn classify(&self, mv: i32) -> usize {
+        let t = &self.thresholds;
+        [t.connected, t.ready, t.ventilation, t.short_circuit]
+            .into_iter()
+            .enumerate()
+            .filter(|&(i, threshold)| {
+                // Leaving the current state needs to cross the threshold by the hysteresis
+                if i < self.level {
+                    mv > threshold - t.hysteresis
+                } else {
+                    mv > threshold + t.hysteresis
+                }
+            })
+            .count()
+    }
+
+    pub fn mode(&self) -> ControlPilotMode {
+        LEVELS[self.level]
+    }
+
+    /// State changes since started
+    pub fn transitions(&self) -> u32 {
+        self.transitions
+    }
+
+    /// Readings of a different state that did not last enough to change to it
+    pub fn glitches(&self) -> u32 {
+        self.glitches
+    }
 }
 
-#[derive(Default)]
 pub struct ControlPilotReader {
     cp_mv: AtomicI32,
     pub negative: AtomicBool,
+    classifier: Mutex<PilotClassifier>,
+    duty: Mutex<DutyMeter>,
 }
 
 impl ControlPilotReader {
+    pub fn new(thresholds: &PilotThresholds) -> Self {
+        Self {
+            cp_mv: Default::default(),
+            negative: Default::default(),
+            classifier: Mutex::new(PilotClassifier::new(thresholds)),
+            duty: Mutex::new(DutyMeter::new(thresholds.connected)),
+        }
+    }
+
     pub fn receive(&self, data: &mut dyn Iterator<Item = i32>) {
-        if let Some(max) = data.max() {
+        let mut duty = self.duty.lock().unwrap();
+        if let Some(max) = data.inspect(|&mv| duty.receive(mv)).max() {
             self.cp_mv.store(max, std::sync::atomic::Ordering::Relaxed);
+            self.classifier.lock().unwrap().receive(max);
         }
     }
 
-    pub fn state(&self) -> ControlPilotMode {
-        // TODO: Pilot check triggers
-        // if self.negative.load(std::sync::atomic::Ordering::Relaxed) {
-        //     return ControlPilotMode::Error;
-        // }
-        let x = self.cp_mv.load(std::sync::atomic::Ordering::Relaxed);
-        match x {
-            i32::MIN..=50 => ControlPilotMode::NotConnected, // < 10
-            51..=650 => ControlPilotMode::Connected,         // ~ 450
-            651.. => ControlPilotMode::Ready, // ~ 1300 (normal) // ~ 2600 (ventilation)
+    /// Last (maximum) pilot reading
+    pub fn mv(&self) -> i32 {
+        self.cp_mv.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// Whether the pilot negative alarm fired since the last call. The alarm has to be re-armed.
+    pub fn negative_alarm(&self) -> bool {
+        self.negative
+            .swap(false, std::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// Pilot state from the positive level. The negative level is checked with `negative_alarm`.
+    /// `holding_negative` is whether the pilot is driven at -12V, as it reads like A.
+    pub fn state(&self, holding_negative: bool) -> ControlPilotMode {
+        if holding_negative {
+            return ControlPilotMode::Unavailable;
         }
+        self.classifier.lock().unwrap().mode()
+    }
+
+    /// Last PWM measured and the number of measurements so far
+    pub fn pwm(&self) -> (Option<PilotPwm>, u32) {
+        self.duty.lock().unwrap().last()
+    }
+
+    /// State changes and glitches since started
+    pub fn stats(&self) -> (u32, u32) {
+        let classifier = self.classifier.lock().unwrap();
+        (classifier.transitions(), classifier.glitches())
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::control_pilot::current_to_duty;
+    use super::*;
     use std::ops::RangeInclusive;
 
     #[test]
@@ -86,8 +251,83 @@ mod tests {
         ];
 
         for (amps, range) in TEST_VALUES {
-            let cp = current_to_duty(amps * 1000);
+            let cp = current_to_duty(amps * 1000, &PilotDuty::default());
             assert!(range.contains(&cp));
         }
     }
+
+    #[test]
+    fn standard_duty() {
+        let standard = PilotDuty {
+            mapping: DutyMapping::Standard,
+            ..Default::default()
+        };
+        // 10%, 26.7% and 53.3%
+        assert_eq!(current_to_duty(6000, &standard), 1638);
+        assert_eq!(current_to_duty(16000, &standard), 4368);
+        assert_eq!(current_to_duty(32000, &standard), 8737);
+        assert_eq!(current_to_duty(5400, &standard), 1474);
+    }
+
+    #[test]
+    fn interpolates_table() {
+        let points = [(6000, 1000), (10000, 2000), (20000, 2500)];
+        assert_eq!(interpolate(&points, 6000), 1000);
+        assert_eq!(interpolate(&points, 8000), 1500);
+        assert_eq!(interpolate(&points, 10000), 2000);
+        assert_eq!(interpolate(&points, 12000), 2100);
+        assert_eq!(interpolate(&points, 20000), 2500);
+        // Extrapolates
+        assert_eq!(interpolate(&points, 30000), 3000);
+        assert_eq!(interpolate(&points, 4000), 500);
+        assert_eq!(interpolate(&points, 0), 0);
+
+        assert_eq!(interpolate(&[(6000, 1000)], 10000), 1000);
+        assert_eq!(interpolate(&[], 10000), 0);
+    }
+
+    fn classifier() -> PilotClassifier {
+        PilotClassifier::new(&PilotThresholds {
+            connected: 150,
+            ready: 650,
+            ventilation: 1950,
+            short_circuit: 3250,
+            hysteresis: 25,
+            frames: 3,
+        })
+    }
+
+    #[test]
+    fn ignores_glitches() {
+        let mut c = classifier();
+        for mv in [5, 450, 450, 5, 1300, 5, 450, 1300, 1300, 5] {
+            c.receive(mv);
+            assert_eq!(c.mode(), ControlPilotMode::NotConnected);
+        }
+        assert_eq!(c.glitches(), 4);
+
+        for _ in 0..3 {
+            c.receive(450);
+        }
+        assert_eq!(c.mode(), ControlPilotMode::Connected);
+        assert_eq!(c.transitions(), 1);
+    }
+
+    #[test]
+    fn hysteresis() {
+        let mut c = classifier();
+        let mut read = |mv| {
+            for _ in 0..3 {
+                c.receive(mv);
+            }
+            c.mode()
+        };
+        assert_eq!(read(660), ControlPilotMode::Connected);
+        assert_eq!(read(680), ControlPilotMode::Ready);
+        assert_eq!(read(640), ControlPilotMode::Ready);
+        assert_eq!(read(620), ControlPilotMode::Connected);
+        assert_eq!(read(3300), ControlPilotMode::ShortCircuit);
+        assert_eq!(read(3240), ControlPilotMode::ShortCircuit);
+        assert_eq!(read(0), ControlPilotMode::NotConnected);
+    }
 }
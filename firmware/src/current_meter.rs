@@ -1,68 +1,620 @@
 use std::{
     cmp::{max, min},
     f32::consts::SQRT_2,
-    sync::atomic::AtomicU32,
+    ops::RangeInclusive,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU32, Ordering},
+    },
+    time::Duration,
 };
 
+use serde::Serialize;
+
+use crate::{
+    adc::SAMPLE_RATE_HZ,
+    settings::{CurrentCalibration, MIN_SMOOTHED_WINDOW, MainsFrequency, PhaseCalibration},
+};
+
+/// Peak-to-peak readings under this are noise, until the noise of the channel is learnt
 const DEADZONE_MV: i32 = 100;
-const WAVELENGTH: i32 = 200;
+/// Readings under this many times the RMS noise of the channel are noise
+const NOISE_DEADZONE: f32 = 3.0;
+/// Minimum RMS deadzone (mV)
+const MIN_DEADZONE_MV: f32 = 2.0;
+/// Weight of each idle measurement in the profile of the channel
+const PROFILE_LEARNING_RATE: f32 = 0.2;
+/// DC level of a working CT channel (mV), half the ADC range
+const CT_BIAS_RANGE: RangeInclusive<i32> = 1000..=1800;
+/// RMS noise of a working CT channel (mV). Less is a stuck reading, more a floating input.
+const CT_NOISE_RANGE: RangeInclusive<f32> = 0.1..=20.0;
+/// Mains frequency until detected (Hz)
+const DEFAULT_FREQUENCY: u32 = 50;
+/// Mains frequencies that can be detected (Hz)
+const FREQUENCY_RANGE: RangeInclusive<f32> = 45.0..=65.0;
+/// Readings have to go this much below the bias before a rising zero crossing (mV), so noise
+/// doesn't cut cycles short
+const CROSSING_HYSTERESIS_MV: f32 = 20.0;
+/// The RMS and the peak estimate are this many times apart, the waveform is far from a sine
+const CROSS_CHECK_RATIO: f32 = 2.0;
 
-const CT_RATIO: f32 = 600.0;
-const SHUNT_RESISTOR: f32 = 15.0;
+pub const SHUNT_RESISTOR: f32 = 15.0;
+/// Resistance of the board traces in series with the shunt of each phase
+pub const EXTRA_RESISTORS: [f32; 3] = [0.8, 1.4, 1.6];
 
+/// DC level and noise of a CT channel without current
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct CtProfile {
+    pub bias_mv: f32,
+    /// RMS (mV)
+    pub noise_mv: f32,
+}
+
+impl CtProfile {
+    /// The CT is disconnected or broken
+    pub fn faulty(&self) -> bool {
+        !CT_BIAS_RANGE.contains(&(self.bias_mv as i32)) || !CT_NOISE_RANGE.contains(&self.noise_mv)
+    }
+}
+
+/// Currents measured on a CT channel (mA)
+#[derive(Debug, Default)]
+pub struct CurrentReadings {
+    /// RMS of the last few cycles, to react quickly to overcurrents
+    pub fast: AtomicU32,
+    /// RMS over a longer window, steadier for the status and the regulation
+    pub smoothed: AtomicU32,
+}
+
+/// Shared with the controller to learn the profile of a CT channel while the contactors are open
+#[derive(Debug, Default)]
+pub struct CtHealth {
+    /// The contactors are open, so there's no current
+    pub idle: AtomicBool,
+    profile: Mutex<Option<CtProfile>>,
+}
+
+impl CtHealth {
+    /// Profile learnt, `None` until the contactors were open for a while
+    pub fn profile(&self) -> Option<CtProfile> {
+        *self.profile.lock().unwrap()
+    }
+}
+
+/// Measures the RMS current of a CT channel
+///
+/// Samples are split in cycles at the rising zero crossings, so each cycle has a whole period and
+/// its mean is the DC bias of the CT, which is removed before computing the RMS of the cycles. The
+/// RMS is published both for a few cycles (fast) and for a longer window (smoothed). The
+/// peak-to-peak of each cycle gives the RMS of a sine, kept to check the smoothed RMS against. The
+/// time between crossings gives the mains frequency. The noise learnt while idle is taken out of
+/// the RMS and sets the deadzone.
 #[derive(Debug)]
 pub struct CurrentMeter {
-    count: i32,
-    stats: &'static AtomicU32,
+    readings: Arc<CurrentReadings>,
+    /// Mains frequency measured (cHz), 0 until measured
+    frequency: Arc<AtomicU32>,
+    health: Arc<CtHealth>,
+    /// Idle during the whole measurement in progress
+    idle: bool,
+    /// Samples of a mains cycle
+    wavelength: u32,
+    /// Set the wavelength from the frequency measured
+    detect_frequency: bool,
+    /// DC level of the readings (mV), the mean of the last cycle
+    bias: f32,
+    below_bias: bool,
+    /// The cycle in progress started at a zero crossing
+    from_crossing: bool,
+    /// Cycle in progress
+    count: u32,
+    sum: i64,
+    sum_squares: i64,
     min: i32,
     max: i32,
-    wave_count: i32,
-    wave_sum: i32,
-    peak_mv_to_rms_ma: f32,
+    /// Cycles of the fast measurement in progress
+    fast_cycles: u32,
+    fast_count: u32,
+    fast_samples: u32,
+    fast_sum: i64,
+    fast_sum_squares: i64,
+    /// Samples in each smoothed measurement
+    window_samples: u32,
+    /// Cycles of the smoothed measurement in progress
+    wave_count: u32,
+    wave_samples: u32,
+    wave_sum: i64,
+    wave_sum_squares: i64,
+    wave_peak_to_peak: i32,
+    /// Cycles between two zero crossings and their samples, to measure the frequency
+    crossing_cycles: u32,
+    crossing_samples: u32,
+    /// The peak estimate doesn't match the RMS
+    mismatch: bool,
+    rms_mv_to_ma: f32,
+    calibration: PhaseCalibration,
 }
 
 impl CurrentMeter {
-    pub fn new(stats: &'static AtomicU32, extra_resistor: f32) -> Self {
+    /// Meter for the CT of `phase` (0-2)
+    pub fn new(
+        readings: Arc<CurrentReadings>,
+        frequency: Arc<AtomicU32>,
+        health: Arc<CtHealth>,
+        mains: MainsFrequency,
+        extra_resistor: f32,
+        calibration: &CurrentCalibration,
+        phase: usize,
+    ) -> Self {
         Self {
+            readings,
+            frequency,
+            health,
+            idle: false,
+            wavelength: SAMPLE_RATE_HZ / mains.hz().unwrap_or(DEFAULT_FREQUENCY),
+            detect_frequency: mains.hz().is_none(),
+            bias: 0.0,
+            below_bias: false,
+            from_crossing: false,
             count: 0,
-            stats,
-            min: 10000,
-            max: 0,
+            sum: 0,
+            sum_squares: 0,
+            min: i32::MAX,
+            max: i32::MIN,
+            fast_cycles: calibration.fast_cycles.max(1),
+            fast_count: 0,
+            fast_samples: 0,
+            fast_sum: 0,
+            fast_sum_squares: 0,
+            window_samples: (calibration
+                .smoothed_window
+                .max(MIN_SMOOTHED_WINDOW)
+                .as_secs_f32()
+                * SAMPLE_RATE_HZ as f32) as u32,
             wave_count: 0,
+            wave_samples: 0,
             wave_sum: 0,
-            peak_mv_to_rms_ma: CT_RATIO / (SHUNT_RESISTOR + extra_resistor) / SQRT_2 / 2.0,
+            wave_sum_squares: 0,
+            wave_peak_to_peak: 0,
+            crossing_cycles: 0,
+            crossing_samples: 0,
+            mismatch: false,
+            rms_mv_to_ma: calibration.ct_ratio as f32 / (SHUNT_RESISTOR + extra_resistor),
+            calibration: calibration.phases[phase],
         }
     }
 
     pub fn receive(&mut self, data: &mut dyn Iterator<Item = i32>) {
+        self.idle &= self.health.idle.load(Ordering::Relaxed);
         for d in data {
             self.count += 1;
+            self.sum += d as i64;
+            self.sum_squares += d as i64 * d as i64;
             self.min = min(self.min, d);
             self.max = max(self.max, d);
 
-            if self.count > WAVELENGTH {
-                self.wave_count += 1;
-                self.wave_sum += self.max - self.min;
-                self.min = 10000;
-                self.max = 0;
-                self.count = 0;
+            let mv = d as f32 - self.bias;
+            let mut crossing = false;
+            if mv < -CROSSING_HYSTERESIS_MV {
+                self.below_bias = true;
+            } else if self.below_bias && mv >= 0.0 {
+                self.below_bias = false;
+                // Harmonics may cross again within the cycle
+                crossing = self.count >= self.wavelength * 3 / 4;
+            }
+            // Without current there are no crossings but noise, cut after about a cycle
+            if crossing || self.count >= self.wavelength * 5 / 4 {
+                self.end_cycle(crossing);
             }
         }
 
-        // Average all waves every second
-        if self.wave_count >= 50 {
-            let peak_to_peak_mv = self.wave_sum / self.wave_count;
-            let rms_ma = if peak_to_peak_mv > DEADZONE_MV {
-                peak_to_peak_mv as f32 * self.peak_mv_to_rms_ma
-            } else {
+        // Average all waves of the window
+        if self.wave_samples >= self.window_samples {
+            self.measure();
+        }
+    }
+
+    fn end_cycle(&mut self, crossing: bool) {
+        if crossing && self.from_crossing {
+            self.crossing_cycles += 1;
+            self.crossing_samples += self.count;
+        }
+        self.from_crossing = crossing;
+        self.bias = self.sum as f32 / self.count as f32;
+        self.fast_count += 1;
+        self.fast_samples += self.count;
+        self.fast_sum += self.sum;
+        self.fast_sum_squares += self.sum_squares;
+        if self.fast_count >= self.fast_cycles {
+            self.measure_fast();
+        }
+        self.wave_count += 1;
+        self.wave_samples += self.count;
+        self.wave_sum += self.sum;
+        self.wave_sum_squares += self.sum_squares;
+        self.wave_peak_to_peak += self.max - self.min;
+
+        self.count = 0;
+        self.sum = 0;
+        self.sum_squares = 0;
+        self.min = i32::MAX;
+        self.max = i32::MIN;
+    }
+
+    fn measure_fast(&mut self) {
+        let (_, variance) =
+            mean_and_variance(self.fast_samples, self.fast_sum, self.fast_sum_squares);
+        let rms_mv = signal_rms_mv(variance, self.health.profile());
+        let rms_ma = if rms_mv > 0.0 {
+            self.calibration.apply(rms_mv * self.rms_mv_to_ma)
+        } else {
+            0
+        };
+        self.readings.fast.store(rms_ma, Ordering::Relaxed);
+
+        self.fast_count = 0;
+        self.fast_samples = 0;
+        self.fast_sum = 0;
+        self.fast_sum_squares = 0;
+    }
+
+    fn measure(&mut self) {
+        let (mean, variance) =
+            mean_and_variance(self.wave_samples, self.wave_sum, self.wave_sum_squares);
+        let peak_to_peak_mv = self.wave_peak_to_peak / self.wave_count as i32;
+
+        let profile = if self.idle {
+            self.learn(mean, variance)
+        } else {
+            self.health.profile()
+        };
+
+        let rms_mv = signal_rms_mv(variance, profile);
+        let rms_ma = if rms_mv > 0.0 {
+            let peak_estimate_mv = peak_to_peak_mv as f32 / 2.0 / SQRT_2;
+            let ratio = rms_mv.max(peak_estimate_mv) / rms_mv.min(peak_estimate_mv);
+            let mismatch = ratio > CROSS_CHECK_RATIO;
+            if mismatch && !self.mismatch {
+                log::warn!(
+                    "Current RMS {} mA but the peak estimate is {} mA, check the CT",
+                    rms_mv * self.rms_mv_to_ma,
+                    peak_estimate_mv * self.rms_mv_to_ma
+                );
+            }
+            self.mismatch = mismatch;
+            self.calibration.apply(rms_mv * self.rms_mv_to_ma)
+        } else {
+            0
+        };
+        self.readings.smoothed.store(rms_ma, Ordering::Relaxed);
+
+        // Needs crossings over most of the measurement
+        if self.crossing_samples >= self.window_samples / 2 {
+            let hz =
+                self.crossing_cycles as f32 * SAMPLE_RATE_HZ as f32 / self.crossing_samples as f32;
+            if FREQUENCY_RANGE.contains(&hz) {
+                self.frequency
+                    .store((hz * 100.0).round() as u32, Ordering::Relaxed);
+                if self.detect_frequency {
+                    self.wavelength = (SAMPLE_RATE_HZ as f32 / hz).round() as u32;
+                }
+            }
+        }
+
+        // Reset
+        self.idle = self.health.idle.load(Ordering::Relaxed);
+        self.crossing_cycles = 0;
+        self.crossing_samples = 0;
+        self.wave_count = 0;
+        self.wave_samples = 0;
+        self.wave_sum = 0;
+        self.wave_sum_squares = 0;
+        self.wave_peak_to_peak = 0;
+    }
+
+    /// Updates the profile of the channel with a measurement without current
+    fn learn(&mut self, bias_mv: f32, variance: f32) -> Option<CtProfile> {
+        let mut profile = self.health.profile.lock().unwrap();
+        let learnt = match *profile {
+            Some(last) if !last.faulty() => CtProfile {
+                bias_mv: last.bias_mv + (bias_mv - last.bias_mv) * PROFILE_LEARNING_RATE,
+                noise_mv: (last.noise_mv.powi(2)
+                    + (variance - last.noise_mv.powi(2)) * PROFILE_LEARNING_RATE)
+                    .sqrt(),
+            },
+            // Starts over from a faulty profile, so a CT plugged back in is healthy right away
+            _ => CtProfile {
+                bias_mv,
+                noise_mv: variance.sqrt(),
+            },
+        };
+        if learnt.faulty() && !profile.is_some_and(|p| p.faulty()) {
+            log::warn!("CT looks disconnected or faulty: {learnt:?}");
+        }
+        *profile = Some(learnt);
+        *profile
+    }
+}
+
+/// Mean and variance (mV) of readings from their sums
+fn mean_and_variance(samples: u32, sum: i64, sum_squares: i64) -> (f32, f32) {
+    let samples = samples as f64;
+    let mean = sum as f64 / samples;
+    let variance = (sum_squares as f64 / samples - mean * mean).max(0.0);
+    (mean as f32, variance as f32)
+}
+
+/// RMS (mV) of the current in readings with a `variance`, without the noise of the channel.
+/// 0 under the deadzone.
+fn signal_rms_mv(variance: f32, profile: Option<CtProfile>) -> f32 {
+    let (rms_mv, deadzone_mv) = match profile {
+        Some(profile) if !profile.faulty() => (
+            (variance - profile.noise_mv.powi(2)).max(0.0).sqrt(),
+            (profile.noise_mv * NOISE_DEADZONE).max(MIN_DEADZONE_MV),
+        ),
+        _ => (variance.sqrt(), DEADZONE_MV as f32 / 2.0 / SQRT_2),
+    };
+    if rms_mv > deadzone_mv { rms_mv } else { 0.0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use super::*;
+
+    struct TestMeter {
+        meter: CurrentMeter,
+        readings: Arc<CurrentReadings>,
+        frequency: Arc<AtomicU32>,
+        health: Arc<CtHealth>,
+        /// Samples received, so the waveform goes on across calls
+        samples: u32,
+    }
+
+    impl TestMeter {
+        fn new(mains: MainsFrequency) -> Self {
+            Self::with_calibration(mains, &Default::default())
+        }
+
+        fn with_calibration(mains: MainsFrequency, calibration: &CurrentCalibration) -> Self {
+            let readings = Arc::new(CurrentReadings::default());
+            let frequency = Arc::new(AtomicU32::new(0));
+            let health = Arc::new(CtHealth::default());
+            let meter = CurrentMeter::new(
+                readings.clone(),
+                frequency.clone(),
+                health.clone(),
+                mains,
+                0.0,
+                calibration,
+                0,
+            );
+            Self {
+                meter,
+                readings,
+                frequency,
+                health,
+                samples: 0,
+            }
+        }
+
+        /// Samples `current` (mA, at each angle of the cycle) at `hz` with a 1400mV bias
+        fn receive(&mut self, seconds: f32, hz: f32, current: impl Fn(f32) -> f32) {
+            let start = self.samples;
+            self.samples += (seconds * SAMPLE_RATE_HZ as f32) as u32;
+            // Start out of phase, as the ADC starts at any time
+            self.receive_mv((start..self.samples).map(|n| {
+                let t = n as f32 / SAMPLE_RATE_HZ as f32 + 0.0037;
+                1400 + (current(2.0 * PI * hz * t) * SHUNT_RESISTOR / 600.0) as i32
+            }));
+        }
+
+        /// Receives readings in frames, as the ADC does
+        fn receive_mv(&mut self, data: impl Iterator<Item = i32>) {
+            let data: Vec<i32> = data.collect();
+            for frame in data.chunks(100) {
+                self.meter.receive(&mut frame.iter().copied());
+            }
+        }
+
+        /// Smoothed current (mA)
+        fn current(&self) -> u32 {
+            self.readings.smoothed.load(Ordering::Relaxed)
+        }
+
+        fn fast_current(&self) -> u32 {
+            self.readings.fast.load(Ordering::Relaxed)
+        }
+
+        fn frequency(&self) -> u32 {
+            self.frequency.load(Ordering::Relaxed)
+        }
+    }
+
+    /// Current (mA) measured over 2s of 50Hz
+    fn measure(current: impl Fn(f32) -> f32) -> u32 {
+        let mut meter = TestMeter::new(MainsFrequency::Auto);
+        meter.receive(2.0, 50.0, current);
+        meter.current()
+    }
+
+    /// RMS (mA) of a waveform (mA) over a cycle
+    fn rms(current: impl Fn(f32) -> f32) -> f32 {
+        let squares: f32 = (0..200)
+            .map(|n| current(2.0 * PI * n as f32 / 200.0).powi(2))
+            .sum();
+        (squares / 200.0).sqrt()
+    }
+
+    fn assert_close(measured: u32, expected: f32) {
+        let error = (measured as f32 - expected).abs() / expected;
+        assert!(error < 0.02, "{measured} mA, expected {expected} mA");
+    }
+
+    #[test]
+    fn measures_sine() {
+        for rms_ma in [6000.0, 16000.0, 32000.0] {
+            assert_close(measure(|a| rms_ma * SQRT_2 * a.sin()), rms_ma);
+        }
+        assert_eq!(measure(|_| 0.0), 0);
+    }
+
+    #[test]
+    fn measures_distorted_waveforms() {
+        // Rectifier with a capacitor: narrow pulses at the peaks of the voltage
+        let pulses = |a: f32| {
+            let pulse = (a.sin().abs() - 0.8).max(0.0) * 5.0;
+            50000.0 * pulse.powi(2) * a.sin().signum()
+        };
+        // Sine with 3rd and 5th harmonics
+        let harmonics =
+            |a: f32| 16000.0 * SQRT_2 * (a.sin() + 0.4 * (3.0 * a).sin() + 0.2 * (5.0 * a).sin());
+        // Phase-cut dimmer
+        let phase_cut = |a: f32| {
+            if a.rem_euclid(PI) < PI / 3.0 {
                 0.0
-            };
-            self.stats
-                .store(rms_ma as u32, std::sync::atomic::Ordering::Relaxed);
+            } else {
+                20000.0 * a.sin()
+            }
+        };
+        for current in [&pulses as &dyn Fn(f32) -> f32, &harmonics, &phase_cut] {
+            assert_close(measure(current), rms(current));
+        }
+    }
+
+    #[test]
+    fn ignores_spikes() {
+        let mut meter = TestMeter::new(MainsFrequency::Auto);
+        let mut data = (0..20000).map(|n| {
+            let sine = 400.0 * (2.0 * PI * n as f32 / 200.0).sin();
+            1400 + sine as i32 + if n % 500 == 0 { 1000 } else { 0 }
+        });
+        meter.meter.receive(&mut data);
+        assert_close(meter.current(), 400.0 / SQRT_2 * 600.0 / SHUNT_RESISTOR);
+    }
 
-            // Reset
-            self.wave_sum = 0;
-            self.wave_count = 0;
+    #[test]
+    fn removes_bias() {
+        for bias in [1000, 1400, 1800] {
+            let mut meter = TestMeter::new(MainsFrequency::Auto);
+            let mut data =
+                (0..20000).map(|n| bias + (400.0 * (2.0 * PI * n as f32 / 200.0).sin()) as i32);
+            meter.meter.receive(&mut data);
+            assert_close(meter.current(), 400.0 / SQRT_2 * 600.0 / SHUNT_RESISTOR);
         }
     }
+
+    #[test]
+    fn measures_frequency() {
+        let sine = |a: f32| 10000.0 * SQRT_2 * a.sin();
+        for (mains, hz) in [
+            (MainsFrequency::Auto, 50.0),
+            (MainsFrequency::Auto, 60.0),
+            (MainsFrequency::Hz60, 60.0),
+            (MainsFrequency::Hz50, 49.8),
+        ] {
+            let mut meter = TestMeter::new(mains);
+            meter.receive(3.0, hz, sine);
+            assert_close(meter.current(), 10000.0);
+            assert!(
+                meter.frequency().abs_diff((hz * 100.0) as u32) <= 5,
+                "{mains:?} {hz}: {}",
+                meter.frequency()
+            );
+        }
+
+        // Not without current
+        let mut meter = TestMeter::new(MainsFrequency::Auto);
+        meter.receive(3.0, 60.0, |_| 0.0);
+        assert_eq!(meter.frequency(), 0);
+    }
+
+    /// Uniform noise (mV) for the `n`th sample, like the ADC's
+    fn noise(n: u32, amplitude: i32) -> i32 {
+        let hash = n.wrapping_mul(1103515245).wrapping_add(12345) >> 16;
+        (hash % (2 * amplitude as u32 + 1)) as i32 - amplitude
+    }
+
+    #[test]
+    fn learns_noise() {
+        let mut meter = TestMeter::new(MainsFrequency::Auto);
+        meter.health.idle.store(true, Ordering::Relaxed);
+        meter.receive_mv((0..30000).map(|n| 1350 + noise(n, 8)));
+        let profile = meter.health.profile().unwrap();
+        assert!((profile.bias_mv - 1350.0).abs() < 1.0, "{profile:?}");
+        // Uniform noise RMS is the amplitude / sqrt(3)
+        assert!((profile.noise_mv - 4.6).abs() < 0.5, "{profile:?}");
+        assert!(!profile.faulty());
+        assert_eq!(meter.current(), 0);
+
+        // Measures low currents, under the default deadzone
+        meter.health.idle.store(false, Ordering::Relaxed);
+        meter.receive_mv((0..30000).map(|n| {
+            let sine = 1000.0 * SQRT_2 * (2.0 * PI * n as f32 / 200.0).sin();
+            1350 + (sine * SHUNT_RESISTOR / 600.0).round() as i32 + noise(n, 8)
+        }));
+        assert_close(meter.current(), 1000.0);
+        // Keeps the profile learnt
+        assert_eq!(meter.health.profile(), Some(profile));
+    }
+
+    #[test]
+    fn flags_faulty_ct() {
+        let faulty = |readings: &dyn Fn(u32) -> i32| {
+            let mut meter = TestMeter::new(MainsFrequency::Auto);
+            meter.health.idle.store(true, Ordering::Relaxed);
+            meter.receive_mv((0..30000).map(readings));
+            meter.health.profile().unwrap().faulty()
+        };
+        assert!(!faulty(&|n| 1400 + noise(n, 8)));
+        // No bias
+        assert!(faulty(&|n| noise(n, 8).max(0)));
+        // Floating input
+        assert!(faulty(&|n| 1400 + noise(n, 100)));
+        // Stuck reading
+        assert!(faulty(&|_| 1400));
+    }
+
+    #[test]
+    fn publishes_fast_current() {
+        let mut meter = TestMeter::new(MainsFrequency::Auto);
+        meter.receive(1.5, 50.0, |a| 10000.0 * SQRT_2 * a.sin());
+        assert_close(meter.fast_current(), 10000.0);
+        assert_close(meter.current(), 10000.0);
+
+        // A few cycles later
+        meter.receive(0.05, 50.0, |a| 30000.0 * SQRT_2 * a.sin());
+        assert_close(meter.fast_current(), 30000.0);
+        assert_close(meter.current(), 10000.0);
+        meter.receive(0.05, 50.0, |_| 0.0);
+        assert_eq!(meter.fast_current(), 0);
+    }
+
+    #[test]
+    fn averages_windows() {
+        let calibration = CurrentCalibration {
+            fast_cycles: 5,
+            smoothed_window: Duration::from_millis(200),
+            ..Default::default()
+        };
+        let mut meter = TestMeter::with_calibration(MainsFrequency::Auto, &calibration);
+        meter.receive(1.0, 50.0, |a| 10000.0 * SQRT_2 * a.sin());
+        // Still averaging some cycles at 10A
+        meter.receive(0.06, 50.0, |a| 30000.0 * SQRT_2 * a.sin());
+        assert!(meter.fast_current() < 25000, "{} mA", meter.fast_current());
+        meter.receive(0.5, 50.0, |a| 30000.0 * SQRT_2 * a.sin());
+        assert_close(meter.fast_current(), 30000.0);
+        assert_close(meter.current(), 30000.0);
+    }
+
+    #[test]
+    fn follows_frequency() {
+        let mut meter = TestMeter::new(MainsFrequency::Auto);
+        meter.receive(2.0, 60.0, |a| 10000.0 * SQRT_2 * a.sin());
+        assert_eq!(meter.meter.wavelength, 167);
+        let mut meter = TestMeter::new(MainsFrequency::Hz50);
+        meter.receive(2.0, 60.0, |a| 10000.0 * SQRT_2 * a.sin());
+        assert_eq!(meter.meter.wavelength, 200);
+    }
 }
@@ -18,7 +18,7 @@ use crate::adc::*;
 use esp_idf_sys::*;
 
 // Minimum frequency to measure 1kHz PWM at 10% duty = 10kHz. Multiply by 4 inputs
-const SAMPLING_FREQ_HZ: Hertz = Hertz(10000 * 4);
+const SAMPLING_FREQ_HZ: Hertz = Hertz(SAMPLE_RATE_HZ * 4);
 
 pub struct AdcDmaDriver {
     adc: Option<AdcContDriver<'static>>,
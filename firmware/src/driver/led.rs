@@ -18,7 +18,8 @@ pub struct RmtDriver {
 
 impl Drop for RmtDriver {
     fn drop(&mut self) {
-        esp!(unsafe { rmt_driver_uninstall(self.channel) }).expect("Failure uninstalling RMT driver");
+        esp!(unsafe { rmt_driver_uninstall(self.channel) })
+            .expect("Failure uninstalling RMT driver");
     }
 }
 
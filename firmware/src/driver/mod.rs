@@ -1,4 +1,5 @@
 pub mod adc;
 pub mod gpio;
 pub mod led;
+pub mod storage;
 pub mod watchdog;
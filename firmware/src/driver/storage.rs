@@ -0,0 +1,50 @@
+use std::sync::Mutex;
+
+use esp_idf_svc::nvs::{EspDefaultNvs, EspDefaultNvsPartition};
+
+use crate::storage::Storage;
+
+static PARTITION: Mutex<Option<EspDefaultNvsPartition>> = Mutex::new(None);
+
+/// Default NVS partition. It can only be taken once, so it's kept and shared by the configuration
+/// and the controller storage.
+pub fn nvs_partition() -> anyhow::Result<EspDefaultNvsPartition> {
+    let mut partition = PARTITION.lock().unwrap();
+    match &*partition {
+        Some(taken) => Ok(taken.clone()),
+        None => Ok(partition.insert(EspDefaultNvsPartition::take()?).clone()),
+    }
+}
+
+/// Storage on the default NVS partition
+pub struct NvsStorage;
+
+impl NvsStorage {
+    fn open() -> anyhow::Result<EspDefaultNvs> {
+        Ok(EspDefaultNvs::new(nvs_partition()?, "phievse", true)?)
+    }
+}
+
+impl Storage for NvsStorage {
+    fn get_u64(&self, key: &str) -> anyhow::Result<Option<u64>> {
+        Ok(Self::open()?.get_u64(key)?)
+    }
+
+    fn set_u64(&mut self, key: &str, value: u64) -> anyhow::Result<()> {
+        Ok(Self::open()?.set_u64(key, value)?)
+    }
+
+    fn get_blob(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let nvs = Self::open()?;
+        if let Some(len) = nvs.blob_len(key)? {
+            let mut buf = vec![0u8; len];
+            Ok(nvs.get_blob(key, &mut buf)?.map(|b| b.to_vec()))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn set_blob(&mut self, key: &str, value: &[u8]) -> anyhow::Result<()> {
+        Ok(Self::open()?.set_blob(key, value)?)
+    }
+}
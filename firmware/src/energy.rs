@@ -0,0 +1,129 @@
+//! Integrates the charging power into session and lifetime energy counters
+
+use std::time::Duration;
+
+/// Minimum energy and time between writes of the lifetime counter, to reduce flash wear
+const PERSIST_MIN_WH: u64 = 100;
+const PERSIST_MIN_INTERVAL: Duration = Duration::from_secs(600);
+
+pub struct EnergyMeter {
+    session_wh: f64,
+    lifetime_wh: f64,
+    last_update: Option<Duration>,
+    persisted_wh: u64,
+    persisted_at: Duration,
+}
+
+impl EnergyMeter {
+    pub fn new(lifetime_wh: u64) -> Self {
+        Self {
+            session_wh: 0.0,
+            lifetime_wh: lifetime_wh as f64,
+            last_update: None,
+            persisted_wh: lifetime_wh,
+            persisted_at: Duration::ZERO,
+        }
+    }
+
+    /// Accumulates the energy at `watts` since the last update
+    pub fn update(&mut self, watts: u32, now: Duration) {
+        if let Some(last) = self.last_update {
+            let wh = watts as f64 * now.saturating_sub(last).as_secs_f64() / 3600.0;
+            self.session_wh += wh;
+            self.lifetime_wh += wh;
+        }
+        self.last_update = Some(now);
+    }
+
+    pub fn start_session(&mut self) {
+        self.session_wh = 0.0;
+    }
+
+    /// Energy of the current (or last) session in Wh
+    pub fn session_wh(&self) -> u32 {
+        self.session_wh as u32
+    }
+
+    /// Total energy delivered by the charger in Wh
+    pub fn lifetime_wh(&self) -> u64 {
+        self.lifetime_wh as u64
+    }
+
+    /// Adds the lifetime energy loaded from storage to the energy metered since started
+    pub fn restore(&mut self, stored_wh: u64) {
+        self.lifetime_wh += stored_wh as f64;
+        self.persisted_wh += stored_wh;
+    }
+
+    /// Returns the lifetime counter if it should be persisted now. `force` skips the minimum interval.
+    pub fn persist_due(&self, now: Duration, force: bool) -> Option<u64> {
+        let lifetime_wh = self.lifetime_wh();
+        let pending = lifetime_wh - self.persisted_wh;
+        let due = if force {
+            pending > 0
+        } else {
+            pending >= PERSIST_MIN_WH && now >= self.persisted_at + PERSIST_MIN_INTERVAL
+        };
+        due.then_some(lifetime_wh)
+    }
+
+    pub fn persisted(&mut self, lifetime_wh: u64, now: Duration) {
+        self.persisted_wh = lifetime_wh;
+        self.persisted_at = now;
+    }
+
+    /// Waits for the next interval to try again
+    pub fn persist_failed(&mut self, now: Duration) {
+        self.persisted_at = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn integrates_power() {
+        let mut meter = EnergyMeter::new(1000);
+        meter.update(3600, Duration::ZERO);
+        meter.update(3600, MINUTE * 10);
+        assert_eq!(meter.session_wh(), 600);
+        assert_eq!(meter.lifetime_wh(), 1600);
+
+        meter.start_session();
+        meter.update(7200, MINUTE * 15);
+        assert_eq!(meter.session_wh(), 600);
+        assert_eq!(meter.lifetime_wh(), 2200);
+    }
+
+    #[test]
+    fn limits_writes() {
+        let mut meter = EnergyMeter::new(0);
+        meter.update(600, Duration::ZERO);
+        meter.update(600, MINUTE * 5);
+        assert_eq!(meter.persist_due(MINUTE * 5, false), None);
+        assert_eq!(meter.persist_due(MINUTE * 5, true), Some(50));
+
+        meter.update(600, MINUTE * 10);
+        assert_eq!(meter.persist_due(MINUTE * 10, false), Some(100));
+        meter.persisted(100, MINUTE * 10);
+        assert_eq!(meter.persist_due(MINUTE * 10, true), None);
+
+        // Enough energy, but too soon
+        meter.update(60000, MINUTE * 11);
+        assert_eq!(meter.persist_due(MINUTE * 11, false), None);
+        assert_eq!(meter.persist_due(MINUTE * 20, false), Some(1100));
+    }
+
+    #[test]
+    fn restores_stored_energy() {
+        let mut meter = EnergyMeter::new(0);
+        meter.update(600, Duration::ZERO);
+        meter.update(600, MINUTE * 5);
+        meter.restore(1000);
+        assert_eq!(meter.lifetime_wh(), 1050);
+        assert_eq!(meter.persist_due(MINUTE * 5, true), Some(1050));
+    }
+}
@@ -0,0 +1,57 @@
+use std::fmt::Display;
+
+use serde::{Deserialize, Serialize};
+
+/// Faults kept in the status, newest first
+pub const MAX_RECENT_FAULTS: usize = 10;
+
+/// Why the controller went to `PhiEvseState::Error`
+#[derive(PartialEq, Debug, Copy, Clone, Serialize, Deserialize)]
+#[serde(tag = "code", content = "phase")]
+pub enum Fault {
+    /// The pilot negative rail is low (e.g: pilot shorted)
+    PilotNegativeLow,
+    /// The car has no diode in the pilot circuit
+    DiodeMissing,
+    /// The pilot voltage is not a valid state
+    PilotError,
+    /// The car draws too much current on the given phase (1-3)
+    Overcurrent(u8),
+    /// There's voltage after the contactor while open
+    RelayWelded,
+    /// There's no voltage after the contactor while closed
+    RelayFailedToClose,
+    /// The car was ready but never drew current, even after trying to wake it up
+    ChargeStartTimeout,
+    /// The pilot is at 0V (state E)
+    PilotShortCircuit,
+    /// The car asks for ventilation (state D) and it's not allowed
+    VentilationRequired,
+    /// The pilot PWM measured doesn't offer the current set, or has the wrong frequency
+    PilotPwmMismatch,
+}
+
+impl Display for Fault {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Fault::PilotNegativeLow => write!(f, "Pilot negative voltage low"),
+            Fault::DiodeMissing => write!(f, "Diode missing in the car"),
+            Fault::PilotError => write!(f, "Invalid pilot voltage"),
+            Fault::Overcurrent(phase) => write!(f, "Overcurrent on phase {phase}"),
+            Fault::RelayWelded => write!(f, "Contactor welded"),
+            Fault::RelayFailedToClose => write!(f, "Contactor failed to close"),
+            Fault::ChargeStartTimeout => write!(f, "Car did not draw current"),
+            Fault::PilotShortCircuit => write!(f, "Pilot short circuit"),
+            Fault::VentilationRequired => write!(f, "Car requires ventilation"),
+            Fault::PilotPwmMismatch => write!(f, "Pilot PWM does not match the current offered"),
+        }
+    }
+}
+
+/// A fault and when it happened
+#[derive(PartialEq, Debug, Copy, Clone, Serialize)]
+pub struct FaultEvent {
+    /// Unix time (s)
+    pub at: u64,
+    pub fault: Fault,
+}
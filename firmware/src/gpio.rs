@@ -1,7 +1,9 @@
-use std::{sync::atomic::AtomicBool, thread::{self, sleep}, time::Duration};
 use embedded_hal::PwmPin;
+use std::time::Duration;
 
-pub struct AlarmReceiver(pub Box<dyn Fn()>);
+use crate::clock::Clock;
+
+pub struct AlarmReceiver(pub Box<dyn Fn() + Send>);
 
 impl AlarmReceiver {
     pub fn alarm(&self) {
@@ -16,55 +18,53 @@ pub trait AlarmInput {
 }
 
 /// Time to go to holding voltage. Coil takes 45ms to switch, let's take a conservative double
-const RELAY_SWITCH_DURATION: std::time::Duration = Duration::from_millis(90);
+const RELAY_SWITCH_DURATION: Duration = Duration::from_millis(90);
 
 /// Holding voltage (in percentage of nominal voltage)
 const RELAY_HOLDING_DUTY: u32 = 85;
 
 pub struct RelayPin<P: PwmPin<Duty = u32>> {
     pin: P,
-    state: AtomicBool
+    /// When the coil was energized, while it's still at nominal voltage
+    switched_at: Option<Duration>,
 }
 
-impl<P: PwmPin<Duty = u32> + Send + 'static> RelayPin<P> {
+impl<P: PwmPin<Duty = u32>> RelayPin<P> {
     pub fn new(mut pin: P) -> Self {
         pin.set_duty(0);
         Self {
             pin,
-            state: AtomicBool::new(false)
+            switched_at: None,
         }
     }
 
-    pub fn set_level(&mut self, level: bool) {
-        let old_level = self.state.swap(level, std::sync::atomic::Ordering::Relaxed);
+    pub fn set_level(&mut self, level: bool, now: Duration) {
         if !level {
             // Off
             self.pin.set_duty(0);
-        } else if !old_level {
-            // Just turned on
+            self.switched_at = None;
+        } else if !self.level() {
+            // Just turned on, `update` will set the holding duty once it has switched
             self.pin.set_duty(self.pin.get_max_duty());
+            self.switched_at = Some(now);
+        }
+    }
 
-            // SAFETY: The pin will live until the application crashes
-            // This also creates multiple references to it (inside the threads and outside)
-            // but access is controlled with the state boolean.
-
-            // This is probably best solved with ledc fades, but they are not
-            // available in esp-idf-sys yet.
-            let p: &'static mut P = unsafe { std::mem::transmute(&mut self.pin) };
-            let s: &'static AtomicBool = unsafe { std::mem::transmute(&self.state) };
-            thread::spawn(move || {
-                sleep(RELAY_SWITCH_DURATION);
-                if s.load(std::sync::atomic::Ordering::Relaxed) {
-                    // If we haven't turned off the relay, set operation duty
-                    p.set_duty(p.get_max_duty() / 100 * RELAY_HOLDING_DUTY)
-                }
-            });
+    /// Lowers the coil to holding voltage after switching. Needs to be called periodically.
+    pub fn update(&mut self, now: Duration) {
+        if let Some(switched_at) = self.switched_at
+            && now >= switched_at + RELAY_SWITCH_DURATION
+        {
+            self.pin
+                .set_duty(self.pin.get_max_duty() / 100 * RELAY_HOLDING_DUTY);
+            self.switched_at = None;
         }
     }
 
-    pub fn set_level_and_wait(&mut self, level: bool) {
-        self.set_level(level);
-        sleep(RELAY_SWITCH_DURATION * 2);
+    pub fn set_level_and_wait(&mut self, level: bool, clock: &impl Clock) {
+        self.set_level(level, clock.now());
+        clock.sleep(RELAY_SWITCH_DURATION * 2);
+        self.update(clock.now());
     }
 
     pub fn level(&self) -> bool {
@@ -0,0 +1,200 @@
+use std::sync::{Arc, Mutex, mpsc};
+use std::time::Duration;
+
+use anyhow::anyhow;
+use askama::Template;
+use embedded_svc::http::Method;
+use embedded_svc::http::server::Request;
+use embedded_svc::io::Write;
+use esp_idf_svc::http::server::{EspHttpConnection, EspHttpServer};
+use esp_idf_sys::*;
+use phievse::settings::{CurrentCalibration, DutyMapping, PilotDuty};
+use phievse::{ControlMessage, Diagnostics};
+
+use crate::config::*;
+
+/// Currents (A) to show the duty of
+const REFERENCE_CURRENTS: [u32; 8] = [6, 8, 10, 13, 16, 20, 25, 32];
+
+#[derive(Template)]
+#[template(path = "calibration.html")]
+struct CalibrationTemplate<'a> {
+    page: &'a str,
+    message: Option<&'a str>,
+    duty: &'a PilotDuty,
+    current: &'a CurrentCalibration,
+    diagnostics: &'a Diagnostics,
+    /// Reference currents (A) with their duty
+    references: Vec<(u32, u32)>,
+}
+
+fn show(
+    req: Request<&mut EspHttpConnection>,
+    diagnostics: &Mutex<Diagnostics>,
+    message: Option<&str>,
+) -> Result<(), anyhow::Error> {
+    let config = PhiEvseConfig::load()?;
+    let duty = &config.controller.pilot_duty;
+
+    let mut response = req.into_ok_response()?;
+    response.write_all(
+        CalibrationTemplate {
+            message,
+            duty,
+            current: &config.controller.current_calibration,
+            diagnostics: &*diagnostics.lock().map_err(|_| anyhow!("Poisoned mutex"))?,
+            references: REFERENCE_CURRENTS
+                .iter()
+                .map(|&amps| (amps, duty.duty(amps * 1000)))
+                .collect(),
+            page: "calibration",
+        }
+        .render()?
+        .as_bytes(),
+    )?;
+    Ok(())
+}
+
+fn save(
+    mut req: Request<&mut EspHttpConnection>,
+    diagnostics: &Mutex<Diagnostics>,
+) -> Result<(), anyhow::Error> {
+    let Some(data) = super::read_form(&mut req)? else {
+        return show(req, diagnostics, Some("The form is too large"));
+    };
+    let form = form_urlencoded::parse(&data);
+
+    let mut config = PhiEvseConfig::load()?;
+    let duty = &mut config.controller.pilot_duty;
+    let current = &mut config.controller.current_calibration;
+    for (key, value) in form {
+        match key.as_ref() {
+            "ct.ratio" => current.ct_ratio = value.parse::<u32>()?.max(1),
+            "ct.offset1" => current.phases[0].offset = value.parse()?,
+            "ct.offset2" => current.phases[1].offset = value.parse()?,
+            "ct.offset3" => current.phases[2].offset = value.parse()?,
+            "ct.fast" => current.fast_cycles = value.parse::<u32>()?.max(1),
+            "ct.smoothed" => {
+                current.smoothed_window = Duration::from_millis(value.parse::<u64>()?.max(100))
+            }
+            "cp.duty.mode" => {
+                duty.mapping = match value.as_ref() {
+                    "standard" => DutyMapping::Standard,
+                    _ => DutyMapping::Table,
+                }
+            }
+            "cp.duty" => match PilotDuty::parse_table(&value) {
+                Some(table) => duty.table = table,
+                None => {
+                    return show(
+                        req,
+                        diagnostics,
+                        Some(
+                            "The table needs at least two mA:duty points with increasing currents",
+                        ),
+                    );
+                }
+            },
+            _ => log::warn!("Unknown calibration key: {key}"),
+        }
+    }
+
+    if let Err(e) = config.save() {
+        log::warn!("Error saving calibration {e}");
+        show(req, diagnostics, Some("Error saving calibration"))
+    } else {
+        show(
+            req,
+            diagnostics,
+            Some("Calibration saved, restart to apply it"),
+        )
+    }
+}
+
+/// Calibrates the gain of a phase with the current read by a clamp meter while charging
+fn calibrate_current(
+    mut req: Request<&mut EspHttpConnection>,
+    diagnostics: &Mutex<Diagnostics>,
+) -> Result<(), anyhow::Error> {
+    let Some(data) = super::read_form(&mut req)? else {
+        return show(req, diagnostics, Some("The form is too large"));
+    };
+    let mut phase = 0;
+    let mut reference = 0;
+    for (key, value) in form_urlencoded::parse(&data) {
+        match key.as_ref() {
+            "phase" => phase = value.parse()?,
+            "reference" => reference = (value.parse::<f32>()? * 1000.0) as u32,
+            _ => log::warn!("Unknown calibration key: {key}"),
+        }
+    }
+    if !(1..=3).contains(&phase) {
+        return show(req, diagnostics, Some("Unknown phase"));
+    }
+
+    // Calibrate what is measured now, which may not be saved yet
+    let calibration = {
+        let diagnostics = diagnostics.lock().map_err(|_| anyhow!("Poisoned mutex"))?;
+        let measured = diagnostics.currents[phase - 1];
+        log::info!("Calibrating L{phase}: measured {measured} mA, reference {reference} mA");
+        diagnostics.current_calibration.phases[phase - 1].calibrate(measured, reference)
+    };
+    let Some(calibration) = calibration else {
+        return show(
+            req,
+            diagnostics,
+            Some("The phase needs to draw at least 3A, close to the reference"),
+        );
+    };
+
+    let mut config = PhiEvseConfig::load()?;
+    config.controller.current_calibration.phases[phase - 1] = calibration;
+    if let Err(e) = config.save() {
+        log::warn!("Error saving calibration {e}");
+        show(req, diagnostics, Some("Error saving calibration"))
+    } else {
+        show(
+            req,
+            diagnostics,
+            Some("Current calibrated, restart to apply it"),
+        )
+    }
+}
+
+fn test_duty(
+    mut req: Request<&mut EspHttpConnection>,
+    control_channel: &mpsc::Sender<ControlMessage>,
+) -> Result<(), anyhow::Error> {
+    let Some(data) = super::read_form(&mut req)? else {
+        return super::redirect(req, "/calibration");
+    };
+    let mut duty = None;
+    for (key, value) in form_urlencoded::parse(&data) {
+        if key == "duty" {
+            duty = Some(value.parse()?);
+        }
+    }
+    control_channel.send(ControlMessage::TestPilotDuty(duty))?;
+
+    req.into_response(302, Some("Found"), &[("Location", "/calibration")])?;
+    Ok(())
+}
+
+pub fn register(
+    httpd: &mut EspHttpServer,
+    diagnostics: Arc<Mutex<Diagnostics>>,
+    control_channel: mpsc::Sender<ControlMessage>,
+) -> Result<(), EspError> {
+    let d = diagnostics.clone();
+    httpd.fn_handler("/calibration", Method::Get, move |req| show(req, &d, None))?;
+    let d = diagnostics.clone();
+    httpd.fn_handler("/calibration", Method::Post, move |req| save(req, &d))?;
+    httpd.fn_handler("/calibration/current", Method::Post, move |req| {
+        calibrate_current(req, &diagnostics)
+    })?;
+    httpd.fn_handler("/calibration/duty", Method::Post, move |req| {
+        test_duty(req, &control_channel)
+    })?;
+
+    Ok(())
+}
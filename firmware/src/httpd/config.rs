@@ -1,10 +1,14 @@
 use askama::Template;
-use embedded_svc::http::server::Request;
 use embedded_svc::http::Method;
+use embedded_svc::http::server::Request;
 use embedded_svc::io::Write;
-use embedded_svc::utils::io::try_read_full;
 use esp_idf_svc::http::server::{EspHttpConnection, EspHttpServer};
 use esp_idf_sys::*;
+use phievse::settings::{
+    ControllerSettings, DiodeCheck, MIN_LIMIT_CURRENT, MainsFrequency, VOLTAGE_RANGE,
+};
+use std::num::ParseIntError;
+use std::time::Duration;
 
 use crate::config::*;
 
@@ -33,9 +37,10 @@ fn show(req: Request<&mut EspHttpConnection>, message: Option<&str>) -> Result<(
 }
 
 fn save(mut req: Request<&mut EspHttpConnection>) -> Result<(), anyhow::Error> {
-    let mut data = [0u8; 512];
-    let len = try_read_full(&mut req, &mut data).map_err(|e| e.0)?;
-    let form = form_urlencoded::parse(&data[..len]);
+    let Some(data) = super::read_form(&mut req)? else {
+        return show(req, Some("The form is too large"));
+    };
+    let form = form_urlencoded::parse(&data);
 
     let mut hostname: Option<String> = None;
     let mut sta_ssid: Option<String> = None;
@@ -43,24 +48,109 @@ fn save(mut req: Request<&mut EspHttpConnection>) -> Result<(), anyhow::Error> {
     let mut ap_ssid: Option<String> = None;
     let mut ap_psk: Option<String> = None;
     let mut mqtt_uri: Option<String> = None;
+    let mut controller = ControllerSettings::default();
+    // Unchecked checkboxes are not sent
+    controller.installation.three_phase = false;
+    controller.installation.supports_32a = false;
+    controller.allow_ventilation = false;
+    // Edited in the calibration page
+    let saved = PhiEvseConfig::load()?.controller;
+    controller.pilot_duty = saved.pilot_duty;
+    controller.current_calibration = saved.current_calibration;
 
-    for (key, value) in form {
-        if value.is_empty() {
-            continue;
-        }
-        match key.as_ref() {
-            "hostname" => hostname = Some(value.to_string()),
-            "sta.ssid" => sta_ssid = Some(value.to_string()),
-            "sta.psk" => sta_psk = Some(value.to_string()),
-            "ap.ssid" => ap_ssid = Some(value.to_string()),
-            "ap.psk" => ap_psk = Some(value.to_string()),
-            "mqtt.uri" => mqtt_uri = Some(value.to_string()),
-            _ => log::warn!("Unknown config key: {key}"),
-        }
+    let parsed = form
+        .into_iter()
+        .try_for_each(|(key, value)| -> Result<(), ParseIntError> {
+            if value.is_empty() {
+                return Ok(());
+            }
+            match key.as_ref() {
+                "hostname" => hostname = Some(value.to_string()),
+                "sta.ssid" => sta_ssid = Some(value.to_string()),
+                "sta.psk" => sta_psk = Some(value.to_string()),
+                "ap.ssid" => ap_ssid = Some(value.to_string()),
+                "ap.psk" => ap_psk = Some(value.to_string()),
+                "mqtt.uri" => mqtt_uri = Some(value.to_string()),
+                "inst.voltage" => controller.installation.voltage = value.parse()?,
+                "inst.breaker" => {
+                    controller.installation.breaker_current =
+                        value.parse::<u32>()?.saturating_mul(1000)
+                }
+                "inst.cable" => {
+                    controller.installation.cable_current =
+                        value.parse::<u32>()?.saturating_mul(1000)
+                }
+                "inst.3p" => controller.installation.three_phase = true,
+                "inst.32a" => controller.installation.supports_32a = true,
+                "inst.freq" => {
+                    controller.installation.frequency = match value.as_ref() {
+                        "50" => MainsFrequency::Hz50,
+                        "60" => MainsFrequency::Hz60,
+                        _ => MainsFrequency::Auto,
+                    }
+                }
+                "cp.connected" => controller.pilot.connected = value.parse()?,
+                "cp.ready" => controller.pilot.ready = value.parse()?,
+                "cp.vent" => controller.pilot.ventilation = value.parse()?,
+                "cp.short" => controller.pilot.short_circuit = value.parse()?,
+                "cp.hyst" => controller.pilot.hysteresis = value.parse()?,
+                "cp.frames" => controller.pilot.frames = value.parse()?,
+                "phase.rest" => controller.phase_switch_rest = Duration::from_secs(value.parse()?),
+                "phase.up" => controller.three_phase_above = value.parse()?,
+                "phase.down" => controller.one_phase_below = value.parse()?,
+                "phase.dwell" => controller.phase_min_dwell = Duration::from_secs(value.parse()?),
+                "phase.daily" => controller.phase_max_daily_switches = value.parse()?,
+                "start.timeout" => {
+                    controller.charge_start_timeout = Duration::from_secs(value.parse()?)
+                }
+                "start.wakeups" => controller.wake_up_attempts = value.parse()?,
+                "vent.allow" => controller.allow_ventilation = true,
+                "diode.check" => {
+                    controller.diode_check = match value.as_ref() {
+                        "warn" => DiodeCheck::WarnOnly,
+                        _ => DiodeCheck::Enforce,
+                    }
+                }
+                _ => log::warn!("Unknown config key: {key}"),
+            }
+            Ok(())
+        });
+    if parsed.is_err() {
+        return show(req, Some("Numeric fields must be whole numbers"));
     }
     if hostname.is_none() || ap_ssid.is_none() {
         return show(req, Some("Hostname and AP SSID are mandatory"));
     }
+    let installation = &controller.installation;
+    if !VOLTAGE_RANGE.contains(&installation.voltage) {
+        let message = format!(
+            "Voltage must be between {} and {} V",
+            VOLTAGE_RANGE.start(),
+            VOLTAGE_RANGE.end()
+        );
+        return show(req, Some(&message));
+    }
+    if installation.breaker_current < MIN_LIMIT_CURRENT
+        || installation.cable_current < MIN_LIMIT_CURRENT
+    {
+        let message = format!(
+            "Breaker and cable currents must be at least {} A",
+            MIN_LIMIT_CURRENT / 1000
+        );
+        return show(req, Some(&message));
+    }
+    if !controller.pilot.is_valid() {
+        return show(
+            req,
+            Some("Pilot thresholds must be increasing and further apart than twice the hysteresis"),
+        );
+    }
+    if controller.one_phase_below > controller.three_phase_above {
+        return show(
+            req,
+            Some("Power to switch to 1 phase must be lower than the power to switch to 3 phases"),
+        );
+    }
 
     let config = PhiEvseConfig {
         hostname: hostname.unwrap(),
@@ -70,6 +160,7 @@ fn save(mut req: Request<&mut EspHttpConnection>) -> Result<(), anyhow::Error> {
         },
         sta: sta_ssid.map(|ssid| WifiConfig { ssid, psk: sta_psk }),
         mqtt_uri,
+        controller,
     };
 
     if let Err(e) = config.save() {
@@ -1,18 +1,30 @@
 use std::{
-    sync::{mpsc, Arc, Mutex},
+    sync::{Arc, Mutex, mpsc},
     thread,
     time::Duration,
 };
 
 use anyhow::anyhow;
 use askama::Template;
-use embedded_svc::{http::server::*, io::Write, utils::io::try_read_full};
+use embedded_svc::{
+    http::{Headers, server::*},
+    io::Write,
+    utils::io::try_read_full,
+};
 use esp_idf_svc::http::server::*;
-use phievse::{logger::StringRingBuffer, ControlMessage, PhiEvseStatus};
+use phievse::{
+    ControlMessage, Diagnostics, PhiEvseStatus,
+    logger::StringRingBuffer,
+    session::{Session, SessionHistory},
+};
 
+mod calibration;
 mod config;
 mod ota;
 
+/// Forms larger than this are rejected
+const MAX_FORM_LEN: u64 = 4096;
+
 #[derive(Template)]
 #[template(path = "log.html")]
 struct LogTemplate<'a, const S: usize> {
@@ -25,6 +37,41 @@ struct LogTemplate<'a, const S: usize> {
 struct StatusTemplate<'a> {
     page: &'a str,
     status: &'a PhiEvseStatus,
+    /// Phases (e.g: "L2") with a faulty CT
+    faulty_cts: Vec<String>,
+}
+
+#[derive(Template)]
+#[template(path = "sessions.html")]
+struct SessionsTemplate<'a> {
+    page: &'a str,
+    history: &'a SessionHistory,
+    rows: Vec<SessionRow<'a>>,
+}
+
+struct SessionRow<'a> {
+    session: &'a Session,
+    /// Duration (min)
+    minutes: u64,
+}
+
+#[derive(Template)]
+#[template(path = "diagnostics.html")]
+struct DiagnosticsTemplate<'a> {
+    page: &'a str,
+    diagnostics: &'a Diagnostics,
+}
+
+mod filters {
+    use time::{OffsetDateTime, format_description::well_known::Rfc3339};
+
+    /// Formats a Unix time (s)
+    pub fn datetime(secs: &u64) -> askama::Result<String> {
+        Ok(OffsetDateTime::from_unix_timestamp(*secs as i64)
+            .ok()
+            .and_then(|t| t.format(&Rfc3339).ok())
+            .unwrap_or_default())
+    }
 }
 
 fn milligram(req: Request<&mut EspHttpConnection>) -> anyhow::Result<()> {
@@ -42,6 +89,19 @@ fn ota_restart(req: Request<&mut EspHttpConnection>) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Reads the whole form posted, `None` if it's larger than `MAX_FORM_LEN`
+fn read_form(req: &mut Request<&mut EspHttpConnection>) -> anyhow::Result<Option<Vec<u8>>> {
+    let len = req.content_len().unwrap_or(0);
+    if len > MAX_FORM_LEN {
+        log::warn!("Rejecting a form of {len} bytes");
+        return Ok(None);
+    }
+    let mut data = vec![0u8; len as usize];
+    let read = try_read_full(req, &mut data).map_err(|e| e.0)?;
+    data.truncate(read);
+    Ok(Some(data))
+}
+
 fn redirect(req: Request<&mut EspHttpConnection>, to: &str) -> anyhow::Result<()> {
     req.into_response(302, Some("Found"), &[("Location", to)])?;
 
@@ -51,6 +111,8 @@ fn redirect(req: Request<&mut EspHttpConnection>, to: &str) -> anyhow::Result<()
 pub fn start<'a, const S: usize>(
     log_buffer: Arc<Mutex<Box<StringRingBuffer<S>>>>,
     status: Arc<Mutex<PhiEvseStatus>>,
+    sessions: Arc<Mutex<SessionHistory>>,
+    diagnostics: Arc<Mutex<Diagnostics>>,
     control_channel: mpsc::Sender<ControlMessage>,
 ) -> anyhow::Result<EspHttpServer<'a>> {
     let mut httpd = EspHttpServer::new(&Configuration {
@@ -63,10 +125,16 @@ pub fn start<'a, const S: usize>(
     // Status
     let st = status.clone();
     httpd.fn_handler("/", Method::Get, move |req| -> anyhow::Result<()> {
+        let status = st.lock().map_err(|_| anyhow!("Poisoned mutex"))?;
         let mut response = req.into_ok_response()?;
         response.write_all(
             StatusTemplate {
-                status: &*st.lock().map_err(|_| anyhow!("Poisoned mutex"))?,
+                status: &status,
+                faulty_cts: (1..=3)
+                    .zip(status.faulty_cts)
+                    .filter(|&(_, faulty)| faulty)
+                    .map(|(phase, _)| format!("L{phase}"))
+                    .collect(),
                 page: "status",
             }
             .render()?
@@ -89,14 +157,75 @@ pub fn start<'a, const S: usize>(
         redirect(req, "/")
     })?;
 
+    let cc = control_channel.clone();
     httpd.fn_handler("/shutdown", Method::Post, move |req| {
-        control_channel.send(ControlMessage::Shutdown)?;
+        cc.send(ControlMessage::Shutdown)?;
 
         redirect(req, "/")
     })?;
 
     httpd.fn_handler("/restart", Method::Post, ota_restart)?;
 
+    // Sessions
+    let se = sessions.clone();
+    httpd.fn_handler("/sessions", Method::Get, move |req| -> anyhow::Result<()> {
+        let history = se.lock().map_err(|_| anyhow!("Poisoned mutex"))?;
+        let mut response = req.into_ok_response()?;
+        response.write_all(
+            SessionsTemplate {
+                history: &history,
+                rows: history
+                    .sessions()
+                    .iter()
+                    .map(|session| SessionRow {
+                        session,
+                        minutes: session.end.saturating_sub(session.start) / 60,
+                    })
+                    .collect(),
+                page: "sessions",
+            }
+            .render()?
+            .as_bytes(),
+        )?;
+        Ok(())
+    })?;
+
+    httpd.fn_handler(
+        "/sessions.json",
+        Method::Get,
+        move |req| -> anyhow::Result<()> {
+            let json = serde_json::to_vec(
+                sessions
+                    .lock()
+                    .map_err(|_| anyhow!("Poisoned mutex"))?
+                    .sessions(),
+            )?;
+            let mut response =
+                req.into_response(200, Some("OK"), &[("Content-Type", "application/json")])?;
+            response.write_all(&json)?;
+            Ok(())
+        },
+    )?;
+
+    // Diagnostics
+    let di = diagnostics.clone();
+    httpd.fn_handler(
+        "/diagnostics",
+        Method::Get,
+        move |req| -> anyhow::Result<()> {
+            let mut response = req.into_ok_response()?;
+            response.write_all(
+                DiagnosticsTemplate {
+                    diagnostics: &*di.lock().map_err(|_| anyhow!("Poisoned mutex"))?,
+                    page: "diagnostics",
+                }
+                .render()?
+                .as_bytes(),
+            )?;
+            Ok(())
+        },
+    )?;
+
     // Logs
     httpd.fn_handler("/log", Method::Get, move |req| -> anyhow::Result<()> {
         let mut response = req.into_ok_response()?;
@@ -116,6 +245,7 @@ pub fn start<'a, const S: usize>(
 
     // Config
     config::register(&mut httpd)?;
+    calibration::register(&mut httpd, diagnostics, control_channel)?;
 
     Ok(httpd)
 }
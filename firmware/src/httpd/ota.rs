@@ -1,8 +1,8 @@
 use std::sync::{Arc, Mutex};
 
 use askama::Template;
-use embedded_svc::http::server::Request;
 use embedded_svc::http::Method;
+use embedded_svc::http::server::Request;
 use embedded_svc::io::Write;
 use embedded_svc::ota::*;
 use esp_idf_svc::http::server::{EspHttpConnection, EspHttpServer};
@@ -83,7 +83,8 @@ impl Drop for LedController {
 
 impl LedController {
     pub fn new<T: LedDriver>(driver: T) -> Self {
-        let (tx, rx): (Sender<LedControllerCommand>, Receiver<LedControllerCommand>) = mpsc::channel();
+        let (tx, rx): (Sender<LedControllerCommand>, Receiver<LedControllerCommand>) =
+            mpsc::channel();
         let pattern = Arc::new(Mutex::new(vec![LedPatternItem::fixed(Color::OFF)]));
 
         let thread = LedControllerThread {
@@ -153,7 +154,9 @@ impl<T: LedDriver> LedControllerThread<T> {
             let command = if wait.is_zero() {
                 self.rx.recv().unwrap_or(LedControllerCommand::Timeout)
             } else {
-                self.rx.recv_timeout(wait).unwrap_or(LedControllerCommand::Timeout)
+                self.rx
+                    .recv_timeout(wait)
+                    .unwrap_or(LedControllerCommand::Timeout)
             };
 
             match command {
@@ -174,7 +177,7 @@ impl<T: LedDriver> LedControllerThread<T> {
 #[cfg(test)]
 mod tests {
     use super::{Color, LedController, MockLedDriver};
-    use mockall::{predicate::*, Sequence};
+    use mockall::{Sequence, predicate::*};
     use std::{thread, time::Duration};
 
     #[test]
@@ -245,7 +248,11 @@ mod tests {
         let led = LedController::new(driver);
         thread::sleep(Duration::from_millis(10));
 
-        led.set_blink(Color { r: 10, g: 10, b: 10 });
+        led.set_blink(Color {
+            r: 10,
+            g: 10,
+            b: 10,
+        });
         thread::sleep(Duration::from_millis(700));
     }
 }
@@ -1,40 +1,135 @@
+use ac_sense::{AcDetector, SupplyMonitor};
 use adc::{AdcChannel, AdcSubscriber};
-use control_pilot::{ControlPilotMode, ControlPilotReader, ControlPilotSignal, set_control_pilot};
-use current_meter::CurrentMeter;
+use control_pilot::{
+    ControlPilotMode, ControlPilotReader, ControlPilotSignal, MAX_DUTY, set_control_pilot,
+};
+use current_meter::{CtHealth, CtProfile, CurrentMeter, CurrentReadings, EXTRA_RESISTORS};
 use embedded_hal::{PwmPin, digital::v2::InputPin};
 use serde::Serialize;
 use std::{
-    cmp::min,
     fmt::Display,
     sync::{
         Arc, Mutex,
         atomic::{AtomicU32, Ordering},
         mpsc,
     },
-    thread::sleep,
     time::Duration,
 };
 
+use car_phases::CarPhaseDetector;
+use clock::Clock;
+use energy::EnergyMeter;
+use fault::{Fault, FaultEvent, MAX_RECENT_FAULTS};
 use gpio::{AlarmInput, AlarmReceiver, RelayPin};
+use overcurrent::{OvercurrentAction, OvercurrentGuard};
+use phase_planner::{MIN_CURRENT, PhaseHold, PhasePlanner};
+use pilot_pwm::{PilotPwm, PwmCheck, PwmMonitor};
+use regulator::CurrentRegulator;
+use session::{EndReason, SessionHistory, SessionRecorder};
+use settings::{ControllerSettings, CurrentCalibration, DiodeCheck};
+use storage::Storage;
 use watchdog::Watchdog;
 
+pub mod ac_sense;
 pub mod adc;
+pub mod car_phases;
+pub mod clock;
 mod control_pilot;
-mod current_meter;
+pub mod current_meter;
+pub mod energy;
+pub mod fault;
 pub mod gpio;
 pub mod led;
 pub mod logger;
+pub mod overcurrent;
+pub mod phase_planner;
+pub mod pilot_pwm;
+pub mod regulator;
+pub mod session;
+pub mod settings;
+pub mod storage;
 pub mod watchdog;
 
 #[cfg(target_arch = "riscv32")]
 pub mod driver;
 
+#[cfg(not(target_arch = "riscv32"))]
+pub mod sim;
+
+/// Time between iterations of the state machine
+const TICK: Duration = Duration::from_millis(100);
+
+/// Time to wait for the car to stop drawing current before opening the contactors
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Time to wait after the car is ready before closing the contactors
+const RELAY_CLOSE_DELAY: Duration = Duration::from_millis(500);
+
+/// Storage key of the lifetime energy counter (Wh)
+const LIFETIME_ENERGY_KEY: &str = "energy.total";
+
+/// Time between attempts to load the persisted data when it fails
+const STORAGE_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Time for the contactors to settle before checking the voltage after them
+const RELAY_CHECK_DELAY: Duration = Duration::from_millis(200);
+
+/// Window to detect AC on the voltage sense inputs (3 periods at 50Hz) and interval between samples
+const AC_SENSE_WINDOW: Duration = Duration::from_millis(60);
+const AC_SENSE_INTERVAL: Duration = Duration::from_millis(1);
+
+/// While running, the voltage sense inputs are sampled a few times on each tick instead of
+/// blocking for a whole window. The loop takes `TICK` plus these samples, so the samples of
+/// consecutive ticks land at different points of the mains period.
+const AC_SENSE_TICK_SAMPLES: u32 = 3;
+/// Ticks sampled to detect AC while running
+const AC_SENSE_TICKS: u32 = 10;
+
+/// Time for the current meters to publish a first measurement
+const CURRENT_METER_SETTLE: Duration = Duration::from_millis(1200);
+
+/// Maximum pilot reading at +12V, between the readings with the car charging (C) and asking for
+/// ventilation (D)
+const SELF_TEST_PILOT_MAX_MV: i32 = 1950;
+
+/// Maximum pilot reading at -12V
+const SELF_TEST_PILOT_NEGATIVE_MAX_MV: i32 = 50;
+
+/// Maximum current read with the contactors open (mA)
+const SELF_TEST_MAX_CURRENT: u32 = 1000;
+
+/// Consecutive ticks with the pilot negative low before the diode check fails, so glitches on the
+/// alarm are ignored
+const DIODE_CHECK_TICKS: u32 = 3;
+
+/// Time between updates of the current regulator while charging
+const REGULATION_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Minimum change of the offered current to update the pilot (mA)
+const PILOT_MIN_STEP: u32 = 100;
+
+/// Time the pilot is interrupted to wake up a car that doesn't draw current
+const WAKE_UP_PAUSE: Duration = Duration::from_secs(3);
+
+/// Time to keep the pilot at -12V after a fault. Then it goes back to +12V (without PWM) so the car
+/// being unplugged can be seen.
+const FAULT_PILOT_HOLD: Duration = Duration::from_secs(3);
+
+/// Time for the pilot reading to settle after changing the signal
+const PILOT_SETTLE: Duration = Duration::from_millis(500);
+
+/// Interval between charging logs
+const LOG_INTERVAL: Duration = Duration::from_secs(5);
+
 pub enum ControlMessage {
     SetMaxPower(u32),
     Shutdown,
+    /// Drives the pilot with a raw duty to calibrate the duty mapping, or stops with `None`. Only
+    /// without a car.
+    TestPilotDuty(Option<u32>),
 }
 
-pub struct PhiEvsePeripherals<A, CP, CPN, R1, R2, L1, L2, L3, L3S, W>
+pub struct PhiEvsePeripherals<A, CP, CPN, R1, R2, L1, L2, L3, L3S, W, K, S>
 where
     A: AdcSubscriber,
     CP: PwmPin,
@@ -46,6 +141,8 @@ where
     L3: InputPin,
     L3S: InputPin,
     W: Watchdog,
+    K: Clock,
+    S: Storage,
 {
     // /// Main contactor
     pub relay_main: RelayPin<R1>,
@@ -64,6 +161,8 @@ where
     pub v_sense_3_phase: L3S,
 
     pub watchdog: W,
+    pub clock: K,
+    pub storage: S,
 }
 
 #[derive(PartialEq, Debug, Copy, Clone, Default, Serialize)]
@@ -73,6 +172,9 @@ pub enum PhiEvseState {
     Connected,
     Ready,
     Charging,
+    SwitchingPhases,
+    /// Interrupting the pilot as the car did not draw current
+    WakingUp,
     Error,
     Stopping,
     ShuttingDown,
@@ -85,14 +187,80 @@ impl Display for PhiEvseState {
     }
 }
 
+/// Steps to switch between 1 and 3 phases without load on the contactors
+#[derive(PartialEq, Debug, Copy, Clone)]
+enum PhaseSwitch {
+    /// Pilot in standby, waiting (until the deadline) for the car to stop drawing current
+    Pausing(Duration),
+    /// Contactors open and phases switched, resting until the given time
+    Resting(Duration),
+}
+
+/// Result of one of the checks at power on
+#[derive(Clone, Serialize)]
+pub struct SelfTestCheck {
+    pub name: &'static str,
+    pub passed: bool,
+    pub detail: String,
+}
+
+/// Information to troubleshoot the charger, updated less often than the status
+#[derive(Clone, Default, Serialize)]
+pub struct Diagnostics {
+    pub self_test: Vec<SelfTestCheck>,
+    /// Last (maximum) pilot reading (mV), to calibrate the thresholds
+    pub pilot_mv: i32,
+    /// Pilot state changes since started
+    pub pilot_transitions: u32,
+    /// Pilot readings ignored as they did not last enough to change the state
+    pub pilot_glitches: u32,
+    /// Raw duty driven on the pilot to calibrate it
+    pub pilot_test_duty: Option<u32>,
+    /// Current measured on each phase (mA)
+    pub currents: [u32; 3],
+    /// Calibration the currents are measured with, to calibrate them further
+    pub current_calibration: CurrentCalibration,
+    /// Offset and noise of each CT, learnt while the contactors are open
+    pub ct_profiles: [Option<CtProfile>; 3],
+}
+
 #[derive(Clone, Default, Serialize)]
 pub struct PhiEvseStatus {
     pub power: u32,
     pub state: PhiEvseState,
     pub max_power: u32,
+    /// Maximum power allowed by the installation
+    pub power_limit: u32,
+    pub three_phase: bool,
+    /// Phases 2 and 3 are available at the input
+    pub three_phase_supply: bool,
+    /// Grid frequency (Hz), measured while charging
+    pub mains_frequency: Option<f32>,
+    /// Phases the car draws current from, once detected while charging with 3 phases
+    pub car_phases: Option<u8>,
+    /// The car draws very different currents from each phase, probably a wiring problem
+    pub phase_imbalance: bool,
+    /// The CT of each phase looks disconnected or faulty
+    pub faulty_cts: [bool; 3],
+    /// Why phases are not being switched, if the power asks for it
+    pub phase_hold: Option<PhaseHold>,
+    /// Phase switches in the last 24 hours
+    pub phase_switches: u32,
+    /// Pilot PWM measured, while a car is connected
+    pub pilot_pwm: Option<PilotPwm>,
+    /// Current (mA) the car reads from the measured pilot PWM
+    pub pilot_current: Option<u32>,
+    /// Energy of the current or last session (Wh)
+    pub session_energy: u32,
+    /// Energy delivered since the charger was installed (Wh)
+    pub lifetime_energy: u64,
+    /// Why the controller is in `Error`, cleared when the car is unplugged
+    pub fault: Option<Fault>,
+    /// Last faults, newest first
+    pub recent_faults: Vec<FaultEvent>,
 }
 
-pub struct PhiEvseController<A, CP, CPN, R1, R2, L1, L2, L3, L3S, W>
+pub struct PhiEvseController<A, CP, CPN, R1, R2, L1, L2, L3, L3S, W, K, S>
 where
     A: AdcSubscriber,
     CP: PwmPin,
@@ -104,25 +272,72 @@ where
     L3: InputPin,
     L3S: InputPin,
     W: Watchdog,
+    K: Clock,
+    S: Storage,
 {
-    peripherals: PhiEvsePeripherals<A, CP, CPN, R1, R2, L1, L2, L3, L3S, W>,
+    peripherals: PhiEvsePeripherals<A, CP, CPN, R1, R2, L1, L2, L3, L3S, W, K, S>,
+    settings: ControllerSettings,
+    phase_planner: PhasePlanner,
+    energy: EnergyMeter,
+    /// Session in progress, from plug in to unplug
+    session: Option<SessionRecorder>,
+    /// Persisted data could be loaded, so it's safe to overwrite it
+    storage_loaded: bool,
+    storage_retry_at: Duration,
 
-    current: [AtomicU32; 3],
-    control_pilot: ControlPilotReader,
+    current: [Arc<CurrentReadings>; 3],
+    /// Measured from the current of any phase (cHz)
+    mains_frequency: Arc<AtomicU32>,
+    ct_health: [Arc<CtHealth>; 3],
+    control_pilot: Arc<ControlPilotReader>,
     state: PhiEvseState,
+    prev_state: PhiEvseState,
+    state_since: Duration,
+    fault: Option<Fault>,
+    /// The fault can't be cleared by unplugging, only by rebooting
+    fault_latched: bool,
+    /// Pilot is back to +12V in `Error`
+    fault_pilot_released: bool,
+    /// Consecutive ticks with the pilot negative low
+    pilot_negative_low: u32,
+    /// Contactor levels at the last check of the voltage sense inputs
+    checked_relays: (bool, bool),
+    relay_check_at: Option<Duration>,
+    /// The contactors settled, check them with the next AC detection
+    relay_check_pending: bool,
+    supply: SupplyMonitor,
+    /// AC detection of L1, L2, L3 and the 3-phase supply in progress
+    ac_detectors: [AcDetector; 4],
+    ac_ticks: u32,
     max_power: u32,
     max_current: u32,
     three_phase: bool,
-    current_adjustment: i32,
+    regulator: CurrentRegulator,
+    /// Current offered by the pilot (mA)
+    offered_current: u32,
+    overcurrent: OvercurrentGuard,
+    car_phases: CarPhaseDetector,
+    pilot_test_duty: Option<u32>,
+    pwm_monitor: PwmMonitor,
+    changing_power: bool,
+    next_current_adjustment: Duration,
+    stop_deadline: Duration,
+    /// The car has to draw current before this, otherwise it's woken up
+    charge_start_deadline: Option<Duration>,
+    wake_ups: u32,
+    phase_switch: PhaseSwitch,
+    next_log: Duration,
 
     status: Arc<Mutex<PhiEvseStatus>>,
+    sessions: Arc<Mutex<SessionHistory>>,
+    diagnostics: Arc<Mutex<Diagnostics>>,
 
     control_tx: mpsc::Sender<ControlMessage>,
     control_rx: mpsc::Receiver<ControlMessage>,
 }
 
-impl<A, CP, CPN, R1, R2, L1, L2, L3, L3S, W>
-    PhiEvseController<A, CP, CPN, R1, R2, L1, L2, L3, L3S, W>
+impl<A, CP, CPN, R1, R2, L1, L2, L3, L3S, W, K, S>
+    PhiEvseController<A, CP, CPN, R1, R2, L1, L2, L3, L3S, W, K, S>
 where
     A: AdcSubscriber,
     CP: PwmPin<Duty = u32>,
@@ -134,20 +349,63 @@ where
     L3: InputPin,
     L3S: InputPin,
     W: Watchdog,
+    K: Clock,
+    S: Storage,
 {
-    pub fn new(peripherals: PhiEvsePeripherals<A, CP, CPN, R1, R2, L1, L2, L3, L3S, W>) -> Self {
+    pub fn new(
+        peripherals: PhiEvsePeripherals<A, CP, CPN, R1, R2, L1, L2, L3, L3S, W, K, S>,
+        settings: ControllerSettings,
+    ) -> Self {
         let (tx, rx) = mpsc::channel();
 
         Self {
             current: Default::default(),
+            mains_frequency: Default::default(),
+            ct_health: Default::default(),
             peripherals,
-            control_pilot: Default::default(),
+            phase_planner: PhasePlanner::new(&settings),
+            regulator: CurrentRegulator::new(settings.installation.max_current()),
+            overcurrent: OvercurrentGuard::new(settings.installation.max_current()),
+            control_pilot: Arc::new(ControlPilotReader::new(&settings.pilot)),
+            energy: EnergyMeter::new(0),
+            session: None,
+            storage_loaded: false,
+            storage_retry_at: Duration::ZERO,
+            status: Arc::new(Mutex::new(PhiEvseStatus {
+                power_limit: settings.installation.max_power(),
+                ..Default::default()
+            })),
+            sessions: Default::default(),
+            diagnostics: Default::default(),
+            settings,
             state: PhiEvseState::NotConnected,
+            prev_state: PhiEvseState::NotConnected,
+            state_since: Duration::ZERO,
+            fault: None,
+            fault_latched: false,
+            fault_pilot_released: false,
+            pilot_negative_low: 0,
+            checked_relays: (false, false),
+            relay_check_at: None,
+            relay_check_pending: false,
+            supply: SupplyMonitor::new(true),
+            ac_detectors: Default::default(),
+            ac_ticks: 0,
             max_power: 0,
             max_current: 0,
             three_phase: false,
-            status: Default::default(),
-            current_adjustment: 0,
+
+            offered_current: 0,
+            car_phases: Default::default(),
+            pilot_test_duty: None,
+            pwm_monitor: Default::default(),
+            changing_power: false,
+            next_current_adjustment: Duration::ZERO,
+            stop_deadline: Duration::ZERO,
+            charge_start_deadline: None,
+            wake_ups: 0,
+            phase_switch: PhaseSwitch::Pausing(Duration::ZERO),
+            next_log: Duration::ZERO,
             control_tx: tx,
             control_rx: rx,
         }
@@ -157,21 +415,45 @@ where
         self.status.clone()
     }
 
+    /// Last completed charging sessions
+    pub fn sessions(&self) -> Arc<Mutex<SessionHistory>> {
+        self.sessions.clone()
+    }
+
+    pub fn diagnostics(&self) -> Arc<Mutex<Diagnostics>> {
+        self.diagnostics.clone()
+    }
+
     pub fn control_channel(&self) -> mpsc::Sender<ControlMessage> {
         self.control_tx.clone()
     }
 
-    pub fn run(&'static mut self) -> ! {
-        let mut set_control_pilot =
-            |signal| set_control_pilot(&mut self.peripherals.control_pilot, signal);
+    /// Runs the state machine forever
+    pub fn run(&mut self) -> ! {
+        self.init();
+        loop {
+            self.step(self.peripherals.clock.now());
+            self.peripherals.clock.sleep(TICK);
+        }
+    }
 
+    /// Starts the measurements and alarms. Needs to be called once before `step`.
+    pub fn init(&mut self) {
         // Initialize current meters / ADC
-        let mut current_meters = [
-            CurrentMeter::new(&self.current[0], 0.8),
-            CurrentMeter::new(&self.current[1], 1.4),
-            CurrentMeter::new(&self.current[2], 1.6),
-        ];
-        let cp = &self.control_pilot;
+        let calibration = &self.settings.current_calibration;
+        let mut current_meters = [0, 1, 2].map(|phase| {
+            CurrentMeter::new(
+                self.current[phase].clone(),
+                self.mains_frequency.clone(),
+                self.ct_health[phase].clone(),
+                self.settings.installation.frequency,
+                EXTRA_RESISTORS[phase],
+                calibration,
+                phase,
+            )
+        });
+        self.diagnostics.lock().unwrap().current_calibration = calibration.clone();
+        let cp = self.control_pilot.clone();
         self.peripherals.analog.subscribe(move |c, d| match c {
             AdcChannel::CurrentL1 => current_meters[0].receive(d),
             AdcChannel::CurrentL2 => current_meters[1].receive(d),
@@ -179,277 +461,1876 @@ where
             AdcChannel::ControlPilot => cp.receive(d),
         });
 
+        // Load persisted data
+        self.load_storage(self.peripherals.clock.now());
+
         // Initialize CP negative alarm
-        let cp_negative = &self.control_pilot.negative;
+        let cp = self.control_pilot.clone();
         self.peripherals
             .pilot_negative
-            .subscribe(AlarmReceiver(Box::new(|| {
-                cp_negative.store(true, std::sync::atomic::Ordering::Relaxed)
+            .subscribe(AlarmReceiver(Box::new(move || {
+                cp.negative.store(true, Ordering::Relaxed)
             })));
 
-        set_control_pilot(ControlPilotSignal::Standby);
+        set_control_pilot(
+            &mut self.peripherals.control_pilot,
+            ControlPilotSignal::Standby,
+            &self.settings.pilot_duty,
+            0,
+        );
 
-        // TODO: Safety checks
+        self.self_test();
+
+        let [.., supply] = self.sense_ac();
+        self.supply = SupplyMonitor::new(supply);
+        self.phase_planner.set_supply_three_phase(supply);
+        self.status.lock().unwrap().three_phase_supply = supply;
+        log::info!("3-phase supply available: {supply}");
 
         // Wait for everything to settle before reading CP and enabling watchdogs/alarms
-        sleep(Duration::from_millis(500));
+        self.peripherals.clock.sleep(Duration::from_millis(500));
+        self.control_pilot.negative_alarm();
         self.peripherals.pilot_negative.arm();
         self.peripherals.watchdog.init(Duration::from_secs(2));
+    }
 
-        // State machine
-        let mut i = 0;
-        let mut changing_power = false;
-        let mut next_current_adjustment = 0;
-        let mut prev_state = PhiEvseState::NotConnected;
-        let mut stop_timeout = 0;
-        loop {
-            i = (i + 1) % 50;
-            self.peripherals.watchdog.reset();
-
-            let total_mamps: u32 = self.current.iter().map(|c| c.load(Ordering::Relaxed)).sum();
-            self.status.lock().unwrap().power = total_mamps * 230 / 1000;
-
-            // Receive commands
-            if let Ok(msg) = self.control_rx.try_recv() {
-                match msg {
-                    ControlMessage::SetMaxPower(watts) => {
-                        self.max_power = match watts {
-                            0..=1499 => 0,
-                            1500..=11000 => watts,
-                            _ => 11000,
-                        };
-                        self.status.lock().unwrap().max_power = self.max_power;
-                        (self.max_current, self.three_phase) = calculate_power(self.max_power);
-                        changing_power = true;
-                        log::info!(
-                            "Setting max power to {}W ({} mA, 3p={})",
-                            watts,
-                            self.max_current,
-                            self.three_phase
-                        );
-                    }
-                    ControlMessage::Shutdown => {
-                        if self.state == PhiEvseState::Charging {
-                            self.state = PhiEvseState::ShuttingDown;
-                            stop_timeout = 50;
-                        } else {
-                            self.state = PhiEvseState::Shutdown;
-                        }
-                    }
-                }
+    /// Runs one iteration of the state machine. Should be called every `TICK`.
+    pub fn step(&mut self, now: Duration) {
+        self.peripherals.watchdog.reset();
+        self.peripherals.relay_main.update(now);
+        // Learn the profile of the CTs while there can't be any current
+        let contactors_open = !self.peripherals.relay_main.level();
+        for ct in &self.ct_health {
+            ct.idle.store(contactors_open, Ordering::Relaxed);
+        }
+        self.peripherals.relay_3_phase.update(now);
+
+        // Check the voltage after the contactors with samples taken once they settled
+        if self.relay_check_at.is_some_and(|t| now >= t) {
+            self.relay_check_at = None;
+            self.relay_check_pending = true;
+            self.ac_detectors = Default::default();
+            self.ac_ticks = 0;
+        }
+        let ac = self.sample_ac();
+        if let Some([.., supply]) = ac
+            && self.supply.update(supply, now)
+        {
+            let available = self.supply.available();
+            log::warn!("3-phase supply available: {available}");
+            self.phase_planner.set_supply_three_phase(available);
+        }
+        if let Some(ac) = ac
+            && std::mem::take(&mut self.relay_check_pending)
+            && let Some(fault) = self.check_relays(ac)
+        {
+            if !self.fault_latched {
+                self.fault = Some(fault);
+                self.fault_latched = fault == Fault::RelayWelded;
             }
+            if self.state == PhiEvseState::Error {
+                // Might have released the pilot already
+                set_control_pilot(
+                    &mut self.peripherals.control_pilot,
+                    ControlPilotSignal::Error,
+                    &self.settings.pilot_duty,
+                    0,
+                );
+                self.fault_pilot_released = false;
+            }
+            self.state = PhiEvseState::Error;
+        }
+
+        self.check_diode();
+        let duty = self.peripherals.control_pilot.get_duty();
+        let pilot_held_negative = duty == 0;
 
-            // Check safety indicators first
-            let cp_state = self.control_pilot.state();
-            if cp_state == ControlPilotMode::Error && self.state != PhiEvseState::Error {
-                log::error!("Pilot check failed, IGNORE");
+        // Ventilation is the same as C when allowed
+        let cp_state = match self.control_pilot.state(pilot_held_negative) {
+            ControlPilotMode::VentilationRequired if self.settings.allow_ventilation => {
+                ControlPilotMode::Ready
             }
-            if !self.peripherals.pilot_negative.is_high() {
-                log::error!("Pilot negative is low right now, STOP");
+            mode => mode,
+        };
+
+        // Check the car reads the current offered from the pilot
+        let offering = matches!(
+            self.state,
+            PhiEvseState::Connected | PhiEvseState::Ready | PhiEvseState::Charging
+        ) && matches!(
+            cp_state,
+            ControlPilotMode::Connected | ControlPilotMode::Ready
+        ) && self.pilot_test_duty.is_none()
+            && duty > 0
+            && duty < MAX_DUTY;
+        let pwm = self.control_pilot.pwm();
+        match self
+            .pwm_monitor
+            .update(offering.then_some(self.offered_current), duty, pwm, now)
+        {
+            Some(PwmCheck::Trim(trim)) => set_control_pilot(
+                &mut self.peripherals.control_pilot,
+                ControlPilotSignal::Charge(self.offered_current),
+                &self.settings.pilot_duty,
+                trim,
+            ),
+            Some(PwmCheck::Fault) if self.state != PhiEvseState::Error => {
+                log::error!("{}, STOP", Fault::PilotPwmMismatch);
+                self.fault = Some(Fault::PilotPwmMismatch);
                 self.state = PhiEvseState::Error;
             }
+            _ => {}
+        }
 
-            match self.state {
-                PhiEvseState::NotConnected | PhiEvseState::Connected => {
-                    // Wait until EV is connected and ready to charge
-                    self.state = match cp_state {
-                        ControlPilotMode::NotConnected => PhiEvseState::NotConnected,
-                        ControlPilotMode::Connected => PhiEvseState::Connected,
-                        ControlPilotMode::Ready => PhiEvseState::Ready,
-                        ControlPilotMode::Error => PhiEvseState::Error,
+        let pilot_duty = &self.settings.pilot_duty;
+        let pilot_trim = self.pwm_monitor.trim();
+        let mut set_control_pilot = |signal| {
+            set_control_pilot(
+                &mut self.peripherals.control_pilot,
+                signal,
+                pilot_duty,
+                pilot_trim,
+            )
+        };
+
+        let total_mamps: u32 = self
+            .current
+            .iter()
+            .map(|c| c.smoothed.load(Ordering::Relaxed))
+            .sum();
+
+        // Receive commands
+        if let Ok(msg) = self.control_rx.try_recv() {
+            match msg {
+                ControlMessage::SetMaxPower(watts) => {
+                    let installation = &self.settings.installation;
+                    self.max_power = if watts < MIN_CURRENT * installation.voltage / 1000 {
+                        0
+                    } else {
+                        watts.min(installation.max_power())
                     };
+                    self.changing_power = true;
+                    if let Some(session) = &mut self.session {
+                        session.set_max_power(self.max_power, self.peripherals.clock.unix_time());
+                    }
+                    log::info!("Setting max power to {}W", self.max_power);
+                }
+                ControlMessage::Shutdown => {
+                    if let Some(session) = &mut self.session {
+                        session.stopped(EndReason::Shutdown);
+                    }
+                    if matches!(
+                        self.state,
+                        PhiEvseState::Charging | PhiEvseState::SwitchingPhases
+                    ) {
+                        self.state = PhiEvseState::ShuttingDown;
+                        self.stop_deadline = now + STOP_TIMEOUT;
+                    } else {
+                        self.state = PhiEvseState::Shutdown;
+                    }
+                }
+                ControlMessage::TestPilotDuty(duty) => {
+                    if self.state == PhiEvseState::NotConnected {
+                        log::info!("Testing pilot duty {duty:?}");
+                        // A zero duty would read as state F
+                        self.pilot_test_duty = duty.map(|d| d.max(1));
+                        set_control_pilot(
+                            self.pilot_test_duty
+                                .map_or(ControlPilotSignal::Standby, ControlPilotSignal::Duty),
+                        );
+                    } else {
+                        log::warn!("The pilot duty can only be tested without a car");
+                    }
+                }
+            }
+        }
 
-                    if changing_power && self.state == PhiEvseState::Connected {
-                        if self.max_current > 6000 {
-                            set_control_pilot(ControlPilotSignal::Charge(
-                                (self.max_current as i32 + self.current_adjustment) as u32,
-                            ));
-                        } else {
-                            set_control_pilot(ControlPilotSignal::Standby);
-                        }
+        // Re-plan every tick, as held phase switches can be released later on
+        let (max_current, three_phase) = self.phase_planner.plan(self.max_power, now);
+        if (max_current, three_phase) != (self.max_current, self.three_phase) {
+            (self.max_current, self.three_phase) = (max_current, three_phase);
+            self.changing_power = true;
+            log::info!(
+                "Setting max current to {} mA, 3p={}",
+                self.max_current,
+                self.three_phase
+            );
+        }
+
+        let power = total_mamps * self.settings.installation.voltage / 1000;
+        self.energy.update(power, now);
+        if let Some(session) = &mut self.session {
+            session.update(power, self.peripherals.relay_3_phase.level(), now);
+        }
+
+        {
+            let mut status = self.status.lock().unwrap();
+            status.power = power;
+            status.max_power = self.max_power;
+            status.three_phase = self.three_phase;
+            status.three_phase_supply = self.supply.available();
+            let mains_frequency = self.mains_frequency.load(Ordering::Relaxed);
+            status.mains_frequency = (mains_frequency > 0).then(|| mains_frequency as f32 / 100.0);
+            status.car_phases = self.car_phases.phases();
+            status.phase_imbalance = self.car_phases.imbalance();
+            status.faulty_cts = self
+                .ct_health
+                .each_ref()
+                .map(|ct| ct.profile().is_some_and(|p| p.faulty()));
+            status.phase_hold = self.phase_planner.hold();
+            status.phase_switches = self.phase_planner.switches();
+            let pilot_pwm = pwm.0.filter(|_| !pilot_held_negative);
+            status.pilot_pwm = pilot_pwm;
+            status.pilot_current = pilot_pwm.filter(|pwm| !pwm.locked).map(|pwm| pwm.current());
+            status.session_energy = self.energy.session_wh();
+            status.lifetime_energy = self.energy.lifetime_wh();
+        }
+        {
+            let mut diagnostics = self.diagnostics.lock().unwrap();
+            diagnostics.pilot_mv = self.control_pilot.mv();
+            (diagnostics.pilot_transitions, diagnostics.pilot_glitches) =
+                self.control_pilot.stats();
+            diagnostics.pilot_test_duty = self.pilot_test_duty;
+            diagnostics.currents = self
+                .current
+                .each_ref()
+                .map(|c| c.smoothed.load(Ordering::Relaxed));
+            diagnostics.ct_profiles = self.ct_health.each_ref().map(|ct| ct.profile());
+        }
+
+        let pilot_fault = match cp_state {
+            ControlPilotMode::ShortCircuit => Some(Fault::PilotShortCircuit),
+            ControlPilotMode::VentilationRequired => Some(Fault::VentilationRequired),
+            _ => None,
+        };
+        if let Some(fault) = pilot_fault
+            && !matches!(self.state, PhiEvseState::Error | PhiEvseState::Shutdown)
+        {
+            log::error!("{fault}, STOP");
+            self.fault = Some(fault);
+            self.state = PhiEvseState::Error;
+        }
+
+        // Check the current whenever the contactors are closed, the car may not stop drawing when
+        // asked to
+        if self.peripherals.relay_main.level() && self.state != PhiEvseState::Error {
+            // Over the setpoint only lowers the pilot, over the installation trips
+            self.overcurrent.set_limit(self.max_current, now);
+            // Protection reacts to the fast readings
+            let fast_currents = self
+                .current
+                .each_ref()
+                .map(|c| c.fast.load(Ordering::Relaxed));
+            match self.overcurrent.update(fast_currents, now) {
+                Some(OvercurrentAction::LowerPilot { phase, by })
+                    if self.state == PhiEvseState::Charging =>
+                {
+                    log::warn!(
+                        "Car pulling {} mamps on L{} while maximum allowed is {}, lowering the pilot",
+                        fast_currents[phase as usize - 1],
+                        phase,
+                        self.max_current
+                    );
+                    self.regulator
+                        .cap(self.offered_current.saturating_sub(by), now);
+                    self.offered_current = self.regulator.output(self.max_current);
+                    set_control_pilot(ControlPilotSignal::Charge(self.offered_current));
+                }
+                Some(OvercurrentAction::Trip(phase)) => {
+                    // Car still drawing too much current, emergency shutdown
+                    log::warn!(
+                        "Car pulling {} mamps on L{} while the installation allows {}. Stop!",
+                        fast_currents[phase as usize - 1],
+                        phase,
+                        self.settings.installation.max_current()
+                    );
+                    self.fault = Some(Fault::Overcurrent(phase));
+                    self.state = PhiEvseState::Error;
+                }
+                _ => {}
+            }
+        } else {
+            self.overcurrent.reset();
+        }
+
+        match self.state {
+            PhiEvseState::NotConnected | PhiEvseState::Connected => {
+                // Wait until EV is connected and ready to charge
+                self.state = pilot_state(&cp_state);
+
+                if self.changing_power && self.state == PhiEvseState::Connected {
+                    if self.max_current >= MIN_CURRENT {
+                        self.offered_current = self.regulator.output(self.max_current);
+                        set_control_pilot(ControlPilotSignal::Charge(self.offered_current));
+                    } else {
+                        set_control_pilot(ControlPilotSignal::Standby);
                     }
                 }
-                PhiEvseState::Ready => {
-                    // Start charging
-                    if self.max_current > 6000 {
-                        sleep(Duration::from_millis(500)); // Wait a bit or the car gets angry at us for switching the relay too soon
+            }
+            PhiEvseState::Ready => {
+                if self.max_current >= MIN_CURRENT && cp_state == ControlPilotMode::Ready {
+                    // Start charging. Wait a bit or the car gets angry at us for switching the relay too soon
+                    if now >= self.state_since + RELAY_CLOSE_DELAY {
                         self.peripherals
                             .relay_3_phase
-                            .set_level_and_wait(self.three_phase);
-                        self.peripherals.relay_main.set_level(true);
+                            .set_level_and_wait(self.three_phase, &self.peripherals.clock);
+                        self.peripherals
+                            .relay_main
+                            .set_level(true, self.peripherals.clock.now());
                         self.state = PhiEvseState::Charging;
+                    }
+                } else {
+                    // We can end up here if max current is resetted while car is ready to charge
+                    // This can happen if we suddenly change the max power during charge start-up
+                    self.state = pilot_state(&cp_state);
+                }
+            }
+            PhiEvseState::Charging => {
+                if self.changing_power {
+                    if self.max_current < MIN_CURRENT {
+                        // Not enough power to charge, pause until there is
+                        self.regulator.reset();
+                        self.state = PhiEvseState::Stopping;
+                        self.stop_deadline = now + STOP_TIMEOUT;
+                    } else if self.three_phase != self.peripherals.relay_3_phase.level() {
+                        // Phases can't be switched under load, pause the charge first
+                        self.state = PhiEvseState::SwitchingPhases;
                     } else {
-                        // We can end up here if max current is resetted while car is ready to charge
-                        // This can happen if we suddenly change the max power during charge start-up
-                        self.state = match cp_state {
-                            ControlPilotMode::NotConnected => PhiEvseState::NotConnected,
-                            ControlPilotMode::Connected => PhiEvseState::Connected,
-                            ControlPilotMode::Ready => PhiEvseState::Ready,
-                            ControlPilotMode::Error => PhiEvseState::Error,
-                        };
+                        self.offered_current = self.regulator.output(self.max_current);
+                        set_control_pilot(ControlPilotSignal::Charge(self.offered_current));
+                        self.next_current_adjustment = now + Duration::from_secs(5);
                     }
                 }
-                PhiEvseState::Charging => {
-                    if changing_power {
-                        set_control_pilot(ControlPilotSignal::Charge(
-                            (self.max_current as i32 + self.current_adjustment) as u32,
-                        ));
-                        next_current_adjustment = 50;
 
-                        if self.three_phase != self.peripherals.relay_3_phase.level() {
-                            self.peripherals.relay_3_phase.set_level(self.three_phase);
-                            next_current_adjustment = 100; // Give additional time to settle
-                        }
-                    }
+                if now >= self.next_log {
+                    self.next_log = now + LOG_INTERVAL;
+                    log::info!(
+                        "Charging at {:?} mamps / ADJ = {}",
+                        self.current
+                            .iter()
+                            .map(|c| c.smoothed.load(Ordering::Relaxed))
+                            .collect::<Vec<u32>>(),
+                        self.regulator.correction()
+                    )
+                };
 
-                    if i == 0 {
+                if self.state == PhiEvseState::Charging {
+                    let currents = self
+                        .current
+                        .each_ref()
+                        .map(|c| c.smoothed.load(Ordering::Relaxed));
+                    let three_phase = self.peripherals.relay_3_phase.level();
+                    if self.car_phases.update(currents, three_phase, now) {
                         log::info!(
-                            "Charging at {:?} mamps / ADJ = {}",
-                            self.current
-                                .iter()
-                                .map(|c| c.load(Ordering::Relaxed))
-                                .collect::<Vec<u32>>(),
-                            self.current_adjustment
-                        )
-                    };
+                            "Car draws current from {:?} phases",
+                            self.car_phases.phases()
+                        );
+                        self.phase_planner.set_car_phases(self.car_phases.phases());
+                    }
+                }
 
-                    if next_current_adjustment == 0 {
-                        // Check if EV wants to stop charging
-                        if cp_state != ControlPilotMode::Ready {
-                            self.state = PhiEvseState::Stopping;
-                            stop_timeout = 50;
-                        } else {
-                            let phases = if self.three_phase { 3 } else { 1 };
-                            let mamps_per_phase = total_mamps / phases;
-                            let current_diff: i32 =
-                                self.max_current as i32 - mamps_per_phase as i32;
-                            if mamps_per_phase < 1000 {
-                                // Not yet charging, wait before adjusting
-                                // TODO: Abort if waiting for too long for charge to start?
-                                next_current_adjustment += 1;
-                            } else if mamps_per_phase > self.max_current + 4000 {
-                                // Car drawing way too much current, emergency shutdown
-                                log::warn!(
-                                    "Car pulling {} mamps while maximum allowed is {}. Stop!",
-                                    mamps_per_phase,
-                                    self.max_current
-                                );
-                                self.state = PhiEvseState::Error;
-                            } else if mamps_per_phase < 6500 {
-                                // Current close to minimum, increase to avoid cut-off
-                                self.current_adjustment += 500;
-                                if self.current_adjustment.abs() > 1000 {
-                                    self.current_adjustment =
-                                        self.current_adjustment.signum() * 1000
+                if self.state == PhiEvseState::Charging && now >= self.next_current_adjustment {
+                    // Check if EV wants to stop charging
+                    if cp_state != ControlPilotMode::Ready {
+                        if let Some(session) = &mut self.session {
+                            session.stopped(match cp_state {
+                                ControlPilotMode::NotConnected => EndReason::Unplugged,
+                                ControlPilotMode::Unavailable => {
+                                    EndReason::Fault(Fault::PilotError)
                                 }
-                                log::info!(
-                                    "Adjusting over 6500. ADJ = {}",
-                                    self.current_adjustment
-                                );
-                                set_control_pilot(ControlPilotSignal::Charge(
-                                    (self.max_current as i32 + self.current_adjustment) as u32,
-                                ));
-                                next_current_adjustment = 30;
-                            } else if current_diff.abs() > 500 {
-                                self.current_adjustment += current_diff.signum() * 300;
-                                if self.current_adjustment.abs() > 1000 {
-                                    self.current_adjustment =
-                                        self.current_adjustment.signum() * 1000
+                                _ => EndReason::CarFinished,
+                            });
+                        }
+                        self.state = PhiEvseState::Stopping;
+                        self.stop_deadline = now + STOP_TIMEOUT;
+                    } else {
+                        // Most loaded phase, in case the car doesn't use all of them
+                        let mamps_per_phase = self
+                            .current
+                            .iter()
+                            .map(|c| c.smoothed.load(Ordering::Relaxed))
+                            .max()
+                            .unwrap_or(0);
+                        if mamps_per_phase >= 1000 {
+                            self.charge_start_deadline = None;
+                            self.wake_ups = 0;
+                        }
+                        if mamps_per_phase < 1000 {
+                            // Not yet charging, check again on the next tick before adjusting
+                            if self.charge_start_deadline.is_some_and(|t| now >= t) {
+                                if self.wake_ups < self.settings.wake_up_attempts {
+                                    self.wake_ups += 1;
+                                    log::info!(
+                                        "Car not drawing current, waking it up ({}/{})",
+                                        self.wake_ups,
+                                        self.settings.wake_up_attempts
+                                    );
+                                    self.state = PhiEvseState::WakingUp;
+                                } else {
+                                    log::warn!("Car did not draw current, giving up");
+                                    self.fault = Some(Fault::ChargeStartTimeout);
+                                    self.state = PhiEvseState::Error;
                                 }
-                                log::info!(
-                                    "Adjusting 500 mamps diff. ADJ = {} ",
-                                    self.current_adjustment
-                                );
-                                set_control_pilot(ControlPilotSignal::Charge(
-                                    (self.max_current as i32 + self.current_adjustment) as u32,
-                                ));
-                                next_current_adjustment = 30;
                             }
+                        } else {
+                            let pilot =
+                                self.regulator
+                                    .update(self.max_current, mamps_per_phase, now);
+                            if pilot.abs_diff(self.offered_current) >= PILOT_MIN_STEP {
+                                self.offered_current = pilot;
+                                set_control_pilot(ControlPilotSignal::Charge(pilot));
+                            }
+                            self.next_current_adjustment = now + REGULATION_INTERVAL;
                         }
-                    } else {
-                        next_current_adjustment -= 1;
                     }
                 }
-                PhiEvseState::Stopping | PhiEvseState::ShuttingDown => {
-                    // Wait until car stops charging or timeout expires and then disconnect relays
-                    let total_mamps: u32 =
-                        self.current.iter().map(|c| c.load(Ordering::Relaxed)).sum();
-                    if total_mamps == 0 || stop_timeout <= 0 {
-                        self.peripherals.relay_main.set_level_and_wait(false);
-                        self.peripherals.relay_3_phase.set_level(false);
-
-                        if self.state == PhiEvseState::Stopping {
-                            self.state = match cp_state {
-                                ControlPilotMode::NotConnected => PhiEvseState::NotConnected,
-                                ControlPilotMode::Connected => PhiEvseState::Connected,
-                                ControlPilotMode::Ready => unreachable!(),
-                                ControlPilotMode::Error => PhiEvseState::Error,
-                            };
+            }
+            PhiEvseState::SwitchingPhases => match self.phase_switch {
+                _ if cp_state == ControlPilotMode::NotConnected => {
+                    self.peripherals
+                        .relay_main
+                        .set_level_and_wait(false, &self.peripherals.clock);
+                    self.peripherals.relay_3_phase.set_level(false, now);
+                    self.state = PhiEvseState::NotConnected;
+                }
+                PhaseSwitch::Pausing(deadline) => {
+                    // Wait until car stops charging or timeout expires and then switch with the contactor open
+                    if total_mamps == 0 || now >= deadline {
+                        self.peripherals
+                            .relay_main
+                            .set_level_and_wait(false, &self.peripherals.clock);
+                        if self.three_phase != self.peripherals.relay_3_phase.level() {
+                            self.phase_planner.switched(now);
+                        }
+                        self.peripherals
+                            .relay_3_phase
+                            .set_level_and_wait(self.three_phase, &self.peripherals.clock);
+                        log::info!("Switched to 3p={}, resting", self.three_phase);
+                        self.phase_switch =
+                            PhaseSwitch::Resting(now + self.settings.phase_switch_rest);
+                    }
+                }
+                PhaseSwitch::Resting(until) => {
+                    if now >= until {
+                        if self.max_current >= MIN_CURRENT {
+                            // Max power might have changed again while resting
+                            if self.three_phase != self.peripherals.relay_3_phase.level() {
+                                self.phase_planner.switched(now);
+                            }
+                            self.peripherals
+                                .relay_3_phase
+                                .set_level_and_wait(self.three_phase, &self.peripherals.clock);
+                            self.peripherals
+                                .relay_main
+                                .set_level(true, self.peripherals.clock.now());
+                            self.offered_current = self.regulator.output(self.max_current);
+                            set_control_pilot(ControlPilotSignal::Charge(self.offered_current));
+                            // Give additional time to settle
+                            self.next_current_adjustment = now + Duration::from_secs(10);
+                            self.state = PhiEvseState::Charging;
                         } else {
-                            self.state = PhiEvseState::Shutdown;
+                            self.state = PhiEvseState::Connected;
                         }
+                    }
+                }
+            },
+            PhiEvseState::Stopping | PhiEvseState::ShuttingDown => {
+                // Wait until car stops charging or timeout expires and then disconnect relays
+                if total_mamps == 0 || now >= self.stop_deadline {
+                    self.peripherals
+                        .relay_main
+                        .set_level_and_wait(false, &self.peripherals.clock);
+                    self.peripherals.relay_3_phase.set_level(false, now);
+
+                    if self.state == PhiEvseState::Stopping {
+                        self.state = pilot_state(&cp_state);
                     } else {
-                        stop_timeout -= 1;
+                        self.state = PhiEvseState::Shutdown;
                     }
                 }
-                PhiEvseState::Error => {
-                    // Wait until EV is disconnected to clean the error
-                    if cp_state == ControlPilotMode::NotConnected {
-                        self.state = PhiEvseState::NotConnected;
+            }
+            PhiEvseState::WakingUp => {
+                // Offer current again after the interruption, the car goes through Ready as usual
+                if cp_state == ControlPilotMode::NotConnected {
+                    self.state = PhiEvseState::NotConnected;
+                } else if now >= self.state_since + WAKE_UP_PAUSE {
+                    self.state = PhiEvseState::Connected;
+                }
+            }
+            PhiEvseState::Error if self.fault_latched => {
+                // Can't be fixed by unplugging (e.g: welded contactor), stay in error until rebooted
+            }
+            PhiEvseState::Error => {
+                // Wait until EV is disconnected to clean the error
+                if !self.fault_pilot_released {
+                    if now >= self.state_since + FAULT_PILOT_HOLD {
+                        set_control_pilot(ControlPilotSignal::Standby);
+                        self.fault_pilot_released = true;
                     }
+                } else if now >= self.state_since + FAULT_PILOT_HOLD + PILOT_SETTLE
+                    && cp_state == ControlPilotMode::NotConnected
+                {
+                    self.state = PhiEvseState::NotConnected;
                 }
-                PhiEvseState::Shutdown => {}
             }
-            changing_power = false;
+            PhiEvseState::Shutdown => {}
+        }
+        self.changing_power = false;
 
-            // Actions when entering a new state
-            if prev_state != self.state {
-                log::info!("State transition {:?} => {:?}", prev_state, self.state);
+        // Contactors are open again, make sure the energy of the session is not lost
+        let contactors_opened = self.prev_state != self.state
+            && matches!(
+                self.state,
+                PhiEvseState::NotConnected
+                    | PhiEvseState::Connected
+                    | PhiEvseState::WakingUp
+                    | PhiEvseState::Error
+                    | PhiEvseState::Shutdown
+            );
 
-                match self.state {
-                    PhiEvseState::NotConnected => {
-                        set_control_pilot(ControlPilotSignal::Standby);
-                    }
-                    PhiEvseState::Connected => {
-                        self.current_adjustment = 1000;
-                        if self.max_current > 6000 {
-                            set_control_pilot(ControlPilotSignal::Charge(
-                                (self.max_current as i32 + self.current_adjustment) as u32,
-                            ));
-                        }
-                    }
-                    PhiEvseState::Ready => {
-                        // set_control_pilot(ControlPilotSignal::Charge(self.max_current));
-                    }
-                    PhiEvseState::Charging => {}
-                    PhiEvseState::Error => {
-                        set_control_pilot(ControlPilotSignal::Error);
-                        self.peripherals.relay_main.set_level_and_wait(false);
-                        self.peripherals.relay_3_phase.set_level(false);
+        // Actions when entering a new state
+        if self.prev_state != self.state {
+            log::info!("State transition {:?} => {:?}", self.prev_state, self.state);
+
+            if self.prev_state == PhiEvseState::NotConnected
+                && matches!(self.state, PhiEvseState::Connected | PhiEvseState::Ready)
+            {
+                self.energy.start_session();
+                self.session = Some(SessionRecorder::new(
+                    self.peripherals.clock.unix_time(),
+                    self.max_power,
+                    now,
+                ));
+            }
+
+            if self.pilot_test_duty.take().is_some() {
+                log::info!("Stopped testing the pilot duty");
+                set_control_pilot(ControlPilotSignal::Standby);
+            }
+
+            match self.state {
+                PhiEvseState::NotConnected => {
+                    set_control_pilot(ControlPilotSignal::Standby);
+                    self.fault = None;
+                    self.wake_ups = 0;
+                    self.car_phases.reset();
+                    self.pwm_monitor.reset();
+                    self.phase_planner.set_car_phases(None);
+                }
+                PhiEvseState::Connected => {
+                    self.regulator.reset();
+                    if self.max_current >= MIN_CURRENT {
+                        self.offered_current = self.regulator.output(self.max_current);
+                        set_control_pilot(ControlPilotSignal::Charge(self.offered_current));
                     }
-                    PhiEvseState::Stopping | PhiEvseState::ShuttingDown => {
-                        set_control_pilot(ControlPilotSignal::Standby);
+                }
+                PhiEvseState::Ready => {
+                    // set_control_pilot(ControlPilotSignal::Charge(self.max_current));
+                }
+                PhiEvseState::Charging => {
+                    if let Some(session) = &mut self.session {
+                        session.resumed();
                     }
-                    PhiEvseState::Shutdown => {
-                        self.peripherals.watchdog.stop();
+                    self.charge_start_deadline = Some(now + self.settings.charge_start_timeout);
+                }
+                PhiEvseState::WakingUp => {
+                    set_control_pilot(ControlPilotSignal::Standby);
+                    self.peripherals
+                        .relay_main
+                        .set_level_and_wait(false, &self.peripherals.clock);
+                    self.peripherals.relay_3_phase.set_level(false, now);
+                }
+                PhiEvseState::SwitchingPhases => {
+                    // The correction per phase changes with the number of phases
+                    self.regulator.reset();
+                    set_control_pilot(ControlPilotSignal::Standby);
+                    self.phase_switch = PhaseSwitch::Pausing(now + STOP_TIMEOUT);
+                }
+                PhiEvseState::Error => {
+                    // Pilot errors are not checked before the transition
+                    self.fault.get_or_insert(Fault::PilotError);
+                    set_control_pilot(ControlPilotSignal::Error);
+                    self.fault_pilot_released = false;
+                    self.peripherals
+                        .relay_main
+                        .set_level_and_wait(false, &self.peripherals.clock);
+                    self.peripherals.relay_3_phase.set_level(false, now);
+                }
+                PhiEvseState::Stopping | PhiEvseState::ShuttingDown => {
+                    set_control_pilot(ControlPilotSignal::Standby);
+                }
+                PhiEvseState::Shutdown => {
+                    self.peripherals.watchdog.stop();
+                }
+            }
+
+            if matches!(
+                self.state,
+                PhiEvseState::NotConnected | PhiEvseState::Shutdown
+            ) && let Some(session) = self.session.take()
+            {
+                let reason = if self.state == PhiEvseState::Shutdown {
+                    EndReason::Shutdown
+                } else {
+                    EndReason::Unplugged
+                };
+                let session = session.finish(
+                    self.peripherals.clock.unix_time(),
+                    self.energy.session_wh(),
+                    reason,
+                );
+                log::info!(
+                    "Session ended: {:?}, {} Wh",
+                    session.end_reason,
+                    session.energy
+                );
+                let mut sessions = self.sessions.lock().unwrap();
+                sessions.push(session);
+                if self.storage_loaded
+                    && let Err(e) = sessions.save_last(&mut self.peripherals.storage)
+                {
+                    log::warn!("Could not save session: {e}");
+                }
+            }
+
+            self.status.lock().unwrap().state = self.state;
+            self.prev_state = self.state;
+            self.state_since = now;
+        }
+
+        let recorded_fault = self.status.lock().unwrap().fault;
+        if self.fault != recorded_fault {
+            if let Some(fault) = self.fault {
+                self.record_fault(fault);
+            }
+            self.status.lock().unwrap().fault = self.fault;
+        }
+
+        // Schedule a check of the contactors after they switch
+        let relays = (
+            self.peripherals.relay_main.level(),
+            self.peripherals.relay_3_phase.level(),
+        );
+        if relays != self.checked_relays {
+            self.checked_relays = relays;
+            self.relay_check_at = Some(now + RELAY_CHECK_DELAY);
+        }
+
+        if !self.storage_loaded && now >= self.storage_retry_at {
+            self.load_storage(now);
+        }
+        if self.storage_loaded
+            && let Some(wh) = self.energy.persist_due(now, contactors_opened)
+        {
+            match self.peripherals.storage.set_u64(LIFETIME_ENERGY_KEY, wh) {
+                Ok(()) => self.energy.persisted(wh, now),
+                Err(e) => {
+                    log::warn!("Could not save lifetime energy: {e}");
+                    self.energy.persist_failed(now);
+                }
+            }
+        }
+    }
+
+    /// Loads the lifetime energy and the sessions, merging them with the ones metered since started.
+    /// Until then nothing is saved, so the stored data is not overwritten.
+    fn load_storage(&mut self, now: Duration) {
+        let storage = &self.peripherals.storage;
+        match (
+            storage.get_u64(LIFETIME_ENERGY_KEY),
+            SessionHistory::load(storage),
+        ) {
+            (Ok(wh), Ok(mut history)) => {
+                self.energy.restore(wh.unwrap_or(0));
+                let mut sessions = self.sessions.lock().unwrap();
+                for session in sessions.sessions().iter().rev() {
+                    history.push(session.clone());
+                    if let Err(e) = history.save_last(&mut self.peripherals.storage) {
+                        log::warn!("Could not save session: {e}");
                     }
                 }
+                *sessions = history;
+                self.storage_loaded = true;
+            }
+            (Err(e), _) | (_, Err(e)) => {
+                log::error!("Could not load energy and sessions, retrying later: {e}");
+                self.storage_retry_at = now + STORAGE_RETRY_INTERVAL;
+            }
+        }
+    }
+
+    /// Checks the pilot, current sensors and that no contactor is welded before charging. Contactors
+    /// are never closed without a car, they are checked on every switch instead. Only contactor
+    /// failures latch a fault, the rest are reported as warnings.
+    fn self_test(&mut self) {
+        let mut checks = Vec::new();
+        let mut fault = None;
+        let mut check = |name: &'static str, passed: bool, detail: String, latch: Option<Fault>| {
+            match latch {
+                Some(failure) if !passed => {
+                    log::error!("Self test failed: {name} ({detail})");
+                    fault.get_or_insert(failure);
+                }
+                None if !passed => log::warn!("Self test warning: {name} ({detail})"),
+                _ => (),
+            }
+            checks.push(SelfTestCheck {
+                name,
+                passed,
+                detail,
+            });
+        };
+
+        // Pilot at +12V (already in standby) and -12V
+        self.peripherals.clock.sleep(CURRENT_METER_SETTLE);
+        let positive_mv = self.control_pilot.mv();
+        let negative_in_standby = self.peripherals.pilot_negative.is_high();
+        let currents = self
+            .current
+            .each_ref()
+            .map(|c| c.smoothed.load(Ordering::Relaxed));
+        set_control_pilot(
+            &mut self.peripherals.control_pilot,
+            ControlPilotSignal::Error,
+            &self.settings.pilot_duty,
+            0,
+        );
+        self.peripherals.clock.sleep(PILOT_SETTLE);
+        let negative_mv = self.control_pilot.mv();
+        let negative_in_error = self.peripherals.pilot_negative.is_high();
+        set_control_pilot(
+            &mut self.peripherals.control_pilot,
+            ControlPilotSignal::Standby,
+            &self.settings.pilot_duty,
+            0,
+        );
+
+        check(
+            "Pilot +12V",
+            positive_mv <= SELF_TEST_PILOT_MAX_MV,
+            format!("{positive_mv} mV"),
+            None,
+        );
+        check(
+            "Pilot -12V",
+            negative_mv <= SELF_TEST_PILOT_NEGATIVE_MAX_MV,
+            format!("{negative_mv} mV"),
+            None,
+        );
+        check(
+            "Pilot negative",
+            negative_in_standby && negative_in_error,
+            format!("standby={negative_in_standby} error={negative_in_error}"),
+            None,
+        );
+        for (phase, mamps) in currents.into_iter().enumerate() {
+            check(
+                ["Current L1", "Current L2", "Current L3"][phase],
+                mamps <= SELF_TEST_MAX_CURRENT,
+                format!("{mamps} mA"),
+                None,
+            );
+        }
+
+        let [l1, l2, l3, _] = self.sense_ac();
+        check(
+            "No AC with contactors open",
+            !(l1 || l2 || l3),
+            format!("L1={l1} L2={l2} L3={l3}"),
+            Some(Fault::RelayWelded),
+        );
+
+        self.diagnostics.lock().unwrap().self_test = checks;
+        if let Some(fault) = fault {
+            self.fault = Some(fault);
+            self.fault_latched = true;
+            self.state = PhiEvseState::Error;
+        }
+    }
+
+    /// IEC 61851 diode check: the negative half of the pilot has to reach -12V. While the pilot is
+    /// oscillating, failures are `Fault::DiodeMissing` and follow the configured policy. Otherwise
+    /// the pilot has a fixed level (e.g: shorted) and it always stops with `Fault::PilotNegativeLow`.
+    fn check_diode(&mut self) {
+        let alarm = self.control_pilot.negative_alarm();
+        if alarm {
+            self.peripherals.pilot_negative.arm();
+        }
+        if !alarm && self.peripherals.pilot_negative.is_high() {
+            self.pilot_negative_low = 0;
+            return;
+        }
+
+        self.pilot_negative_low += 1;
+        if self.pilot_negative_low < DIODE_CHECK_TICKS || self.state == PhiEvseState::Error {
+            return;
+        }
+        let pin = &self.peripherals.control_pilot;
+        let oscillating = pin.get_duty() > 0 && pin.get_duty() < pin.get_max_duty();
+        let fault = if oscillating {
+            Fault::DiodeMissing
+        } else {
+            Fault::PilotNegativeLow
+        };
+        if fault == Fault::DiodeMissing && self.settings.diode_check == DiodeCheck::WarnOnly {
+            if self.pilot_negative_low == DIODE_CHECK_TICKS {
+                log::warn!("Diode check failed, ignoring as configured");
+            }
+        } else {
+            log::error!("{fault}, STOP");
+            self.fault = Some(fault);
+            self.state = PhiEvseState::Error;
+        }
+    }
+
+    fn record_fault(&mut self, fault: Fault) {
+        log::error!("Fault: {fault}");
+        {
+            let mut status = self.status.lock().unwrap();
+            status.recent_faults.insert(
+                0,
+                FaultEvent {
+                    at: self.peripherals.clock.unix_time(),
+                    fault,
+                },
+            );
+            status.recent_faults.truncate(MAX_RECENT_FAULTS);
+        }
+        if let Some(session) = &mut self.session {
+            session.stopped(EndReason::Fault(fault));
+        }
+    }
+
+    /// Compares the voltage after the contactors with the level they are driven at
+    fn check_relays(&self, [l1, l2, l3, supply]: [bool; 4]) -> Option<Fault> {
+        let main = self.peripherals.relay_main.level();
+        let three_phase = self.peripherals.relay_3_phase.level();
+        log::info!("Contactors main={main} 3p={three_phase}, AC on L1={l1} L2={l2} L3={l3}");
+
+        if (!main && (l1 || l2 || l3)) || (main && !three_phase && (l2 || l3)) {
+            Some(Fault::RelayWelded)
+        } else if main && (!l1 || (three_phase && supply && !(l2 && l3))) {
+            Some(Fault::RelayFailedToClose)
+        } else {
+            None
+        }
+    }
 
-                self.status.lock().unwrap().state = self.state;
-                prev_state = self.state;
+    /// Samples the voltage sense inputs over `AC_SENSE_WINDOW`, blocking. Returns whether there's
+    /// AC on L1, L2, L3 and the 3-phase supply.
+    fn sense_ac(&self) -> [bool; 4] {
+        let clock = &self.peripherals.clock;
+        let mut detectors: [AcDetector; 4] = Default::default();
+        let end = clock.now() + AC_SENSE_WINDOW;
+        while clock.now() < end {
+            for (detector, high) in detectors.iter_mut().zip(self.sense_inputs()) {
+                detector.sample(high);
             }
+            clock.sleep(AC_SENSE_INTERVAL);
+        }
+        detectors.map(|d| d.present())
+    }
 
-            sleep(Duration::from_millis(100));
+    /// Takes the samples of the voltage sense inputs for this tick. Once `AC_SENSE_TICKS` were
+    /// sampled, returns whether there's AC on L1, L2, L3 and the 3-phase supply.
+    fn sample_ac(&mut self) -> Option<[bool; 4]> {
+        for _ in 0..AC_SENSE_TICK_SAMPLES {
+            let inputs = self.sense_inputs();
+            for (detector, high) in self.ac_detectors.iter_mut().zip(inputs) {
+                detector.sample(high);
+            }
+            self.peripherals.clock.sleep(AC_SENSE_INTERVAL);
         }
+        self.ac_ticks += 1;
+        (self.ac_ticks >= AC_SENSE_TICKS).then(|| {
+            self.ac_ticks = 0;
+            std::mem::take(&mut self.ac_detectors).map(|d| d.present())
+        })
+    }
+
+    /// Levels of the voltage sense inputs of L1, L2, L3 and the 3-phase supply
+    fn sense_inputs(&self) -> [bool; 4] {
+        let (l1, l2, l3) = &self.peripherals.v_sense;
+        // Read errors count as AC on the contactor outputs, so they can't hide a welded contactor,
+        // and as no 3-phase supply, so it falls back to 1 phase
+        [
+            l1.is_high().unwrap_or(false),
+            l2.is_high().unwrap_or(false),
+            l3.is_high().unwrap_or(false),
+            self.peripherals.v_sense_3_phase.is_high().unwrap_or(true),
+        ]
+    }
+}
+
+/// State to follow the pilot while the contactors are open
+fn pilot_state(mode: &ControlPilotMode) -> PhiEvseState {
+    match mode {
+        ControlPilotMode::NotConnected => PhiEvseState::NotConnected,
+        ControlPilotMode::Connected => PhiEvseState::Connected,
+        ControlPilotMode::Ready => PhiEvseState::Ready,
+        ControlPilotMode::VentilationRequired
+        | ControlPilotMode::ShortCircuit
+        | ControlPilotMode::Unavailable => PhiEvseState::Error,
     }
 }
 
-fn calculate_power(watts: u32) -> (u32, bool) {
-    let total_mamps = watts * 1000 / 230;
-    match total_mamps {
-        0..=6499 => (0, false),
-        6500..=19999 => (min(total_mamps, 16000), false),
-        20000..=47999 => (total_mamps / 3, true),
-        _ => unreachable!(),
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{CarProfile, ContactorFault, SimController, Simulation};
+
+    fn start(car: CarProfile) -> (Simulation, SimController) {
+        start_with(car, ControllerSettings::default(), None)
+    }
+
+    fn start_with(
+        car: CarProfile,
+        settings: ControllerSettings,
+        contactor_fault: Option<ContactorFault>,
+    ) -> (Simulation, SimController) {
+        let sim = Simulation::new(CarProfile {
+            response_time: Duration::from_secs(1),
+            ..car
+        });
+        sim.set_main_contactor_fault(contactor_fault);
+        let mut controller = PhiEvseController::new(sim.peripherals(), settings);
+        controller.init();
+        (sim, controller)
+    }
+
+    fn run_for(sim: &Simulation, controller: &mut SimController, duration: Duration) {
+        let end = sim.now() + duration;
+        while sim.now() < end {
+            sim.step(TICK);
+            controller.step(sim.now());
+        }
+    }
+
+    fn state(controller: &SimController) -> PhiEvseState {
+        controller.status().lock().unwrap().state
+    }
+
+    #[test]
+    fn charges_a_car() {
+        let (sim, mut controller) = start(CarProfile::one_phase());
+        controller
+            .control_channel()
+            .send(ControlMessage::SetMaxPower(3000))
+            .unwrap();
+        run_for(&sim, &mut controller, Duration::from_secs(1));
+        assert_eq!(state(&controller), PhiEvseState::NotConnected);
+
+        sim.plug_in();
+        run_for(&sim, &mut controller, Duration::from_secs(10));
+        assert_eq!(state(&controller), PhiEvseState::Charging);
+        assert_eq!(sim.relays(), (true, false));
+        let power = controller.status().lock().unwrap().power;
+        assert!((2700..3300).contains(&power), "{power} W");
+        assert!(!sim.watchdog_expired());
+    }
+
+    #[test]
+    fn waits_for_the_car_before_closing_contactors() {
+        let (sim, mut controller) = start(CarProfile::default());
+        controller
+            .control_channel()
+            .send(ControlMessage::SetMaxPower(3000))
+            .unwrap();
+        sim.plug_in();
+
+        let mut ready_at = None;
+        while state(&controller) != PhiEvseState::Charging {
+            sim.step(TICK);
+            controller.step(sim.now());
+            if state(&controller) == PhiEvseState::Ready {
+                ready_at.get_or_insert(sim.now());
+                assert_eq!(sim.relays(), (false, false));
+            }
+            assert!(sim.now() < Duration::from_secs(5));
+        }
+        assert!(sim.now() >= ready_at.unwrap() + RELAY_CLOSE_DELAY);
+        assert_eq!(sim.relays(), (true, false));
+    }
+
+    #[test]
+    fn stops_when_unplugged() {
+        let (sim, mut controller) = start(CarProfile::default());
+        controller
+            .control_channel()
+            .send(ControlMessage::SetMaxPower(3000))
+            .unwrap();
+        sim.plug_in();
+        run_for(&sim, &mut controller, Duration::from_secs(10));
+        assert_eq!(state(&controller), PhiEvseState::Charging);
+
+        sim.unplug();
+        run_for(&sim, &mut controller, Duration::from_secs(3));
+        assert_eq!(state(&controller), PhiEvseState::NotConnected);
+        assert_eq!(sim.relays(), (false, false));
+    }
+
+    #[test]
+    fn shuts_down_while_charging() {
+        let (sim, mut controller) = start(CarProfile::default());
+        controller
+            .control_channel()
+            .send(ControlMessage::SetMaxPower(3000))
+            .unwrap();
+        sim.plug_in();
+        run_for(&sim, &mut controller, Duration::from_secs(10));
+
+        controller
+            .control_channel()
+            .send(ControlMessage::Shutdown)
+            .unwrap();
+        run_for(&sim, &mut controller, Duration::from_millis(200));
+        assert_eq!(state(&controller), PhiEvseState::ShuttingDown);
+
+        // The car stops drawing current after the pilot goes to standby
+        run_for(&sim, &mut controller, STOP_TIMEOUT);
+        assert_eq!(state(&controller), PhiEvseState::Shutdown);
+        assert_eq!(sim.relays(), (false, false));
+        assert_eq!(sim.car_currents(), [0, 0, 0]);
+    }
+
+    #[test]
+    fn switches_phases_without_load() {
+        let (sim, mut controller) = start(CarProfile {
+            faults_on_phase_change: true,
+            ..Default::default()
+        });
+        let control = controller.control_channel();
+        control.send(ControlMessage::SetMaxPower(3000)).unwrap();
+        sim.plug_in();
+        run_for(&sim, &mut controller, Duration::from_secs(10));
+        assert_eq!(sim.relays(), (true, false));
+
+        control.send(ControlMessage::SetMaxPower(9000)).unwrap();
+        run_for(&sim, &mut controller, Duration::from_secs(20));
+        assert_eq!(sim.live_phase_switches(), 0);
+        assert!(!sim.car_faulted());
+        assert_eq!(state(&controller), PhiEvseState::Charging);
+        assert_eq!(sim.relays(), (true, true));
+        assert!(sim.car_currents().iter().all(|&c| c > 10000));
+    }
+
+    #[test]
+    fn falls_back_without_three_phase_supply() {
+        let (sim, mut controller) = start(CarProfile::default());
+        sim.set_three_phase_supply(false);
+        run_for(&sim, &mut controller, Duration::from_secs(5));
+        let control = controller.control_channel();
+        control.send(ControlMessage::SetMaxPower(9000)).unwrap();
+        sim.plug_in();
+        run_for(&sim, &mut controller, Duration::from_secs(20));
+        assert_eq!(sim.relays(), (true, false));
+        assert!(!controller.status().lock().unwrap().three_phase_supply);
+
+        sim.set_three_phase_supply(true);
+        run_for(&sim, &mut controller, Duration::from_secs(60));
+        assert_eq!(sim.relays(), (true, true));
+        assert!(controller.status().lock().unwrap().three_phase_supply);
+
+        // Phase lost while charging
+        sim.set_three_phase_supply(false);
+        run_for(&sim, &mut controller, Duration::from_secs(20));
+        assert_eq!(state(&controller), PhiEvseState::Charging);
+        assert_eq!(sim.relays(), (true, false));
+        assert!(controller.status().lock().unwrap().fault.is_none());
+    }
+
+    #[test]
+    fn senses_ac_without_blocking() {
+        let (sim, mut controller) = start(CarProfile::default());
+        let control = controller.control_channel();
+        control.send(ControlMessage::SetMaxPower(3600)).unwrap();
+        sim.plug_in();
+        run_for(&sim, &mut controller, Duration::from_secs(20));
+        assert_eq!(state(&controller), PhiEvseState::Charging);
+
+        for _ in 0..50 {
+            sim.step(TICK);
+            let tick_start = sim.now();
+            controller.step(tick_start);
+            assert!(sim.now() - tick_start <= Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn does_not_chatter_phases() {
+        let (sim, mut controller) = start(CarProfile::default());
+        let control = controller.control_channel();
+        control.send(ControlMessage::SetMaxPower(3000)).unwrap();
+        sim.plug_in();
+        run_for(&sim, &mut controller, Duration::from_secs(20));
+        assert_eq!(sim.relays(), (true, false));
+        // Setpoints without a car don't count
+        assert_eq!(controller.status().lock().unwrap().phase_switches, 0);
+
+        control.send(ControlMessage::SetMaxPower(5200)).unwrap();
+        run_for(&sim, &mut controller, Duration::from_secs(30));
+        assert_eq!(sim.relays(), (true, true));
+
+        for i in 0..10 {
+            let watts = if i % 2 == 0 { 4000 } else { 5200 };
+            control.send(ControlMessage::SetMaxPower(watts)).unwrap();
+            run_for(&sim, &mut controller, Duration::from_secs(15));
+            let status = controller.status().lock().unwrap().clone();
+            assert_eq!(status.phase_switches, 1);
+            assert!(status.three_phase);
+            if watts == 4000 {
+                // 3 phases would draw more than the power, paused while held
+                assert_eq!(status.phase_hold, Some(PhaseHold::MinimumDwell));
+                assert_eq!(sim.car_currents(), [0, 0, 0]);
+            } else {
+                assert_eq!(sim.relays(), (true, true));
+            }
+        }
+        assert_eq!(sim.live_phase_switches(), 0);
+    }
+
+    #[test]
+    fn meters_energy() {
+        let (sim, mut controller) = start(CarProfile::default());
+        controller
+            .control_channel()
+            .send(ControlMessage::SetMaxPower(3600))
+            .unwrap();
+        sim.plug_in();
+        run_for(&sim, &mut controller, Duration::from_secs(120));
+        sim.unplug();
+        run_for(&sim, &mut controller, Duration::from_secs(3));
+
+        let status = controller.status().lock().unwrap().clone();
+        let car_energy = sim.car_energy() as u32;
+        assert!(car_energy > 90, "{car_energy} Wh");
+        assert!(status.session_energy.abs_diff(car_energy) <= car_energy / 10);
+        assert_eq!(status.lifetime_energy, status.session_energy as u64);
+
+        // Lifetime energy survives a reboot, session energy restarts on the next plug in
+        let mut controller =
+            PhiEvseController::new(sim.peripherals(), ControllerSettings::default());
+        controller.init();
+        sim.plug_in();
+        run_for(&sim, &mut controller, Duration::from_secs(1));
+        let rebooted = controller.status().lock().unwrap().clone();
+        assert_eq!(rebooted.lifetime_energy, status.lifetime_energy);
+        assert_eq!(rebooted.session_energy, 0);
+    }
+
+    #[test]
+    fn retries_loading_storage() {
+        let sim = Simulation::new(CarProfile::default());
+        sim.peripherals()
+            .storage
+            .set_u64(LIFETIME_ENERGY_KEY, 1000)
+            .unwrap();
+        sim.set_storage_failure(true);
+        let mut controller =
+            PhiEvseController::new(sim.peripherals(), ControllerSettings::default());
+        controller.init();
+        controller
+            .control_channel()
+            .send(ControlMessage::SetMaxPower(3600))
+            .unwrap();
+        sim.plug_in();
+        run_for(&sim, &mut controller, Duration::from_secs(60));
+        sim.unplug();
+        run_for(&sim, &mut controller, Duration::from_secs(3));
+        let session_energy = controller.status().lock().unwrap().session_energy as u64;
+        assert!(session_energy > 0);
+        assert_eq!(controller.sessions().lock().unwrap().completed(), 1);
+
+        // Loaded once the storage is back, keeping what was metered meanwhile
+        sim.set_storage_failure(false);
+        run_for(&sim, &mut controller, STORAGE_RETRY_INTERVAL);
+        let status = controller.status().lock().unwrap().clone();
+        assert_eq!(status.lifetime_energy, 1000 + session_energy);
+        let history = SessionHistory::load(&sim.peripherals().storage).unwrap();
+        assert_eq!(history.completed(), 1);
+    }
+
+    #[test]
+    fn records_sessions() {
+        let (sim, mut controller) = start(CarProfile::default());
+        let control = controller.control_channel();
+        control.send(ControlMessage::SetMaxPower(3000)).unwrap();
+        sim.plug_in();
+        run_for(&sim, &mut controller, Duration::from_secs(10));
+        control.send(ControlMessage::SetMaxPower(4000)).unwrap();
+        run_for(&sim, &mut controller, Duration::from_secs(20));
+        sim.unplug();
+        run_for(&sim, &mut controller, Duration::from_secs(3));
+
+        sim.plug_in();
+        run_for(&sim, &mut controller, Duration::from_secs(10));
+        control.send(ControlMessage::Shutdown).unwrap();
+        run_for(&sim, &mut controller, STOP_TIMEOUT + Duration::from_secs(1));
+
+        let sessions = controller.sessions();
+        let sessions = sessions.lock().unwrap();
+        assert_eq!(sessions.completed(), 2);
+        let [last, first] = [&sessions.sessions()[0], &sessions.sessions()[1]];
+        assert_eq!(first.end_reason, EndReason::Unplugged);
+        assert_eq!(last.end_reason, EndReason::Shutdown);
+        assert!(first.energy > 0);
+        assert!(
+            (3500..4300).contains(&first.peak_power),
+            "{} W",
+            first.peak_power
+        );
+        assert!(first.one_phase && !first.three_phase);
+        let setpoints: Vec<u32> = first.setpoints.iter().map(|s| s.power).collect();
+        assert_eq!(setpoints, [3000, 4000]);
+    }
+
+    #[test]
+    fn reports_faults() {
+        let (sim, mut controller) = start(CarProfile {
+            pilot_error: 14000,
+            ..CarProfile::one_phase()
+        });
+        controller
+            .control_channel()
+            .send(ControlMessage::SetMaxPower(3000))
+            .unwrap();
+        sim.plug_in();
+        run_for(&sim, &mut controller, Duration::from_secs(30));
+
+        let status = controller.status().lock().unwrap().clone();
+        assert_eq!(status.state, PhiEvseState::Error);
+        assert_eq!(status.fault, Some(Fault::Overcurrent(1)));
+        assert_eq!(sim.relays(), (false, false));
+
+        sim.unplug();
+        run_for(&sim, &mut controller, Duration::from_secs(1));
+        let status = controller.status().lock().unwrap().clone();
+        assert_eq!(status.state, PhiEvseState::NotConnected);
+        assert_eq!(status.fault, None);
+        assert_eq!(status.recent_faults[0].fault, Fault::Overcurrent(1));
+    }
+
+    #[test]
+    fn trips_quickly_on_large_overcurrent() {
+        let (sim, mut controller) = start(CarProfile {
+            pilot_error: 20000,
+            ..CarProfile::one_phase()
+        });
+        controller
+            .control_channel()
+            .send(ControlMessage::SetMaxPower(3000))
+            .unwrap();
+        sim.plug_in();
+        // Over 1.5 times the installation limit and its tolerance
+        let end = sim.now() + Duration::from_secs(30);
+        while sim.car_currents()[0] < 27000 && sim.now() < end {
+            run_for(&sim, &mut controller, TICK);
+        }
+        let over_since = sim.now();
+        while state(&controller) != PhiEvseState::Error && sim.now() < end {
+            run_for(&sim, &mut controller, TICK);
+        }
+        assert_eq!(
+            controller.status().lock().unwrap().fault,
+            Some(Fault::Overcurrent(1))
+        );
+        let reaction = sim.now() - over_since;
+        assert!(reaction < Duration::from_secs(1), "{reaction:?}");
+    }
+
+    #[test]
+    fn trips_on_overcurrent_while_stopping() {
+        let (sim, mut controller) = start(CarProfile::one_phase());
+        let control = controller.control_channel();
+        control.send(ControlMessage::SetMaxPower(3000)).unwrap();
+        sim.plug_in();
+        run_for(&sim, &mut controller, Duration::from_secs(20));
+        assert_eq!(state(&controller), PhiEvseState::Charging);
+
+        // Paused, but still drawing over the installation limit with the contactors closed
+        control.send(ControlMessage::SetMaxPower(0)).unwrap();
+        run_for(&sim, &mut controller, TICK);
+        assert_eq!(state(&controller), PhiEvseState::Stopping);
+        sim.set_ct_stray_current(0, 30000);
+        run_for(&sim, &mut controller, Duration::from_secs(2));
+        assert_eq!(state(&controller), PhiEvseState::Error);
+        assert_eq!(
+            controller.status().lock().unwrap().fault,
+            Some(Fault::Overcurrent(1))
+        );
+    }
+
+    #[test]
+    fn stops_without_diode() {
+        let (sim, mut controller) = start(CarProfile {
+            has_diode: false,
+            ..Default::default()
+        });
+        controller
+            .control_channel()
+            .send(ControlMessage::SetMaxPower(3000))
+            .unwrap();
+        sim.plug_in();
+        run_for(&sim, &mut controller, Duration::from_secs(10));
+
+        let status = controller.status().lock().unwrap().clone();
+        assert_eq!(status.state, PhiEvseState::Error);
+        assert_eq!(status.fault, Some(Fault::DiodeMissing));
+        assert_eq!(sim.relays(), (false, false));
+    }
+
+    #[test]
+    fn diode_check_policy() {
+        let (sim, mut controller) = start_with(
+            CarProfile {
+                has_diode: false,
+                ..Default::default()
+            },
+            ControllerSettings {
+                diode_check: DiodeCheck::WarnOnly,
+                ..Default::default()
+            },
+            None,
+        );
+        controller
+            .control_channel()
+            .send(ControlMessage::SetMaxPower(3000))
+            .unwrap();
+        sim.plug_in();
+        run_for(&sim, &mut controller, Duration::from_secs(10));
+        assert_eq!(state(&controller), PhiEvseState::Charging);
+        assert!(controller.status().lock().unwrap().fault.is_none());
+    }
+
+    #[test]
+    fn ignores_pilot_negative_glitches() {
+        let (sim, mut controller) = start(CarProfile::default());
+        controller
+            .control_channel()
+            .send(ControlMessage::SetMaxPower(3000))
+            .unwrap();
+        sim.plug_in();
+        run_for(&sim, &mut controller, Duration::from_secs(10));
+        for _ in 0..DIODE_CHECK_TICKS - 1 {
+            controller
+                .control_pilot
+                .negative
+                .store(true, Ordering::Relaxed);
+            run_for(&sim, &mut controller, TICK);
+        }
+        run_for(&sim, &mut controller, Duration::from_secs(1));
+        assert_eq!(state(&controller), PhiEvseState::Charging);
+
+        for _ in 0..DIODE_CHECK_TICKS {
+            controller
+                .control_pilot
+                .negative
+                .store(true, Ordering::Relaxed);
+            run_for(&sim, &mut controller, TICK);
+        }
+        assert_eq!(state(&controller), PhiEvseState::Error);
+    }
+
+    #[test]
+    fn latches_welded_contactor() {
+        let (sim, mut controller) = start(CarProfile::default());
+        controller
+            .control_channel()
+            .send(ControlMessage::SetMaxPower(3000))
+            .unwrap();
+        sim.plug_in();
+        run_for(&sim, &mut controller, Duration::from_secs(10));
+        assert_eq!(state(&controller), PhiEvseState::Charging);
+
+        sim.set_main_contactor_fault(Some(ContactorFault::Welded));
+        sim.unplug();
+        run_for(&sim, &mut controller, Duration::from_secs(3));
+        let status = controller.status().lock().unwrap().clone();
+        assert_eq!(status.state, PhiEvseState::Error);
+        assert_eq!(status.fault, Some(Fault::RelayWelded));
+
+        // Refuses to charge again
+        sim.plug_in();
+        run_for(&sim, &mut controller, Duration::from_secs(10));
+        assert_eq!(state(&controller), PhiEvseState::Error);
+        assert_eq!(sim.relays(), (false, false));
+    }
+
+    #[test]
+    fn detects_contactor_not_closing() {
+        let (sim, mut controller) = start(CarProfile::default());
+        controller
+            .control_channel()
+            .send(ControlMessage::SetMaxPower(3000))
+            .unwrap();
+        sim.set_main_contactor_fault(Some(ContactorFault::StuckOpen));
+        sim.plug_in();
+        run_for(&sim, &mut controller, Duration::from_secs(10));
+        let status = controller.status().lock().unwrap().clone();
+        assert_eq!(status.state, PhiEvseState::Error);
+        assert_eq!(status.fault, Some(Fault::RelayFailedToClose));
+        assert_eq!(sim.relays(), (false, false));
+
+        sim.unplug();
+        run_for(&sim, &mut controller, Duration::from_secs(5));
+        assert_eq!(state(&controller), PhiEvseState::NotConnected);
+    }
+
+    #[test]
+    fn self_test_passes() {
+        let (sim, mut controller) = start(CarProfile::default());
+        run_for(&sim, &mut controller, Duration::from_secs(1));
+        assert_eq!(state(&controller), PhiEvseState::NotConnected);
+        let diagnostics = controller.diagnostics().lock().unwrap().clone();
+        assert_eq!(diagnostics.self_test.len(), 7);
+        assert!(diagnostics.self_test.iter().all(|c| c.passed));
+    }
+
+    #[test]
+    fn self_test_blocks_charging() {
+        for (fault, settings, expected) in [
+            (
+                ContactorFault::Welded,
+                Default::default(),
+                Fault::RelayWelded,
+            ),
+            (
+                ContactorFault::StuckOpen,
+                Default::default(),
+                Fault::RelayFailedToClose,
+            ),
+        ] {
+            let (sim, mut controller) = start_with(CarProfile::default(), settings, Some(fault));
+            controller
+                .control_channel()
+                .send(ControlMessage::SetMaxPower(3000))
+                .unwrap();
+            sim.plug_in();
+            run_for(&sim, &mut controller, Duration::from_secs(10));
+            let status = controller.status().lock().unwrap().clone();
+            assert_eq!(status.state, PhiEvseState::Error);
+            assert_eq!(status.fault, Some(expected));
+            assert_eq!(sim.relays(), (false, false));
+        }
+    }
+
+    #[test]
+    fn self_test_only_warns_on_current_sensors() {
+        let sim = Simulation::new(CarProfile {
+            response_time: Duration::from_secs(1),
+            ..CarProfile::one_phase()
+        });
+        sim.set_ct_stray_current(1, 3000);
+        let mut controller = PhiEvseController::new(sim.peripherals(), Default::default());
+        controller.init();
+        let diagnostics = controller.diagnostics().lock().unwrap().clone();
+        let check = diagnostics
+            .self_test
+            .iter()
+            .find(|c| c.name == "Current L2")
+            .unwrap();
+        assert!(!check.passed, "{}", check.detail);
+
+        sim.set_ct_stray_current(1, 0);
+        controller
+            .control_channel()
+            .send(ControlMessage::SetMaxPower(3000))
+            .unwrap();
+        sim.plug_in();
+        run_for(&sim, &mut controller, Duration::from_secs(20));
+        let status = controller.status().lock().unwrap().clone();
+        assert_eq!(status.state, PhiEvseState::Charging);
+        assert_eq!(status.fault, None);
+    }
+
+    #[test]
+    fn ventilation_policy() {
+        let car = CarProfile {
+            requires_ventilation: true,
+            ..Default::default()
+        };
+        for allow_ventilation in [false, true] {
+            let (sim, mut controller) = start_with(
+                car.clone(),
+                ControllerSettings {
+                    allow_ventilation,
+                    ..Default::default()
+                },
+                None,
+            );
+            controller
+                .control_channel()
+                .send(ControlMessage::SetMaxPower(3000))
+                .unwrap();
+            sim.plug_in();
+            run_for(&sim, &mut controller, Duration::from_secs(10));
+            let status = controller.status().lock().unwrap().clone();
+            if allow_ventilation {
+                assert_eq!(status.state, PhiEvseState::Charging);
+                assert_eq!(sim.relays(), (true, false));
+            } else {
+                assert_eq!(status.state, PhiEvseState::Error);
+                assert_eq!(status.fault, Some(Fault::VentilationRequired));
+                assert_eq!(sim.relays(), (false, false));
+            }
+        }
+    }
+
+    #[test]
+    fn stops_on_pilot_short_circuit() {
+        let (sim, mut controller) = start(CarProfile::default());
+        controller
+            .control_channel()
+            .send(ControlMessage::SetMaxPower(3000))
+            .unwrap();
+        sim.plug_in();
+        run_for(&sim, &mut controller, Duration::from_secs(10));
+        assert_eq!(state(&controller), PhiEvseState::Charging);
+
+        sim.set_pilot_short_circuit(true);
+        run_for(&sim, &mut controller, Duration::from_secs(1));
+        let status = controller.status().lock().unwrap().clone();
+        assert_eq!(status.state, PhiEvseState::Error);
+        assert_eq!(status.fault, Some(Fault::PilotShortCircuit));
+        assert_eq!(sim.relays(), (false, false));
+
+        sim.set_pilot_short_circuit(false);
+        sim.unplug();
+        run_for(&sim, &mut controller, Duration::from_secs(10));
+        assert_eq!(state(&controller), PhiEvseState::NotConnected);
+    }
+
+    #[test]
+    fn wakes_up_sleeping_car() {
+        let (sim, mut controller) = start(CarProfile::sleepy());
+        sim.plug_in();
+        run_for(&sim, &mut controller, Duration::from_secs(70));
+        assert!(sim.car_asleep());
+
+        controller
+            .control_channel()
+            .send(ControlMessage::SetMaxPower(3000))
+            .unwrap();
+        run_for(&sim, &mut controller, Duration::from_secs(90));
+        assert!(!sim.car_asleep());
+        assert_eq!(state(&controller), PhiEvseState::Charging);
+        assert!(sim.car_currents()[0] > 10000);
+    }
+
+    #[test]
+    fn gives_up_if_car_does_not_draw_current() {
+        // Ready to charge, but draws nothing of what the pilot offers
+        let (sim, mut controller) = start(CarProfile {
+            pilot_error: -32000,
+            ..Default::default()
+        });
+        controller
+            .control_channel()
+            .send(ControlMessage::SetMaxPower(3000))
+            .unwrap();
+        sim.plug_in();
+        run_for(&sim, &mut controller, Duration::from_secs(200));
+        let status = controller.status().lock().unwrap().clone();
+        assert_eq!(status.state, PhiEvseState::Error);
+        assert_eq!(status.fault, Some(Fault::ChargeStartTimeout));
+        assert_eq!(sim.relays(), (false, false));
+
+        sim.unplug();
+        run_for(&sim, &mut controller, Duration::from_secs(3));
+        let sessions = controller.sessions();
+        assert_eq!(
+            sessions.lock().unwrap().sessions()[0].end_reason,
+            EndReason::Fault(Fault::ChargeStartTimeout)
+        );
+    }
+
+    #[test]
+    fn regulates_current() {
+        for pilot_error in [-1500, 800] {
+            let (sim, mut controller) = start(CarProfile {
+                pilot_error,
+                ..CarProfile::one_phase()
+            });
+            controller
+                .control_channel()
+                .send(ControlMessage::SetMaxPower(3000))
+                .unwrap();
+            sim.plug_in();
+            run_for(&sim, &mut controller, Duration::from_secs(120));
+            let power = controller.status().lock().unwrap().power;
+            assert!(power.abs_diff(3000) < 60, "{pilot_error} mA off: {power} W");
+        }
+    }
+
+    #[test]
+    fn pauses_below_minimum_power() {
+        let (sim, mut controller) = start(CarProfile::one_phase());
+        let control = controller.control_channel();
+        control.send(ControlMessage::SetMaxPower(3000)).unwrap();
+        sim.plug_in();
+        run_for(&sim, &mut controller, Duration::from_secs(20));
+        assert_eq!(state(&controller), PhiEvseState::Charging);
+
+        control.send(ControlMessage::SetMaxPower(0)).unwrap();
+        run_for(&sim, &mut controller, Duration::from_secs(20));
+        let status = controller.status().lock().unwrap().clone();
+        assert_eq!(status.state, PhiEvseState::Connected);
+        assert_eq!(status.fault, None);
+        assert_eq!(sim.relays(), (false, false));
+        assert_eq!(sim.car_currents(), [0, 0, 0]);
+
+        control.send(ControlMessage::SetMaxPower(3000)).unwrap();
+        run_for(&sim, &mut controller, Duration::from_secs(20));
+        assert_eq!(state(&controller), PhiEvseState::Charging);
+        assert!(sim.car_currents()[0] > 10000);
+    }
+
+    #[test]
+    fn lowers_pilot_on_overcurrent() {
+        // Car drawing over the tolerance of 2A
+        let (sim, mut controller) = start(CarProfile {
+            pilot_error: 3000,
+            ..CarProfile::one_phase()
+        });
+        controller
+            .control_channel()
+            .send(ControlMessage::SetMaxPower(16 * 230))
+            .unwrap();
+        sim.plug_in();
+        run_for(&sim, &mut controller, Duration::from_secs(60));
+
+        let status = controller.status().lock().unwrap().clone();
+        assert_eq!(status.state, PhiEvseState::Charging);
+        assert_eq!(status.fault, None);
+        assert!(controller.offered_current < 14000);
+        assert!(sim.car_currents()[0] <= 16000 + 2000);
+    }
+
+    #[test]
+    fn recovers_after_transient_overcurrent() {
+        let (sim, mut controller) = start(CarProfile::one_phase());
+        controller
+            .control_channel()
+            .send(ControlMessage::SetMaxPower(3000))
+            .unwrap();
+        sim.plug_in();
+        run_for(&sim, &mut controller, Duration::from_secs(60));
+        assert_eq!(state(&controller), PhiEvseState::Charging);
+        let offered = controller.offered_current;
+
+        sim.set_ct_stray_current(0, 6000);
+        run_for(&sim, &mut controller, Duration::from_secs(2));
+        sim.set_ct_stray_current(0, 0);
+        assert!(controller.offered_current < offered - 3000);
+
+        run_for(&sim, &mut controller, Duration::from_secs(120));
+        let status = controller.status().lock().unwrap().clone();
+        assert_eq!(status.state, PhiEvseState::Charging);
+        assert_eq!(status.fault, None);
+        assert!(
+            controller.offered_current.abs_diff(offered) <= 500,
+            "{} mA",
+            controller.offered_current
+        );
+    }
+
+    #[test]
+    fn detects_car_phases() {
+        let (sim, mut controller) = start(CarProfile::one_phase());
+        controller
+            .control_channel()
+            .send(ControlMessage::SetMaxPower(6900))
+            .unwrap();
+        sim.plug_in();
+        run_for(&sim, &mut controller, Duration::from_secs(10));
+        let status = controller.status().lock().unwrap().clone();
+        assert!(status.three_phase);
+        assert!((2000..2500).contains(&status.power), "{} W", status.power);
+        assert_eq!(status.car_phases, None);
+
+        // Offers the whole power on the phase the car uses
+        run_for(&sim, &mut controller, Duration::from_secs(60));
+        let status = controller.status().lock().unwrap().clone();
+        assert_eq!(status.car_phases, Some(1));
+        assert!(!status.phase_imbalance);
+        assert!((3500..3900).contains(&status.power), "{} W", status.power);
+
+        sim.unplug();
+        run_for(&sim, &mut controller, Duration::from_secs(3));
+        assert_eq!(controller.status().lock().unwrap().car_phases, None);
+    }
+
+    #[test]
+    fn tests_pilot_duty() {
+        let (sim, mut controller) = start(CarProfile::default());
+        let control = controller.control_channel();
+        let diagnostics = controller.diagnostics();
+        control
+            .send(ControlMessage::TestPilotDuty(Some(1000)))
+            .unwrap();
+        run_for(&sim, &mut controller, Duration::from_secs(1));
+        assert_eq!(controller.peripherals.control_pilot.get_duty(), 1000);
+        assert_eq!(diagnostics.lock().unwrap().pilot_test_duty, Some(1000));
+        assert_eq!(state(&controller), PhiEvseState::NotConnected);
+
+        // Stops when a car is plugged in
+        sim.plug_in();
+        run_for(&sim, &mut controller, Duration::from_secs(1));
+        assert_eq!(state(&controller), PhiEvseState::Connected);
+        assert_eq!(diagnostics.lock().unwrap().pilot_test_duty, None);
+        control
+            .send(ControlMessage::TestPilotDuty(Some(1000)))
+            .unwrap();
+        run_for(&sim, &mut controller, Duration::from_secs(1));
+        assert_eq!(diagnostics.lock().unwrap().pilot_test_duty, None);
+        assert_ne!(controller.peripherals.control_pilot.get_duty(), 1000);
+    }
+
+    #[test]
+    fn measures_mains_frequency() {
+        let (sim, mut controller) = start(CarProfile::one_phase());
+        sim.set_mains_frequency(60.0);
+        controller
+            .control_channel()
+            .send(ControlMessage::SetMaxPower(3000))
+            .unwrap();
+        run_for(&sim, &mut controller, Duration::from_secs(1));
+        assert_eq!(controller.status().lock().unwrap().mains_frequency, None);
+
+        sim.plug_in();
+        run_for(&sim, &mut controller, Duration::from_secs(20));
+        let status = controller.status().lock().unwrap().clone();
+        assert_eq!(status.mains_frequency, Some(60.0));
+        assert!((2800..3200).contains(&status.power), "{} W", status.power);
+    }
+
+    #[test]
+    fn detects_faulty_ct() {
+        let (sim, mut controller) = start(CarProfile::one_phase());
+        sim.set_ct_disconnected(1, true);
+        run_for(&sim, &mut controller, Duration::from_secs(5));
+        let status = controller.status().lock().unwrap().clone();
+        assert_eq!(status.faulty_cts, [false, true, false]);
+        let profile = controller.diagnostics().lock().unwrap().ct_profiles[0].unwrap();
+        assert!((profile.bias_mv - 1400.0).abs() < 2.0, "{profile:?}");
+
+        sim.set_ct_disconnected(1, false);
+        run_for(&sim, &mut controller, Duration::from_secs(10));
+        assert_eq!(controller.status().lock().unwrap().faulty_cts, [false; 3]);
+
+        // Only learns while the contactors are open
+        controller
+            .control_channel()
+            .send(ControlMessage::SetMaxPower(3000))
+            .unwrap();
+        sim.plug_in();
+        run_for(&sim, &mut controller, Duration::from_secs(20));
+        assert_eq!(state(&controller), PhiEvseState::Charging);
+        let diagnostics = controller.diagnostics().lock().unwrap().clone();
+        let current = diagnostics.currents[0];
+        assert!(current > 12000, "{current} mA");
+        let profile = diagnostics.ct_profiles[0].unwrap();
+        assert!((profile.bias_mv - 1400.0).abs() < 2.0, "{profile:?}");
+        assert!(profile.noise_mv < 10.0, "{profile:?}");
+    }
+
+    #[test]
+    fn measures_pilot_pwm() {
+        let (sim, mut controller) = start(CarProfile::one_phase());
+        controller
+            .control_channel()
+            .send(ControlMessage::SetMaxPower(3000))
+            .unwrap();
+        sim.plug_in();
+        run_for(&sim, &mut controller, Duration::from_secs(20));
+        assert_eq!(state(&controller), PhiEvseState::Charging);
+        let status = controller.status().lock().unwrap().clone();
+        let pwm = status.pilot_pwm.unwrap();
+        assert!((pwm.frequency - 1001.0).abs() < 1.0, "{pwm:?}");
+        let read = status.pilot_current.unwrap();
+        assert!(
+            read.abs_diff(controller.offered_current) <= 300,
+            "{read} mA"
+        );
+        assert_eq!(controller.pwm_monitor.trim(), 0);
+
+        sim.unplug();
+        run_for(&sim, &mut controller, Duration::from_secs(3));
+        assert_eq!(state(&controller), PhiEvseState::NotConnected);
+        assert_eq!(controller.status().lock().unwrap().pilot_pwm, None);
+    }
+
+    #[test]
+    fn trims_pilot_pwm() {
+        // Calibrated 1A low
+        let mut settings = ControllerSettings::default();
+        for (mamps, _) in &mut settings.pilot_duty.table {
+            *mamps += 1000;
+        }
+        let (sim, mut controller) = start_with(CarProfile::one_phase(), settings, None);
+        controller
+            .control_channel()
+            .send(ControlMessage::SetMaxPower(3000))
+            .unwrap();
+        sim.plug_in();
+        run_for(&sim, &mut controller, Duration::from_secs(30));
+        assert_eq!(state(&controller), PhiEvseState::Charging);
+        assert!((700..=1300).contains(&controller.pwm_monitor.trim()));
+        let read = controller.status().lock().unwrap().pilot_current.unwrap();
+        assert!(
+            read.abs_diff(controller.offered_current) <= 300,
+            "{read} mA"
+        );
+
+        // Learnt again for the next car
+        sim.unplug();
+        run_for(&sim, &mut controller, Duration::from_secs(3));
+        assert_eq!(state(&controller), PhiEvseState::NotConnected);
+        assert_eq!(controller.pwm_monitor.trim(), 0);
+    }
+
+    #[test]
+    fn ignores_pilot_pwm_locked_to_the_adc() {
+        // Calibrated 1A low, but the duty can't be measured at exactly 1kHz
+        let mut settings = ControllerSettings::default();
+        for (mamps, _) in &mut settings.pilot_duty.table {
+            *mamps += 1000;
+        }
+        let (sim, mut controller) = start_with(CarProfile::one_phase(), settings, None);
+        sim.set_pilot_frequency(1000);
+        controller
+            .control_channel()
+            .send(ControlMessage::SetMaxPower(3000))
+            .unwrap();
+        sim.plug_in();
+        run_for(&sim, &mut controller, Duration::from_secs(30));
+        let status = controller.status().lock().unwrap().clone();
+        assert_eq!(status.state, PhiEvseState::Charging);
+        assert_eq!(status.fault, None);
+        assert!(status.pilot_pwm.unwrap().locked);
+        assert_eq!(status.pilot_current, None);
+        assert_eq!(controller.pwm_monitor.trim(), 0);
+    }
+
+    #[test]
+    fn faults_on_pilot_pwm_mismatch() {
+        // The standard mapping doesn't make up for the distortion of the pilot
+        let mut settings = ControllerSettings::default();
+        settings.pilot_duty.mapping = settings::DutyMapping::Standard;
+        // Within the overcurrent tolerance, so only the pilot check can catch it
+        let car = CarProfile {
+            max_current: 14000,
+            ..CarProfile::one_phase()
+        };
+        let (sim, mut controller) = start_with(car, settings, None);
+        controller
+            .control_channel()
+            .send(ControlMessage::SetMaxPower(3000))
+            .unwrap();
+        sim.plug_in();
+        run_for(&sim, &mut controller, Duration::from_secs(10));
+        assert_eq!(state(&controller), PhiEvseState::Error);
+        let status = controller.status().lock().unwrap().clone();
+        assert_eq!(status.fault, Some(Fault::PilotPwmMismatch));
     }
 }
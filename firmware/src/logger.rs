@@ -4,7 +4,7 @@ use std::{
 };
 
 use log::Log;
-use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use time::{OffsetDateTime, format_description::well_known::Rfc3339};
 
 #[derive(Debug)]
 pub struct StringRingBuffer<const S: usize> {
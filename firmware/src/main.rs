@@ -11,13 +11,14 @@ use esp_idf_svc::netif::{EspNetif, NetifConfiguration};
 use esp_idf_svc::sntp::EspSntp;
 use esp_idf_svc::wifi::WifiDriver;
 use esp_idf_sys::*;
+use phievse::clock::SystemClock;
 use phievse::driver::gpio::InterruptPin;
 use phievse::gpio::RelayPin;
 use phievse::*;
 use std::error::Error;
 
 // Using as library
-use phievse::driver::{adc::*, watchdog::*};
+use phievse::driver::{adc::*, storage::*, watchdog::*};
 use phievse::logger::RingBufferLogger;
 
 // Using directly
@@ -74,7 +75,7 @@ fn main() -> Result<(), Box<dyn Error>> {
     let timer = LedcTimerDriver::new(
         peripherals.ledc.timer0,
         &TimerConfig::default()
-            .frequency(1.kHz().into())
+            .frequency(Hertz(pilot_pwm::PILOT_FREQUENCY_HZ))
             .resolution(Resolution::Bits14),
     )?;
 
@@ -107,23 +108,28 @@ fn main() -> Result<(), Box<dyn Error>> {
         pins.gpio4,
     )?;
 
-    let control_pilot = LedcDriver::new(peripherals.ledc.channel0, &timer, pins.gpio2)?;
-    let pilot_negative = InterruptPin::new(g9);
-    let controller = Box::new(PhiEvseController::new(PhiEvsePeripherals {
-        relay_main,
-        relay_3_phase,
-        v_sense: (g10, g19, g7),
-        v_sense_3_phase: g5,
-        analog,
-        control_pilot,
-        pilot_negative,
-        watchdog: EspWatchdog,
-    }));
-
     // Load configuration from NVS
     let config = PhiEvseConfig::load()?;
     println!("{config:#?}");
 
+    let control_pilot = LedcDriver::new(peripherals.ledc.channel0, &timer, pins.gpio2)?;
+    let pilot_negative = InterruptPin::new(g9);
+    let controller = Box::new(PhiEvseController::new(
+        PhiEvsePeripherals {
+            relay_main,
+            relay_3_phase,
+            v_sense: (g10, g19, g7),
+            v_sense_3_phase: g5,
+            analog,
+            control_pilot,
+            pilot_negative,
+            watchdog: EspWatchdog,
+            clock: SystemClock::default(),
+            storage: NvsStorage,
+        },
+        config.controller.clone(),
+    ));
+
     // Build Wifi configurations
     let mut ap_config = AccessPointConfiguration {
         ssid: config.ap.ssid.as_str().try_into().unwrap(),
@@ -178,12 +184,19 @@ fn main() -> Result<(), Box<dyn Error>> {
     let _h = httpd::start(
         ring_buffer,
         controller.status(),
+        controller.sessions(),
+        controller.diagnostics(),
         controller.control_channel(),
     )?;
     println!("HTTP running");
 
     if let Some(uri) = config.mqtt_uri {
-        mqtt::start(&uri, controller.status(), controller.control_channel())?;
+        mqtt::start(
+            &uri,
+            controller.status(),
+            controller.sessions(),
+            controller.control_channel(),
+        )?;
     }
 
     // Run
@@ -1,5 +1,5 @@
 use std::{
-    sync::{mpsc, Arc, Mutex},
+    sync::{Arc, Mutex, mpsc},
     thread,
     time::Duration,
 };
@@ -7,14 +7,17 @@ use std::{
 use embedded_svc::mqtt::client::QoS;
 use esp_idf_svc::mqtt::client::{EspMqttClient, EventPayload, MqttClientConfiguration};
 use esp_idf_sys::EspError;
-use phievse::{ControlMessage, PhiEvseStatus};
+use phievse::{ControlMessage, PhiEvseStatus, session::SessionHistory};
 
-fn send_autodiscovery(mqtt: &mut EspMqttClient) -> Result<(), EspError> {
+fn send_autodiscovery(mqtt: &mut EspMqttClient, power_limit: u32) -> Result<(), EspError> {
+    let mut max_power: serde_json::Value =
+        serde_json::from_slice(include_bytes!("max_power.json")).unwrap();
+    max_power["max"] = power_limit.into();
     mqtt.publish(
         "homeassistant/number/phievse/max_power/config",
         QoS::AtMostOnce,
         true,
-        include_bytes!("max_power.json"),
+        &serde_json::to_vec(&max_power).unwrap(),
     )?;
     mqtt.publish(
         "homeassistant/sensor/phievse/power/config",
@@ -28,6 +31,30 @@ fn send_autodiscovery(mqtt: &mut EspMqttClient) -> Result<(), EspError> {
         true,
         include_bytes!("state.json"),
     )?;
+    mqtt.publish(
+        "homeassistant/sensor/phievse/session_energy/config",
+        QoS::AtMostOnce,
+        true,
+        include_bytes!("session_energy.json"),
+    )?;
+    mqtt.publish(
+        "homeassistant/sensor/phievse/lifetime_energy/config",
+        QoS::AtMostOnce,
+        true,
+        include_bytes!("lifetime_energy.json"),
+    )?;
+    mqtt.publish(
+        "homeassistant/sensor/phievse/fault/config",
+        QoS::AtMostOnce,
+        true,
+        include_bytes!("fault.json"),
+    )?;
+    mqtt.publish(
+        "homeassistant/sensor/phievse/mains_frequency/config",
+        QoS::AtMostOnce,
+        true,
+        include_bytes!("mains_frequency.json"),
+    )?;
 
     Ok(())
 }
@@ -43,6 +70,34 @@ fn send_state(mqtt: &mut EspMqttClient, status: Arc<Mutex<PhiEvseStatus>>) -> Re
     Ok(())
 }
 
+/// Publishes the sessions completed after the `sent` one, starting after the ones loaded from storage
+fn send_sessions(
+    mqtt: &mut EspMqttClient,
+    sessions: &Mutex<SessionHistory>,
+    sent: &mut Option<u64>,
+) -> Result<(), EspError> {
+    let history = sessions.lock().unwrap();
+    let Some(loaded) = history.loaded() else {
+        // Wait for the history, or the stored sessions would be sent again
+        return Ok(());
+    };
+    let sent = sent.get_or_insert(loaded);
+    let new = history
+        .completed()
+        .saturating_sub(*sent)
+        .min(history.sessions().len() as u64);
+    for session in history.sessions().iter().take(new as usize).rev() {
+        mqtt.publish(
+            "phievse/session",
+            QoS::AtLeastOnce,
+            false,
+            &serde_json::to_vec(session).unwrap(),
+        )?;
+    }
+    *sent = history.completed();
+    Ok(())
+}
+
 enum Event {
     Connected,
     SetMaxPower(u32),
@@ -51,6 +106,7 @@ enum Event {
 pub fn start(
     mqtt_uri: &str,
     status: Arc<Mutex<PhiEvseStatus>>,
+    sessions: Arc<Mutex<SessionHistory>>,
     control_channel: mpsc::Sender<ControlMessage>,
 ) -> Result<(), EspError> {
     let (tx, rx) = mpsc::channel();
@@ -79,6 +135,8 @@ pub fn start(
     )?;
     thread::spawn(move || {
         let mut connected = false;
+        // Only sessions completed since started are published
+        let mut sessions_sent = None;
         loop {
             if let Ok(msg) = rx.try_recv() {
                 match msg {
@@ -88,7 +146,8 @@ pub fn start(
                                 log::warn!("Could not susbcribe");
                                 0
                             });
-                        send_autodiscovery(&mut mqtt)
+                        let power_limit = status.lock().unwrap().power_limit;
+                        send_autodiscovery(&mut mqtt, power_limit)
                             .unwrap_or_else(|_| log::warn!("Could not send autodiscovery"));
                         connected = true;
                     }
@@ -105,6 +164,8 @@ pub fn start(
             if connected {
                 send_state(&mut mqtt, status.clone())
                     .unwrap_or_else(|_| log::warn!("Could not send state"));
+                send_sessions(&mut mqtt, &sessions, &mut sessions_sent)
+                    .unwrap_or_else(|_| log::warn!("Could not send sessions"));
             }
 
             thread::sleep(Duration::from_secs(5));
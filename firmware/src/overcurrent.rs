@@ -0,0 +1,234 @@
+//! Per-phase overcurrent protection with a time-current curve
+//!
+//! Cars may draw a bit over the limit (IEC 61851-1 allows 2A up to 20A and 10% above) and need up to
+//! 5s to follow a lower pilot. Over the current the installation can carry, each phase accumulates
+//! stress faster the higher the overshoot, and trips once it's full. Drawing over the current
+//! allowed by the power setpoint only lowers the pilot, as the wiring is not at risk.
+
+use std::time::Duration;
+
+/// Time the car has to follow a lower pilot
+const PILOT_RESPONSE_TIME: Duration = Duration::from_secs(5);
+
+/// Minimum overshoot allowed over the limit (mA)
+const MIN_TOLERANCE: u32 = 2000;
+
+/// Time to trip by current over the limit plus the tolerance, highest first
+const TRIP_CURVE: [(f32, Duration); 3] = [
+    (1.5, Duration::from_millis(500)),
+    (1.2, Duration::from_secs(2)),
+    (1.0, Duration::from_secs(10)),
+];
+
+/// Time to forget a full stress once back under the tolerance
+const COOL_DOWN: Duration = Duration::from_secs(8);
+
+#[derive(PartialEq, Debug)]
+pub enum OvercurrentAction {
+    /// A phase (1-3) went over the limit, lower the pilot `by` the overshoot (mA)
+    LowerPilot { phase: u8, by: u32 },
+    /// A phase (1-3) drew more than the installation can carry for too long, open the contactors
+    Trip(u8),
+}
+
+pub struct OvercurrentGuard {
+    /// Current the installation and cable can carry (mA), phases trip over it
+    max_current: u32,
+    /// Current the car is allowed to draw by the setpoint (mA), the pilot is lowered over it
+    limit: u32,
+    /// Lower limit that applies once the car had time to follow the pilot
+    pending_limit: Option<(u32, Duration)>,
+    /// Fraction of the trip time spent over the tolerance, per phase
+    stress: [f32; 3],
+    over: [bool; 3],
+    last_update: Option<Duration>,
+}
+
+impl OvercurrentGuard {
+    pub fn new(max_current: u32) -> Self {
+        Self {
+            max_current,
+            limit: 0,
+            pending_limit: None,
+            stress: [0.0; 3],
+            over: [false; 3],
+            last_update: None,
+        }
+    }
+
+    /// Current the car may draw per phase (mA). Lower limits are enforced after
+    /// `PILOT_RESPONSE_TIME`.
+    pub fn set_limit(&mut self, mamps: u32, now: Duration) {
+        if mamps >= self.limit || self.last_update.is_none() {
+            self.limit = mamps;
+            self.pending_limit = None;
+        } else if self
+            .pending_limit
+            .is_none_or(|(pending, _)| mamps < pending)
+        {
+            self.pending_limit = Some((mamps, now + PILOT_RESPONSE_TIME));
+        }
+    }
+
+    /// Checks the current (mA) of each phase
+    pub fn update(&mut self, currents: [u32; 3], now: Duration) -> Option<OvercurrentAction> {
+        let dt = self
+            .last_update
+            .map(|last| now.saturating_sub(last).as_secs_f32())
+            .unwrap_or(0.0);
+        self.last_update = Some(now);
+        if let Some((mamps, from)) = self.pending_limit
+            && now >= from
+        {
+            self.limit = mamps;
+            self.pending_limit = None;
+        }
+
+        let allowed = self.limit + tolerance(self.limit);
+        let trip_from = self.max_current + tolerance(self.max_current);
+        let mut action = None;
+        for (phase, &mamps) in currents.iter().enumerate() {
+            let ratio = mamps as f32 / trip_from as f32;
+            let was_over = self.over[phase];
+            self.over[phase] = mamps > allowed;
+            if let Some(&(_, trip_time)) = TRIP_CURVE.iter().find(|&&(r, _)| ratio > r) {
+                self.stress[phase] += dt / trip_time.as_secs_f32();
+            } else {
+                self.stress[phase] = (self.stress[phase] - dt / COOL_DOWN.as_secs_f32()).max(0.0);
+            }
+
+            let phase_number = phase as u8 + 1;
+            if self.stress[phase] >= 1.0 {
+                return Some(OvercurrentAction::Trip(phase_number));
+            } else if self.over[phase] && !was_over && action.is_none() {
+                action = Some(OvercurrentAction::LowerPilot {
+                    phase: phase_number,
+                    by: mamps - self.limit,
+                });
+            }
+        }
+        action
+    }
+
+    /// Forgets the stress and limits, e.g: when the contactors open
+    pub fn reset(&mut self) {
+        *self = Self::new(self.max_current);
+    }
+}
+
+/// Overshoot allowed over `limit` (mA) indefinitely
+fn tolerance(limit: u32) -> u32 {
+    (limit / 10).max(MIN_TOLERANCE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TICK: Duration = Duration::from_millis(100);
+
+    /// Time until tripping while drawing `currents` with a `limit` (mA) for both the installation
+    /// and the setpoint
+    fn time_to_trip(limit: u32, currents: [u32; 3]) -> Option<Duration> {
+        let mut guard = OvercurrentGuard::new(limit);
+        guard.set_limit(limit, Duration::ZERO);
+        let mut now = Duration::ZERO;
+        while now < Duration::from_secs(60) {
+            if let Some(OvercurrentAction::Trip(_)) = guard.update(currents, now) {
+                return Some(now);
+            }
+            now += TICK;
+        }
+        None
+    }
+
+    #[test]
+    fn follows_the_curve() {
+        assert_eq!(time_to_trip(16000, [17900, 17900, 17900]), None);
+        assert_eq!(time_to_trip(32000, [35000, 0, 0]), None);
+        let slow = time_to_trip(16000, [19000, 0, 0]).unwrap();
+        let medium = time_to_trip(16000, [23000, 0, 0]).unwrap();
+        let fast = time_to_trip(16000, [30000, 0, 0]).unwrap();
+        assert!((Duration::from_secs(9)..=Duration::from_secs(11)).contains(&slow));
+        assert!((Duration::from_secs(1)..=Duration::from_secs(3)).contains(&medium));
+        assert!(fast < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn checks_each_phase() {
+        let mut guard = OvercurrentGuard::new(10000);
+        guard.set_limit(10000, Duration::ZERO);
+        assert_eq!(guard.update([10000, 10000, 10000], Duration::ZERO), None);
+        assert_eq!(
+            guard.update([10000, 14000, 10000], TICK),
+            Some(OvercurrentAction::LowerPilot { phase: 2, by: 4000 })
+        );
+        // Only once
+        assert_eq!(guard.update([10000, 14000, 10000], TICK * 2), None);
+        assert_eq!(
+            time_to_trip(10000, [10000, 10000, 16000]),
+            Some(Duration::from_secs(2))
+        );
+    }
+
+    #[test]
+    fn gives_time_to_follow_the_pilot() {
+        let mut guard = OvercurrentGuard::new(16000);
+        guard.set_limit(16000, Duration::ZERO);
+        guard.update([16000; 3], Duration::ZERO);
+        guard.set_limit(8000, Duration::from_secs(1));
+        let mut now = Duration::from_secs(1);
+        while now < Duration::from_secs(6) {
+            assert_eq!(guard.update([16000; 3], now), None);
+            now += TICK;
+        }
+        assert!(matches!(
+            guard.update([16000; 3], now),
+            Some(OvercurrentAction::LowerPilot { .. })
+        ));
+
+        // Higher limits apply right away
+        guard.set_limit(16000, now);
+        assert_eq!(guard.limit, 16000);
+    }
+
+    #[test]
+    fn only_lowers_pilot_over_the_setpoint() {
+        let mut guard = OvercurrentGuard::new(16000);
+        guard.set_limit(6000, Duration::ZERO);
+        let mut now = Duration::ZERO;
+        let mut lowered = 0;
+        while now < Duration::from_secs(60) {
+            match guard.update([16000, 10000, 10000], now) {
+                Some(OvercurrentAction::LowerPilot {
+                    phase: 1,
+                    by: 10000,
+                }) => lowered += 1,
+                action => assert_eq!(action, None),
+            }
+            now += TICK;
+        }
+        assert_eq!(lowered, 1);
+    }
+
+    #[test]
+    fn cools_down() {
+        let mut guard = OvercurrentGuard::new(16000);
+        guard.set_limit(16000, Duration::ZERO);
+        let mut now = Duration::ZERO;
+        for _ in 0..3 {
+            // 8s over the limit, 10s under it
+            for _ in 0..80 {
+                assert_ne!(
+                    guard.update([19000; 3], now),
+                    Some(OvercurrentAction::Trip(1))
+                );
+                now += TICK;
+            }
+            for _ in 0..100 {
+                guard.update([16000; 3], now);
+                now += TICK;
+            }
+        }
+    }
+}
@@ -0,0 +1,300 @@
+//! Chooses between 1 and 3 phase charging for a given power, without chattering the relays
+//!
+//! Switching up and down uses different thresholds, a minimum time has to pass between switches and
+//! there's a maximum number of switches per day. Only switches of the relay count, the plan can
+//! change freely while the contactors are open.
+
+use std::{collections::VecDeque, time::Duration};
+
+use serde::Serialize;
+
+use crate::settings::{ControllerSettings, InstallationLimits};
+
+/// Minimum current per phase. A bit over 6A as some cars cut off when too close to the minimum.
+pub const MIN_CURRENT: u32 = 6500;
+
+const DAY: Duration = Duration::from_secs(24 * 3600);
+
+/// Reason why the planner is not switching phases even if the power asks for it
+#[derive(PartialEq, Debug, Copy, Clone, Serialize)]
+pub enum PhaseHold {
+    MinimumDwell,
+    DailyLimit,
+}
+
+pub struct PhasePlanner {
+    installation: InstallationLimits,
+    three_phase_above: u32,
+    one_phase_below: u32,
+    min_dwell: Duration,
+    max_daily_switches: u32,
+
+    /// Phases 2 and 3 are available at the input
+    supply_three_phase: bool,
+    /// Phases the car draws current from, if known
+    car_phases: Option<u8>,
+
+    three_phase: bool,
+    hold: Option<PhaseHold>,
+    /// Times the relay switched in the last day
+    switches: VecDeque<Duration>,
+}
+
+impl PhasePlanner {
+    pub fn new(settings: &ControllerSettings) -> Self {
+        Self {
+            installation: settings.installation.clone(),
+            three_phase_above: settings.three_phase_above,
+            one_phase_below: settings.one_phase_below,
+            min_dwell: settings.phase_min_dwell,
+            max_daily_switches: settings.phase_max_daily_switches,
+            supply_three_phase: true,
+            car_phases: None,
+            three_phase: false,
+            hold: None,
+            switches: VecDeque::new(),
+        }
+    }
+
+    /// Returns the maximum current per phase (mA) and whether to use 3 phases to charge at `watts`
+    pub fn plan(&mut self, watts: u32, now: Duration) -> (u32, bool) {
+        while self
+            .switches
+            .front()
+            .is_some_and(|&t| now.saturating_sub(t) >= DAY)
+        {
+            self.switches.pop_front();
+        }
+
+        let wanted = match watts {
+            _ if !self.installation.three_phase || !self.supply_three_phase => false,
+            // No point in 3 phases for a 1 phase car
+            _ if self.car_phases == Some(1) => false,
+            // Not charging, no reason to switch
+            0 => self.three_phase,
+            w if self.three_phase => w >= self.one_phase_below,
+            w => w >= self.three_phase_above,
+        };
+
+        self.hold = None;
+        if wanted != self.three_phase {
+            if self.three_phase && !self.supply_three_phase {
+                // Can't hold 3 phases without the supply
+                self.three_phase = false;
+            } else if self
+                .switches
+                .back()
+                .is_some_and(|&t| now < t + self.min_dwell)
+            {
+                self.hold = Some(PhaseHold::MinimumDwell);
+            } else if self.switches.len() >= self.max_daily_switches as usize {
+                self.hold = Some(PhaseHold::DailyLimit);
+            } else {
+                self.three_phase = wanted;
+            }
+        }
+
+        (self.current(watts), self.three_phase)
+    }
+
+    fn current(&self, watts: u32) -> u32 {
+        let Some(total_mamps) = (watts as u64 * 1000).checked_div(self.installation.voltage as u64)
+        else {
+            return 0;
+        };
+        let total_mamps = u32::try_from(total_mamps).unwrap_or(u32::MAX);
+        let max_current = self.installation.max_current();
+        if total_mamps < MIN_CURRENT || max_current < MIN_CURRENT {
+            0
+        } else if self.three_phase {
+            // Under the minimum if holding 3 phases for a low power, pause instead of drawing more
+            let phases = self.car_phases.unwrap_or(3).clamp(1, 3) as u32;
+            match total_mamps / phases {
+                mamps if mamps < MIN_CURRENT => 0,
+                mamps => mamps.min(max_current),
+            }
+        } else {
+            total_mamps.min(max_current)
+        }
+    }
+
+    /// Whether phases 2 and 3 are available at the input. Without them, 1 phase is used right away.
+    pub fn set_supply_three_phase(&mut self, available: bool) {
+        self.supply_three_phase = available;
+    }
+
+    /// Phases the car draws current from when charging with 3 phases, `None` if not known
+    pub fn set_car_phases(&mut self, phases: Option<u8>) {
+        self.car_phases = phases;
+    }
+
+    pub fn hold(&self) -> Option<PhaseHold> {
+        self.hold
+    }
+
+    /// Records that the relay switched to the phases planned, which starts the minimum dwell time
+    pub fn switched(&mut self, now: Duration) {
+        self.switches.push_back(now);
+    }
+
+    /// Number of switches in the last day
+    pub fn switches(&self) -> u32 {
+        self.switches.len() as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn planner() -> PhasePlanner {
+        PhasePlanner::new(&ControllerSettings {
+            three_phase_above: 5000,
+            one_phase_below: 4500,
+            phase_min_dwell: Duration::from_secs(60),
+            phase_max_daily_switches: 3,
+            ..Default::default()
+        })
+    }
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn calculates_current() {
+        let mut p = planner();
+        assert_eq!(p.plan(0, Duration::ZERO), (0, false));
+        assert_eq!(p.plan(1000, Duration::ZERO), (0, false));
+        assert_eq!(p.plan(2300, Duration::ZERO), (10000, false));
+        assert_eq!(p.plan(4600, Duration::ZERO), (16000, false));
+        assert_eq!(p.plan(6900, Duration::ZERO), (10000, true));
+        assert_eq!(p.plan(20000, Duration::ZERO), (16000, true));
+    }
+
+    #[test]
+    fn hysteresis() {
+        let mut p = planner();
+        assert!(!p.plan(4800, Duration::ZERO).1);
+        assert!(p.plan(5000, MINUTE).1);
+        assert!(p.plan(4800, MINUTE * 2).1);
+        assert!(p.plan(4500, MINUTE * 3).1);
+        assert!(!p.plan(4499, MINUTE * 4).1);
+        assert!(!p.plan(4800, MINUTE * 5).1);
+    }
+
+    #[test]
+    fn minimum_dwell_time() {
+        let mut p = planner();
+        assert_eq!(p.plan(6900, Duration::ZERO), (10000, true));
+        p.switched(Duration::ZERO);
+
+        // Holds 3 phases, pausing as they need more than the power
+        assert_eq!(p.plan(2300, MINUTE / 2), (0, true));
+        assert_eq!(p.hold(), Some(PhaseHold::MinimumDwell));
+        assert_eq!(p.plan(4000, MINUTE / 2), (0, true));
+        assert_eq!(p.plan(4800, MINUTE / 2), (6956, true));
+
+        assert_eq!(p.plan(2300, MINUTE), (10000, false));
+        assert_eq!(p.hold(), None);
+        p.switched(MINUTE);
+        assert_eq!(p.switches(), 2);
+    }
+
+    #[test]
+    fn only_counts_relay_switches() {
+        let mut p = planner();
+        for n in 0..10 {
+            let watts = if n % 2 == 0 { 6900 } else { 2300 };
+            assert_eq!(p.plan(watts, MINUTE * n / 10).1, n % 2 == 0);
+            assert_eq!(p.hold(), None);
+        }
+        assert_eq!(p.switches(), 0);
+    }
+
+    #[test]
+    fn daily_limit() {
+        let mut p = planner();
+        for n in 0..3 {
+            let watts = if n % 2 == 0 { 6900 } else { 2300 };
+            p.plan(watts, MINUTE * n);
+            p.switched(MINUTE * n);
+        }
+        assert_eq!(p.plan(2300, MINUTE * 3), (0, true));
+        assert_eq!(p.hold(), Some(PhaseHold::DailyLimit));
+
+        // Switches are forgotten after a day
+        assert_eq!(p.plan(2300, DAY + MINUTE), (10000, false));
+        assert_eq!(p.switches(), 1);
+    }
+
+    #[test]
+    fn requires_three_phase_supply() {
+        let mut p = planner();
+        p.set_supply_three_phase(false);
+        assert_eq!(p.plan(6900, Duration::ZERO), (16000, false));
+        p.set_supply_three_phase(true);
+        assert_eq!(p.plan(6900, Duration::ZERO), (10000, true));
+        p.switched(Duration::ZERO);
+
+        // Falls back even if the switch should be held
+        p.set_supply_three_phase(false);
+        assert_eq!(p.plan(6900, MINUTE / 2), (16000, false));
+        assert_eq!(p.hold(), None);
+    }
+
+    #[test]
+    fn uses_car_phases() {
+        let mut p = planner();
+        assert_eq!(p.plan(6900, Duration::ZERO), (10000, true));
+        p.switched(Duration::ZERO);
+        p.set_car_phases(Some(2));
+        assert_eq!(p.plan(6900, Duration::ZERO), (15000, true));
+
+        // 1 phase cars go back to 1 phase, once allowed
+        p.set_car_phases(Some(1));
+        assert_eq!(p.plan(6900, MINUTE / 2), (16000, true));
+        assert_eq!(p.hold(), Some(PhaseHold::MinimumDwell));
+        assert_eq!(p.plan(6900, MINUTE), (16000, false));
+        assert_eq!(p.plan(9000, MINUTE * 2), (16000, false));
+
+        p.set_car_phases(None);
+        assert_eq!(p.plan(6900, MINUTE * 3), (10000, true));
+    }
+
+    #[test]
+    fn installation_limits() {
+        let mut p = PhasePlanner::new(&ControllerSettings {
+            installation: InstallationLimits {
+                voltage: 240,
+                breaker_current: 40000,
+                cable_current: 32000,
+                three_phase: false,
+                supports_32a: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        assert_eq!(p.plan(2400, Duration::ZERO), (10000, false));
+        assert_eq!(p.plan(20000, Duration::ZERO), (32000, false));
+
+        let mut p = PhasePlanner::new(&ControllerSettings {
+            installation: InstallationLimits {
+                breaker_current: 20000,
+                cable_current: 32000,
+                supports_32a: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        assert_eq!(p.plan(22000, Duration::ZERO), (20000, true));
+        assert_eq!(p.plan(u32::MAX, Duration::ZERO), (20000, true));
+
+        let mut p = PhasePlanner::new(&ControllerSettings {
+            installation: InstallationLimits {
+                voltage: 0,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        assert_eq!(p.plan(6900, Duration::ZERO).0, 0);
+    }
+}
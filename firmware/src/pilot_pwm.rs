@@ -0,0 +1,334 @@
+//! Measures the pilot PWM from the ADC samples, to check what the car is really told
+//!
+//! The isolated pilot distorts the duty, which the duty calibration makes up for. The samples show
+//! the PWM as the car sees it: at 10kHz there are only 10 samples per period, and the ADC and PWM
+//! clocks come from the same source, so at exactly 1kHz the samples would hit the same points of
+//! every period. The pilot runs slightly faster instead, so the samples slip a period every second
+//! and sweep the whole period over a window, where the part of high samples is the duty. The low
+//! level can only be told apart from the high one with a car connected (states B and C).
+
+use std::{ops::RangeInclusive, time::Duration};
+
+use serde::Serialize;
+
+use crate::adc::SAMPLE_RATE_HZ;
+
+/// Frequency of the pilot PWM (Hz). Within the tolerance of IEC 61851-1, and not a divisor of the
+/// sample rate so the samples sweep the period.
+pub const PILOT_FREQUENCY_HZ: u32 = 1001;
+
+/// Samples in each measurement (1s)
+const WINDOW_SAMPLES: u32 = SAMPLE_RATE_HZ;
+
+/// Minimum rising edges in a window to measure, otherwise the pilot is not oscillating
+const MIN_EDGES: u32 = 100;
+
+/// Difference between the current offered and the one the car reads to correct it (mA)
+const CURRENT_TOLERANCE: u32 = 300;
+
+/// Maximum correction of the pilot current (mA), the pilot is faulty if it needs more
+const MAX_TRIM: i32 = 2000;
+
+/// Pilot frequency allowed (Hz)
+const FREQUENCY_RANGE: RangeInclusive<f32> = 980.0..=1020.0;
+
+/// Time for the PWM to settle after changing the duty, before checking it
+const SETTLE_TIME: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct PilotPwm {
+    /// Part of the period at the high level (0-1)
+    pub duty: f32,
+    /// Hz
+    pub frequency: f32,
+    /// The samples hit the same points of every period, so the duty is only accurate to a sample
+    pub locked: bool,
+}
+
+impl PilotPwm {
+    /// Current (mA) the car reads from the duty as per IEC 61851-1, 0 if it doesn't allow charging
+    pub fn current(&self) -> u32 {
+        match self.duty * 100.0 {
+            p if p < 8.0 => 0,
+            p if p < 10.0 => 6000,
+            p if p <= 85.0 => (p * 600.0).round() as u32,
+            p if p <= 96.0 => ((p - 64.0) * 2500.0).round() as u32,
+            _ => 0,
+        }
+    }
+}
+
+/// Measures the duty and frequency of the pilot over windows of samples
+pub struct DutyMeter {
+    /// Readings over this are at the high level (mV)
+    threshold: i32,
+    samples: u32,
+    high: u32,
+    edges: u32,
+    was_high: bool,
+    /// Sample of the last rising edge in the window
+    last_edge: Option<u32>,
+    /// Shortest and longest samples between rising edges
+    spacing: Option<(u32, u32)>,
+    last: Option<PilotPwm>,
+    windows: u32,
+}
+
+impl DutyMeter {
+    pub fn new(threshold: i32) -> Self {
+        Self {
+            threshold,
+            samples: 0,
+            high: 0,
+            edges: 0,
+            was_high: false,
+            last_edge: None,
+            spacing: None,
+            last: None,
+            windows: 0,
+        }
+    }
+
+    pub fn receive(&mut self, mv: i32) {
+        let high = mv > self.threshold;
+        self.samples += 1;
+        self.high += high as u32;
+        if high && !self.was_high {
+            self.edges += 1;
+            if let Some(last) = self.last_edge.replace(self.samples) {
+                let spacing = self.samples - last;
+                self.spacing = Some(self.spacing.map_or((spacing, spacing), |(min, max)| {
+                    (min.min(spacing), max.max(spacing))
+                }));
+            }
+        }
+        self.was_high = high;
+
+        if self.samples >= WINDOW_SAMPLES {
+            self.last = (self.edges >= MIN_EDGES).then(|| PilotPwm {
+                duty: self.high as f32 / self.samples as f32,
+                frequency: self.edges as f32 * SAMPLE_RATE_HZ as f32 / self.samples as f32,
+                locked: self.spacing.is_some_and(|(min, max)| min == max),
+            });
+            self.windows = self.windows.wrapping_add(1);
+            self.samples = 0;
+            self.high = 0;
+            self.edges = 0;
+            self.last_edge = None;
+            self.spacing = None;
+        }
+    }
+
+    /// Last measurement, `None` if the pilot was not oscillating, and the number of windows so far
+    /// to tell new measurements
+    pub fn last(&self) -> (Option<PilotPwm>, u32) {
+        (self.last, self.windows)
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub enum PwmCheck {
+    /// Set the pilot again, with the new trim
+    Trim(i32),
+    /// The PWM is not what it should be and can't be corrected
+    Fault,
+}
+
+/// Checks the measured PWM against the current offered and works out a trim for the pilot current
+#[derive(Default)]
+pub struct PwmMonitor {
+    /// Added to the current offered to set the pilot (mA)
+    trim: i32,
+    /// Duty set on the pilot and when it settles
+    duty: u32,
+    settled_at: Duration,
+    last_window: u32,
+}
+
+impl PwmMonitor {
+    /// Checks the last measurement, with the pilot offering `offered` mA (`None` if not offering
+    /// a current) with the `duty` set on the pin
+    pub fn update(
+        &mut self,
+        offered: Option<u32>,
+        duty: u32,
+        (measured, window): (Option<PilotPwm>, u32),
+        now: Duration,
+    ) -> Option<PwmCheck> {
+        if offered.is_none() || duty != self.duty {
+            self.duty = duty;
+            self.settled_at = now + SETTLE_TIME;
+        }
+        let offered = offered?;
+        if now < self.settled_at || window == self.last_window {
+            return None;
+        }
+        self.last_window = window;
+
+        let Some(measured) = measured else {
+            log::warn!("Pilot not oscillating while offering {offered} mA");
+            return Some(PwmCheck::Fault);
+        };
+        if !FREQUENCY_RANGE.contains(&measured.frequency) {
+            log::warn!("Pilot frequency {} Hz out of range", measured.frequency);
+            return Some(PwmCheck::Fault);
+        }
+        if measured.locked {
+            log::warn!("Pilot PWM locked to the ADC, can't check the duty");
+            return None;
+        }
+        let read = measured.current();
+        if read.abs_diff(offered) <= CURRENT_TOLERANCE {
+            return None;
+        }
+
+        let trim = self.trim + offered as i32 - read as i32;
+        if trim.abs() > MAX_TRIM {
+            log::warn!("Car reads {read} mA from the pilot while offering {offered} mA");
+            Some(PwmCheck::Fault)
+        } else {
+            log::info!("Car reads {read} mA from the pilot while offering {offered} mA, trimming");
+            self.trim = trim;
+            Some(PwmCheck::Trim(trim))
+        }
+    }
+
+    /// Correction of the pilot current (mA)
+    pub fn trim(&self) -> i32 {
+        self.trim
+    }
+
+    /// Forgets the trim, which was learnt for the last car
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// PWM at `hz` sampled at 10kHz from the same clock, reading `high_mv` at the high level
+    fn measure(duty: f32, hz: u32, high_mv: i32) -> Option<PilotPwm> {
+        let mut meter = DutyMeter::new(150);
+        for n in 0..WINDOW_SAMPLES {
+            let phase = (n * hz % SAMPLE_RATE_HZ) as f32 / SAMPLE_RATE_HZ as f32;
+            meter.receive(if phase < duty { high_mv } else { 0 });
+        }
+        assert_eq!(meter.last().1, 1);
+        meter.last().0
+    }
+
+    #[test]
+    fn measures_duty() {
+        for duty in [0.1, 0.163, 0.267, 0.5, 0.85] {
+            let pwm = measure(duty, PILOT_FREQUENCY_HZ, 1300).unwrap();
+            assert!((pwm.duty - duty).abs() < 0.002, "{duty}: {pwm:?}");
+            // Counts whole periods in the window
+            assert!((pwm.frequency - 1001.0).abs() <= 1.0, "{pwm:?}");
+            assert!(!pwm.locked);
+        }
+        // Not oscillating, or can't tell the levels apart in state A
+        assert_eq!(measure(1.0, PILOT_FREQUENCY_HZ, 1300), None);
+        assert_eq!(measure(0.0, PILOT_FREQUENCY_HZ, 1300), None);
+        assert_eq!(measure(0.267, PILOT_FREQUENCY_HZ, 5), None);
+    }
+
+    #[test]
+    fn detects_locked_clocks() {
+        // At exactly 1kHz the samples hit the same points of every period
+        let pwm = measure(0.267, 1000, 1300).unwrap();
+        assert!(pwm.locked);
+        assert_eq!(pwm.duty, 0.3);
+
+        let mut monitor = PwmMonitor::default();
+        let at = Duration::from_secs;
+        assert_eq!(
+            monitor.update(Some(16000), 1000, (Some(pwm), 1), at(0)),
+            None
+        );
+        assert_eq!(
+            monitor.update(Some(16000), 1000, (Some(pwm), 2), at(3)),
+            None
+        );
+        assert_eq!(monitor.trim(), 0);
+    }
+
+    #[test]
+    fn reads_current() {
+        let pwm = |duty| PilotPwm {
+            duty,
+            frequency: 1000.0,
+            locked: false,
+        };
+        assert_eq!(pwm(0.05).current(), 0);
+        assert_eq!(pwm(0.09).current(), 6000);
+        assert_eq!(pwm(0.1).current(), 6000);
+        assert_eq!(pwm(0.25).current(), 15000);
+        assert_eq!(pwm(0.9).current(), 65000);
+        assert_eq!(pwm(0.98).current(), 0);
+    }
+
+    #[test]
+    fn trims_the_pilot() {
+        let mut monitor = PwmMonitor::default();
+        let mut window = 0;
+        let mut check = |monitor: &mut PwmMonitor, duty: u32, current: u32, secs: u64| {
+            window += 1;
+            let pwm = PilotPwm {
+                duty: current as f32 / 60000.0,
+                frequency: 1000.0,
+                locked: false,
+            };
+            monitor.update(
+                Some(16000),
+                duty,
+                (Some(pwm), window),
+                Duration::from_secs(secs),
+            )
+        };
+        // Settling
+        assert_eq!(check(&mut monitor, 1000, 17000, 0), None);
+        assert_eq!(check(&mut monitor, 1000, 17000, 1), None);
+        assert_eq!(
+            check(&mut monitor, 1000, 17000, 2),
+            Some(PwmCheck::Trim(-1000))
+        );
+        assert_eq!(check(&mut monitor, 980, 16500, 3), None);
+        assert_eq!(
+            check(&mut monitor, 980, 16500, 5),
+            Some(PwmCheck::Trim(-1500))
+        );
+        assert_eq!(check(&mut monitor, 970, 16100, 7), None);
+        assert_eq!(check(&mut monitor, 970, 16100, 9), None);
+        assert_eq!(monitor.trim(), -1500);
+
+        // Too far off
+        assert_eq!(check(&mut monitor, 970, 17000, 10), Some(PwmCheck::Fault));
+    }
+
+    #[test]
+    fn checks_frequency() {
+        let mut monitor = PwmMonitor::default();
+        let pwm = PilotPwm {
+            duty: 0.267,
+            frequency: 1100.0,
+            locked: false,
+        };
+        let at = Duration::from_secs;
+        assert_eq!(
+            monitor.update(Some(16000), 1000, (Some(pwm), 1), at(0)),
+            None
+        );
+        assert_eq!(
+            monitor.update(Some(16000), 1000, (Some(pwm), 2), at(3)),
+            Some(PwmCheck::Fault)
+        );
+        assert_eq!(
+            monitor.update(Some(16000), 1000, (None, 3), at(4)),
+            Some(PwmCheck::Fault)
+        );
+        // Only while offering a current
+        assert_eq!(monitor.update(None, 16383, (None, 4), at(5)), None);
+    }
+}
@@ -0,0 +1,356 @@
+//! Closed loop regulation of the pilot current, so the car draws the target current
+//!
+//! Cars don't draw exactly what the pilot offers (some draw a bit more, most a bit less) and take a
+//! few seconds to follow a change. The regulator offers the target plus a correction from a PI
+//! controller on the measured current. The correction is bounded, rate limited to be slower than the
+//! car and doesn't wind up while the output is saturated or the car draws well under what is offered
+//! (e.g: it's tapering the charge). A ceiling set after an overcurrent is released in steps once
+//! the car stops drawing over the target.
+
+use std::time::Duration;
+
+/// Minimum current the pilot can offer (mA)
+const MIN_PILOT_CURRENT: f32 = 6000.0;
+
+/// Maximum correction over or under the target (mA)
+const MAX_CORRECTION: f32 = 2000.0;
+
+/// Measured current under the pilot (mA) from which the car is limiting its current, more than the
+/// correction could make up for
+const CAR_LIMITING_MARGIN: f32 = MAX_CORRECTION;
+
+/// Maximum change of the correction (mA/s). Cars take around 5s to follow the pilot.
+const MAX_CORRECTION_RATE: f32 = 300.0;
+
+const KP: f32 = 0.2;
+/// Integral gain (1/s)
+const KI: f32 = 0.05;
+
+/// Updates further apart than this are treated as a restart, without integrating
+const MAX_UPDATE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Time the ceiling holds before each release step
+const CEILING_HOLD: Duration = Duration::from_secs(20);
+
+/// Raise of the ceiling on each release step (mA)
+const CEILING_STEP: f32 = 2000.0;
+
+/// Measured current over the target (mA) that keeps holding the ceiling
+const CEILING_MARGIN: f32 = 500.0;
+
+pub struct CurrentRegulator {
+    /// Maximum current the pilot can offer (mA)
+    max_current: f32,
+    /// Lower maximum after the car drew too much, and when it's next raised
+    ceiling: Option<(f32, Duration)>,
+    integral: f32,
+    correction: f32,
+    last_update: Option<Duration>,
+}
+
+impl CurrentRegulator {
+    /// `max_current` is the limit of the installation (mA), never offered over it
+    pub fn new(max_current: u32) -> Self {
+        Self {
+            max_current: max_current as f32,
+            ceiling: None,
+            integral: 0.0,
+            correction: 0.0,
+            last_update: None,
+        }
+    }
+
+    /// Forgets the correction, e.g: for a new car or after switching phases
+    pub fn reset(&mut self) {
+        self.integral = 0.0;
+        self.correction = 0.0;
+        self.ceiling = None;
+        self.last_update = None;
+    }
+
+    /// Never offers more than `mamps`, until released in steps once the car draws under the target
+    pub fn cap(&mut self, mamps: u32, now: Duration) {
+        let mamps = mamps as f32;
+        let ceiling = self
+            .ceiling
+            .map_or(mamps, |(ceiling, _)| ceiling.min(mamps));
+        self.ceiling = Some((ceiling, now + CEILING_HOLD));
+    }
+
+    /// Pilot current (mA) to offer for `target` with the current correction
+    pub fn output(&self, target: u32) -> u32 {
+        self.clamp(target as f32 + self.correction) as u32
+    }
+
+    /// Updates the correction with the `measured` current (mA) of the most loaded phase. Returns the
+    /// pilot current (mA) to offer.
+    pub fn update(&mut self, target: u32, measured: u32, now: Duration) -> u32 {
+        let dt = match self.last_update {
+            Some(last) if now.saturating_sub(last) <= MAX_UPDATE_INTERVAL => {
+                now.saturating_sub(last).as_secs_f32()
+            }
+            _ => 0.0,
+        };
+        self.last_update = Some(now);
+        if let Some((ceiling, release_at)) = self.ceiling {
+            if measured as f32 > target as f32 + CEILING_MARGIN {
+                self.ceiling = Some((ceiling, now + CEILING_HOLD));
+            } else if now >= release_at {
+                let raised = ceiling + CEILING_STEP;
+                self.ceiling = (raised < self.max_current).then_some((raised, now + CEILING_HOLD));
+            }
+        }
+
+        let offered = self.output(target) as f32;
+        let target = target as f32;
+        let error = target - measured as f32;
+
+        // Only integrate if it moves the output away from saturation, and the car follows the pilot
+        // (anti-windup)
+        let unclamped = target + self.correction;
+        let saturated_high = unclamped >= self.upper() && error > 0.0;
+        let saturated_low = unclamped <= MIN_PILOT_CURRENT && error < 0.0;
+        let car_limiting = (measured as f32) < offered - CAR_LIMITING_MARGIN;
+        if !saturated_high && !saturated_low && !car_limiting {
+            self.integral =
+                (self.integral + KI * error * dt).clamp(-MAX_CORRECTION, MAX_CORRECTION);
+        }
+
+        let wanted = (KP * error + self.integral).clamp(-MAX_CORRECTION, MAX_CORRECTION);
+        let max_step = MAX_CORRECTION_RATE * dt;
+        self.correction += (wanted - self.correction).clamp(-max_step, max_step);
+
+        self.output(target as u32)
+    }
+
+    /// Current correction over the target (mA)
+    pub fn correction(&self) -> i32 {
+        self.correction as i32
+    }
+
+    fn clamp(&self, mamps: f32) -> f32 {
+        mamps.clamp(MIN_PILOT_CURRENT, self.upper().max(MIN_PILOT_CURRENT))
+    }
+
+    fn upper(&self) -> f32 {
+        self.ceiling.map_or(self.max_current, |(ceiling, _)| {
+            ceiling.min(self.max_current)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TICK: Duration = Duration::from_millis(100);
+    const UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+
+    /// First order car drawing `pilot + error` (mA), limited to `limit`
+    struct Car {
+        error: f32,
+        limit: f32,
+        current: f32,
+    }
+
+    impl Car {
+        fn new(error: i32) -> Self {
+            Self {
+                error: error as f32,
+                limit: f32::MAX,
+                current: 0.0,
+            }
+        }
+
+        fn step(&mut self, pilot: u32) {
+            let target = (pilot as f32 + self.error).clamp(0.0, self.limit);
+            self.current += (target - self.current) * TICK.as_secs_f32() / 5.0;
+        }
+    }
+
+    /// Runs the loop for `duration`, returning the measured current at each update
+    fn regulate(
+        regulator: &mut CurrentRegulator,
+        car: &mut Car,
+        target: u32,
+        start: Duration,
+        duration: Duration,
+    ) -> Vec<u32> {
+        let mut pilot = regulator.output(target);
+        let mut measured = Vec::new();
+        let mut now = start;
+        let mut next_update = start + UPDATE_INTERVAL;
+        while now < start + duration {
+            car.step(pilot);
+            now += TICK;
+            if now >= next_update {
+                next_update += UPDATE_INTERVAL;
+                measured.push(car.current as u32);
+                pilot = regulator.update(target, car.current as u32, now);
+            }
+        }
+        measured
+    }
+
+    #[test]
+    fn reaches_target() {
+        for error in [-1500, -500, 0, 800, 1500] {
+            let mut regulator = CurrentRegulator::new(16000);
+            let mut car = Car::new(error);
+            let measured = regulate(
+                &mut regulator,
+                &mut car,
+                10000,
+                Duration::ZERO,
+                Duration::from_secs(120),
+            );
+            let last = *measured.last().unwrap();
+            assert!(last.abs_diff(10000) < 150, "error {error}: {last} mA");
+            // Settles without oscillating around the target
+            let settled = &measured[60..];
+            let (min, max) = (settled.iter().min().unwrap(), settled.iter().max().unwrap());
+            assert!(max - min < 300, "error {error}: {min}..{max} mA");
+        }
+    }
+
+    #[test]
+    fn limits_overshoot() {
+        let mut regulator = CurrentRegulator::new(16000);
+        let mut car = Car::new(1500);
+        regulate(
+            &mut regulator,
+            &mut car,
+            8000,
+            Duration::ZERO,
+            Duration::from_secs(60),
+        );
+        let measured = regulate(
+            &mut regulator,
+            &mut car,
+            12000,
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+        );
+        assert!(measured.iter().all(|&m| m < 12000 + 1500), "{measured:?}");
+        assert!(measured.last().unwrap().abs_diff(12000) < 150);
+    }
+
+    #[test]
+    fn does_not_wind_up() {
+        // Car tapering under what is offered
+        let mut regulator = CurrentRegulator::new(16000);
+        let mut car = Car::new(0);
+        car.limit = 7000.0;
+        regulate(
+            &mut regulator,
+            &mut car,
+            10000,
+            Duration::ZERO,
+            Duration::from_secs(600),
+        );
+        assert_eq!(regulator.integral, 0.0);
+        assert_eq!(regulator.correction(), (KP * 3000.0) as i32);
+
+        // Bounded overshoot once the car can draw the target again
+        car.limit = f32::MAX;
+        let measured = regulate(
+            &mut regulator,
+            &mut car,
+            10000,
+            Duration::from_secs(600),
+            Duration::from_secs(120),
+        );
+        assert!(measured.iter().all(|&m| m < 11500), "{measured:?}");
+        assert!(measured.last().unwrap().abs_diff(10000) < 150);
+
+        // Never over the installation limit, without integrating while saturated
+        let mut regulator = CurrentRegulator::new(16000);
+        let measured = regulate(
+            &mut regulator,
+            &mut car,
+            16000,
+            Duration::ZERO,
+            Duration::from_secs(600),
+        );
+        assert!(measured.iter().all(|&m| m <= 16000));
+        assert_eq!(regulator.output(16000), 16000);
+        assert_eq!(regulator.integral, 0.0);
+    }
+
+    #[test]
+    fn converges_with_changing_target() {
+        // A setpoint that moves every few seconds, e.g: following solar production
+        let mut regulator = CurrentRegulator::new(16000);
+        let mut car = Car::new(-1500);
+        let mut start = Duration::ZERO;
+        let mut measured = Vec::new();
+        for target in [13000, 13200].into_iter().cycle().take(40) {
+            let period = Duration::from_secs(6);
+            measured = regulate(&mut regulator, &mut car, target, start, period);
+            start += period;
+        }
+        // The last target was 13200 mA, from 13000 mA
+        let last = *measured.last().unwrap();
+        assert!(last.abs_diff(13200) < 300, "{last} mA");
+        assert!(regulator.correction() > 1200, "{}", regulator.correction());
+    }
+
+    #[test]
+    fn releases_ceiling() {
+        let mut regulator = CurrentRegulator::new(16000);
+        let mut car = Car::new(0);
+        regulate(
+            &mut regulator,
+            &mut car,
+            12000,
+            Duration::ZERO,
+            Duration::from_secs(60),
+        );
+        regulator.cap(6000, Duration::from_secs(60));
+        assert_eq!(regulator.output(12000), 6000);
+
+        // Held while the car still draws over the target
+        car.error = 8000.0;
+        regulate(
+            &mut regulator,
+            &mut car,
+            12000,
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+        );
+        assert_eq!(regulator.output(12000), 6000);
+
+        // Released in steps once back under the target
+        car.error = 0.0;
+        let measured = regulate(
+            &mut regulator,
+            &mut car,
+            12000,
+            Duration::from_secs(120),
+            Duration::from_secs(180),
+        );
+        assert!(measured[30] < 10000, "{measured:?}");
+        assert!(regulator.ceiling.is_none());
+        assert!(measured.last().unwrap().abs_diff(12000) < 150);
+    }
+
+    #[test]
+    fn limits_output() {
+        let mut regulator = CurrentRegulator::new(16000);
+        assert_eq!(regulator.output(4000), 6000);
+        assert_eq!(regulator.output(20000), 16000);
+
+        // Long pauses don't integrate
+        regulator.update(10000, 0, Duration::ZERO);
+        regulator.update(10000, 0, Duration::from_secs(3600));
+        assert_eq!(regulator.correction(), 0);
+        regulator.reset();
+        assert_eq!(regulator.output(10000), 10000);
+
+        regulator.cap(9000, Duration::ZERO);
+        regulator.cap(12000, Duration::ZERO);
+        assert_eq!(regulator.output(10000), 9000);
+        regulator.reset();
+        assert_eq!(regulator.output(10000), 10000);
+    }
+}
@@ -0,0 +1,391 @@
+//! Records each charging session (from plug in to unplug) and keeps the last ones in storage
+
+use std::{collections::VecDeque, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{fault::Fault, storage::Storage};
+
+/// Sessions kept in storage
+pub const MAX_SESSIONS: usize = 10;
+
+/// Setpoint changes kept per session, older ones are dropped
+const MAX_SETPOINTS: usize = 8;
+
+/// Size of a session in storage, fixed so the sessions don't take more space once all slots are used
+const RECORD_SIZE: usize = 28 + 8 * MAX_SETPOINTS;
+
+const COMPLETED_KEY: &str = "session.count";
+
+#[derive(PartialEq, Debug, Copy, Clone, Serialize, Deserialize)]
+pub enum EndReason {
+    CarFinished,
+    Unplugged,
+    Shutdown,
+    Fault(Fault),
+}
+
+/// Max power set during a session
+#[derive(PartialEq, Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct Setpoint {
+    /// Unix time (s)
+    pub at: u64,
+    /// Max power (W)
+    pub power: u32,
+}
+
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
+pub struct Session {
+    /// Unix time (s)
+    pub start: u64,
+    /// Unix time (s)
+    pub end: u64,
+    /// Energy delivered (Wh)
+    pub energy: u32,
+    /// Peak power (W)
+    pub peak_power: u32,
+    /// Average power while the car was drawing current (W)
+    pub average_power: u32,
+    /// Charged on 1 phase at some point
+    pub one_phase: bool,
+    /// Charged on 3 phases at some point
+    pub three_phase: bool,
+    pub setpoints: Vec<Setpoint>,
+    pub end_reason: EndReason,
+}
+
+impl Session {
+    fn to_record(&self) -> [u8; RECORD_SIZE] {
+        // Times are stored as seconds since the start
+        let offset = |unix_time: u64| {
+            u32::try_from(unix_time.saturating_sub(self.start)).unwrap_or(u32::MAX)
+        };
+        let mut record = [0; RECORD_SIZE];
+        record[0..8].copy_from_slice(&self.start.to_le_bytes());
+        record[8..12].copy_from_slice(&offset(self.end).to_le_bytes());
+        record[12..16].copy_from_slice(&self.energy.to_le_bytes());
+        record[16..20].copy_from_slice(&self.peak_power.to_le_bytes());
+        record[20..24].copy_from_slice(&self.average_power.to_le_bytes());
+        record[24] = self.one_phase as u8 | (self.three_phase as u8) << 1;
+        record[25..27].copy_from_slice(&reason_code(self.end_reason));
+        let setpoints = &self.setpoints[self.setpoints.len().saturating_sub(MAX_SETPOINTS)..];
+        record[27] = setpoints.len() as u8;
+        for (chunk, setpoint) in record[28..].chunks_exact_mut(8).zip(setpoints) {
+            chunk[0..4].copy_from_slice(&offset(setpoint.at).to_le_bytes());
+            chunk[4..8].copy_from_slice(&setpoint.power.to_le_bytes());
+        }
+        record
+    }
+
+    fn from_record(record: &[u8]) -> Option<Self> {
+        if record.len() != RECORD_SIZE || record[27] as usize > MAX_SETPOINTS {
+            return None;
+        }
+        let u32_at = |i: usize| u32::from_le_bytes(record[i..i + 4].try_into().unwrap());
+        let start = u64::from_le_bytes(record[0..8].try_into().unwrap());
+        Some(Self {
+            start,
+            end: start + u32_at(8) as u64,
+            energy: u32_at(12),
+            peak_power: u32_at(16),
+            average_power: u32_at(20),
+            one_phase: record[24] & 1 != 0,
+            three_phase: record[24] & 2 != 0,
+            setpoints: (0..record[27] as usize)
+                .map(|i| Setpoint {
+                    at: start + u32_at(28 + i * 8) as u64,
+                    power: u32_at(32 + i * 8),
+                })
+                .collect(),
+            end_reason: reason_from_code([record[25], record[26]])?,
+        })
+    }
+}
+
+fn reason_code(reason: EndReason) -> [u8; 2] {
+    match reason {
+        EndReason::CarFinished => [0, 0],
+        EndReason::Unplugged => [1, 0],
+        EndReason::Shutdown => [2, 0],
+        EndReason::Fault(fault) => match fault {
+            Fault::PilotNegativeLow => [3, 0],
+            Fault::DiodeMissing => [4, 0],
+            Fault::PilotError => [5, 0],
+            Fault::Overcurrent(phase) => [6, phase],
+            Fault::RelayWelded => [7, 0],
+            Fault::RelayFailedToClose => [8, 0],
+            Fault::ChargeStartTimeout => [9, 0],
+            Fault::PilotShortCircuit => [10, 0],
+            Fault::VentilationRequired => [11, 0],
+            Fault::PilotPwmMismatch => [12, 0],
+        },
+    }
+}
+
+fn reason_from_code(code: [u8; 2]) -> Option<EndReason> {
+    Some(match code {
+        [0, _] => EndReason::CarFinished,
+        [1, _] => EndReason::Unplugged,
+        [2, _] => EndReason::Shutdown,
+        [3, _] => EndReason::Fault(Fault::PilotNegativeLow),
+        [4, _] => EndReason::Fault(Fault::DiodeMissing),
+        [5, _] => EndReason::Fault(Fault::PilotError),
+        [6, phase] => EndReason::Fault(Fault::Overcurrent(phase)),
+        [7, _] => EndReason::Fault(Fault::RelayWelded),
+        [8, _] => EndReason::Fault(Fault::RelayFailedToClose),
+        [9, _] => EndReason::Fault(Fault::ChargeStartTimeout),
+        [10, _] => EndReason::Fault(Fault::PilotShortCircuit),
+        [11, _] => EndReason::Fault(Fault::VentilationRequired),
+        [12, _] => EndReason::Fault(Fault::PilotPwmMismatch),
+        _ => return None,
+    })
+}
+
+/// Builds the record of the session in progress
+pub struct SessionRecorder {
+    start: u64,
+    peak_power: u32,
+    energy_ws: f64,
+    drawing_time: Duration,
+    last_update: Duration,
+    one_phase: bool,
+    three_phase: bool,
+    setpoints: Vec<Setpoint>,
+    stop_reason: Option<EndReason>,
+}
+
+impl SessionRecorder {
+    pub fn new(unix_time: u64, max_power: u32, now: Duration) -> Self {
+        Self {
+            start: unix_time,
+            peak_power: 0,
+            energy_ws: 0.0,
+            drawing_time: Duration::ZERO,
+            last_update: now,
+            one_phase: false,
+            three_phase: false,
+            setpoints: vec![Setpoint {
+                at: unix_time,
+                power: max_power,
+            }],
+            stop_reason: None,
+        }
+    }
+
+    /// Accounts the power (W) drawn by the car since the last update
+    pub fn update(&mut self, power: u32, three_phase: bool, now: Duration) {
+        let dt = now.saturating_sub(self.last_update);
+        self.last_update = now;
+        if power > 0 {
+            self.peak_power = self.peak_power.max(power);
+            self.energy_ws += power as f64 * dt.as_secs_f64();
+            self.drawing_time += dt;
+            if three_phase {
+                self.three_phase = true;
+            } else {
+                self.one_phase = true;
+            }
+        }
+    }
+
+    pub fn set_max_power(&mut self, power: u32, unix_time: u64) {
+        if self.setpoints.len() >= MAX_SETPOINTS {
+            self.setpoints.remove(0);
+        }
+        self.setpoints.push(Setpoint {
+            at: unix_time,
+            power,
+        });
+    }
+
+    /// Charging stopped for `reason`. Only the first reason is kept until charging resumes.
+    pub fn stopped(&mut self, reason: EndReason) {
+        self.stop_reason.get_or_insert(reason);
+    }
+
+    pub fn resumed(&mut self) {
+        self.stop_reason = None;
+    }
+
+    /// Completes the record. `reason` is used if charging was not stopped before.
+    pub fn finish(self, unix_time: u64, energy: u32, reason: EndReason) -> Session {
+        let drawing_secs = self.drawing_time.as_secs_f64();
+        Session {
+            start: self.start,
+            end: unix_time,
+            energy,
+            peak_power: self.peak_power,
+            average_power: if drawing_secs > 0.0 {
+                (self.energy_ws / drawing_secs) as u32
+            } else {
+                0
+            },
+            one_phase: self.one_phase,
+            three_phase: self.three_phase,
+            setpoints: self.setpoints,
+            end_reason: self.stop_reason.unwrap_or(reason),
+        }
+    }
+}
+
+/// Last completed sessions, newest first
+#[derive(Default)]
+pub struct SessionHistory {
+    sessions: VecDeque<Session>,
+    completed: u64,
+    /// Sessions completed before starting, `None` until loaded from storage
+    loaded: Option<u64>,
+}
+
+impl SessionHistory {
+    /// Loads the sessions saved with `save_last`. Sessions that can't be read are skipped.
+    pub fn load(storage: &impl Storage) -> anyhow::Result<Self> {
+        let completed = storage.get_u64(COMPLETED_KEY)?.unwrap_or(0);
+        let mut sessions = VecDeque::new();
+        for i in completed.saturating_sub(MAX_SESSIONS as u64)..completed {
+            let slot = slot_key(i);
+            match storage.get_blob(&slot)?.map(|b| Session::from_record(&b)) {
+                Some(Some(session)) => sessions.push_front(session),
+                Some(None) => log::warn!("Skipping unreadable {slot}"),
+                None => log::warn!("Missing {slot}"),
+            }
+        }
+        Ok(Self {
+            sessions,
+            completed,
+            loaded: Some(completed),
+        })
+    }
+
+    pub fn push(&mut self, session: Session) {
+        self.completed += 1;
+        self.sessions.push_front(session);
+        self.sessions.truncate(MAX_SESSIONS);
+    }
+
+    /// Saves the newest session, overwriting the oldest one in storage. If the storage is full, the
+    /// count is not updated so the sessions saved before are still loaded.
+    pub fn save_last(&self, storage: &mut impl Storage) -> anyhow::Result<()> {
+        if let Some(session) = self.sessions.front() {
+            storage.set_blob(&slot_key(self.completed - 1), &session.to_record())?;
+            storage.set_u64(COMPLETED_KEY, self.completed)?;
+        }
+        Ok(())
+    }
+
+    pub fn sessions(&self) -> &VecDeque<Session> {
+        &self.sessions
+    }
+
+    /// Sessions completed since the charger was installed
+    pub fn completed(&self) -> u64 {
+        self.completed
+    }
+
+    /// Sessions completed before starting, `None` until loaded from storage
+    pub fn loaded(&self) -> Option<u64> {
+        self.loaded
+    }
+}
+
+fn slot_key(index: u64) -> String {
+    format!("session.{}", index % MAX_SESSIONS as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{CarProfile, Simulation};
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn records_a_session() {
+        let mut recorder = SessionRecorder::new(1000, 3600, Duration::ZERO);
+        recorder.update(0, false, MINUTE);
+        recorder.update(3600, false, MINUTE * 2);
+        recorder.set_max_power(7200, 1120);
+        recorder.update(7200, true, MINUTE * 3);
+        recorder.update(0, true, MINUTE * 10);
+        recorder.stopped(EndReason::CarFinished);
+        recorder.stopped(EndReason::Fault(Fault::PilotError));
+
+        let session = recorder.finish(1600, 180, EndReason::Unplugged);
+        assert_eq!(session.start, 1000);
+        assert_eq!(session.end, 1600);
+        assert_eq!(session.peak_power, 7200);
+        assert_eq!(session.average_power, 5400);
+        assert!(session.one_phase && session.three_phase);
+        assert_eq!(session.setpoints.len(), 2);
+        assert_eq!(session.end_reason, EndReason::CarFinished);
+    }
+
+    #[test]
+    fn reason_is_cleared_when_resuming() {
+        let mut recorder = SessionRecorder::new(0, 3600, Duration::ZERO);
+        recorder.stopped(EndReason::CarFinished);
+        recorder.resumed();
+        assert_eq!(
+            recorder.finish(0, 0, EndReason::Unplugged).end_reason,
+            EndReason::Unplugged
+        );
+    }
+
+    #[test]
+    fn keeps_the_last_sessions() {
+        let mut storage = Simulation::new(CarProfile::default()).peripherals().storage;
+        assert_eq!(SessionHistory::default().loaded(), None);
+        let mut history = SessionHistory::load(&storage).unwrap();
+        assert_eq!(history.loaded(), Some(0));
+        for i in 0..MAX_SESSIONS as u64 + 5 {
+            let session =
+                SessionRecorder::new(i, 0, Duration::ZERO).finish(i + 1, 0, EndReason::Unplugged);
+            history.push(session);
+            history.save_last(&mut storage).unwrap();
+        }
+        assert_eq!(history.sessions().len(), MAX_SESSIONS);
+        assert_eq!(history.sessions()[0].start, MAX_SESSIONS as u64 + 4);
+
+        let loaded = SessionHistory::load(&storage).unwrap();
+        assert_eq!(loaded.completed(), MAX_SESSIONS as u64 + 5);
+        assert_eq!(loaded.loaded(), Some(MAX_SESSIONS as u64 + 5));
+        assert_eq!(loaded.sessions(), history.sessions());
+    }
+
+    #[test]
+    fn stores_compact_records() {
+        let mut recorder = SessionRecorder::new(1000, 3600, Duration::ZERO);
+        for i in 0..MAX_SETPOINTS as u64 + 2 {
+            recorder.set_max_power(i as u32 * 100, 1000 + i * 60);
+        }
+        recorder.update(7200, true, MINUTE);
+        recorder.stopped(EndReason::Fault(Fault::Overcurrent(2)));
+        let session = recorder.finish(5000, 120, EndReason::Unplugged);
+
+        let record = session.to_record();
+        assert_eq!(record.len(), RECORD_SIZE);
+        assert_eq!(Session::from_record(&record), Some(session));
+        assert_eq!(Session::from_record(&record[1..]), None);
+    }
+
+    #[test]
+    fn keeps_saved_sessions_when_storage_fails() {
+        let sim = Simulation::new(CarProfile::default());
+        let mut storage = sim.peripherals().storage;
+        let mut history = SessionHistory::load(&storage).unwrap();
+        let session =
+            |i| SessionRecorder::new(i, 0, Duration::ZERO).finish(i, 0, EndReason::Unplugged);
+        history.push(session(1));
+        history.save_last(&mut storage).unwrap();
+
+        sim.set_storage_failure(true);
+        history.push(session(2));
+        assert!(history.save_last(&mut storage).is_err());
+        assert_eq!(history.completed(), 2);
+
+        sim.set_storage_failure(false);
+        let loaded = SessionHistory::load(&storage).unwrap();
+        assert_eq!(loaded.completed(), 1);
+        assert_eq!(loaded.sessions()[0].start, 1);
+    }
+}
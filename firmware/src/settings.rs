@@ -0,0 +1,396 @@
+use std::{cmp::min, ops::RangeInclusive, time::Duration};
+
+use serde::Serialize;
+
+/// Phase voltages (V) accepted for an installation
+pub const VOLTAGE_RANGE: RangeInclusive<u32> = 100..=260;
+
+/// Minimum breaker and cable current (mA) accepted for an installation, the least the pilot offers
+pub const MIN_LIMIT_CURRENT: u32 = 6000;
+
+/// Limits of the electrical installation the charger is connected to
+#[derive(Debug, Clone)]
+pub struct InstallationLimits {
+    /// Nominal phase voltage (V)
+    pub voltage: u32,
+    /// Breaker rating per phase (mA)
+    pub breaker_current: u32,
+    /// Cable and socket rating (mA)
+    pub cable_current: u32,
+    /// Phases 2 and 3 are wired to the charger
+    pub three_phase: bool,
+    /// Contactors and wiring inside the charger are rated for 32A, instead of 16A
+    pub supports_32a: bool,
+    pub frequency: MainsFrequency,
+}
+
+impl Default for InstallationLimits {
+    fn default() -> Self {
+        Self {
+            voltage: 230,
+            breaker_current: 16000,
+            cable_current: 16000,
+            three_phase: true,
+            supports_32a: false,
+            frequency: MainsFrequency::Auto,
+        }
+    }
+}
+
+impl InstallationLimits {
+    /// Maximum current per phase (mA)
+    pub fn max_current(&self) -> u32 {
+        let charger = if self.supports_32a { 32000 } else { 16000 };
+        min(charger, min(self.breaker_current, self.cable_current))
+    }
+
+    /// Maximum charging power (W)
+    pub fn max_power(&self) -> u32 {
+        let phases = if self.three_phase { 3 } else { 1 };
+        let watts = self.max_current() as u64 * self.voltage as u64 * phases / 1000;
+        u32::try_from(watts).unwrap_or(u32::MAX)
+    }
+}
+
+/// Frequency of the grid, to split the current readings in cycles
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MainsFrequency {
+    /// Detected from the zero crossings of the current, starting at 50Hz
+    Auto,
+    Hz50,
+    Hz60,
+}
+
+impl MainsFrequency {
+    /// Nominal frequency (Hz), `None` if detected
+    pub fn hz(&self) -> Option<u32> {
+        match self {
+            MainsFrequency::Auto => None,
+            MainsFrequency::Hz50 => Some(50),
+            MainsFrequency::Hz60 => Some(60),
+        }
+    }
+}
+
+/// Pilot readings (mV) where each state starts, as measured through the isolated pilot. They differ
+/// from the standard voltages and have to be calibrated for each board.
+#[derive(Debug, Clone)]
+pub struct PilotThresholds {
+    /// B (+9V)
+    pub connected: i32,
+    /// C (+6V)
+    pub ready: i32,
+    /// D (+3V)
+    pub ventilation: i32,
+    /// E (0V)
+    pub short_circuit: i32,
+    /// Margin to cross a threshold away from the current state
+    pub hysteresis: i32,
+    /// Consecutive ADC frames in a new state before switching to it
+    pub frames: u32,
+}
+
+impl Default for PilotThresholds {
+    fn default() -> Self {
+        Self {
+            connected: 150,
+            ready: 650,
+            ventilation: 1950,
+            short_circuit: 3250,
+            hysteresis: 25,
+            frames: 3,
+        }
+    }
+}
+
+impl PilotThresholds {
+    /// Thresholds are increasing and further apart than twice the hysteresis
+    pub fn is_valid(&self) -> bool {
+        let h = self.hysteresis;
+        h >= 0
+            && self.frames > 0
+            && self.connected + h < self.ready - h
+            && self.ready + h < self.ventilation - h
+            && self.ventilation + h < self.short_circuit - h
+    }
+}
+
+/// How pilot currents are turned into PWM duties
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DutyMapping {
+    /// Interpolated from the calibration table
+    Table,
+    /// IEC 61851-1 / SAE J1772 duty (current / 0.6A %), for boards that don't distort the pilot
+    Standard,
+}
+
+/// Duties (out of 16383) measured on the original board for each current (mA), as the pilot
+/// optocoupler distorts the duty
+pub const DEFAULT_DUTY_TABLE: [(u32, u32); 7] = [
+    (6000, 212),
+    (11000, 296),
+    (12000, 306),
+    (12500, 339),
+    (13000, 389),
+    (13500, 459),
+    (32000, 4807),
+];
+
+#[derive(Debug, Clone)]
+pub struct PilotDuty {
+    pub mapping: DutyMapping,
+    /// Points of current (mA) and duty, with increasing currents
+    pub table: Vec<(u32, u32)>,
+}
+
+impl Default for PilotDuty {
+    fn default() -> Self {
+        Self {
+            mapping: DutyMapping::Table,
+            table: DEFAULT_DUTY_TABLE.to_vec(),
+        }
+    }
+}
+
+impl PilotDuty {
+    /// Parses a table written as `mA:duty` points separated by commas, e.g: `6000:212, 32000:4807`.
+    /// Currents must be increasing and there must be at least two points.
+    pub fn parse_table(text: &str) -> Option<Vec<(u32, u32)>> {
+        let table = text
+            .split(',')
+            .map(|point| {
+                let (mamps, duty) = point.split_once(':')?;
+                Some((mamps.trim().parse().ok()?, duty.trim().parse().ok()?))
+            })
+            .collect::<Option<Vec<(u32, u32)>>>()?;
+        (table.len() >= 2 && table.windows(2).all(|w| w[0].0 < w[1].0)).then_some(table)
+    }
+
+    /// Duty (out of 16383) for a current (mA)
+    pub fn duty(&self, mamps: u32) -> u32 {
+        crate::control_pilot::current_to_duty(mamps, self)
+    }
+
+    /// Table in the format of `parse_table`
+    pub fn table_string(&self) -> String {
+        self.table
+            .iter()
+            .map(|(mamps, duty)| format!("{mamps}:{duty}"))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// Current (mA) a phase needs to draw to calibrate it
+const MIN_CALIBRATION_CURRENT: u32 = 3000;
+
+/// Gains allowed, further off the meter or the reference is probably wrong
+const CALIBRATION_GAINS: RangeInclusive<f32> = 0.5..=2.0;
+
+/// Shortest smoothed window, to have a few cycles to measure the frequency
+pub const MIN_SMOOTHED_WINDOW: Duration = Duration::from_millis(100);
+
+/// Correction of the current measured on a phase, as the CTs and burden resistors have tolerances
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct PhaseCalibration {
+    pub gain: f32,
+    /// Added to the current measured (mA)
+    pub offset: i32,
+}
+
+impl Default for PhaseCalibration {
+    fn default() -> Self {
+        Self {
+            gain: 1.0,
+            offset: 0,
+        }
+    }
+}
+
+impl PhaseCalibration {
+    /// Calibrated current (mA)
+    pub fn apply(&self, mamps: f32) -> u32 {
+        (mamps * self.gain + self.offset as f32).max(0.0) as u32
+    }
+
+    /// Calibration to read `reference` mA (from a clamp meter) where this one reads `measured` mA,
+    /// `None` if the phase doesn't draw enough current or they are too far apart
+    pub fn calibrate(&self, measured: u32, reference: u32) -> Option<Self> {
+        let measured = measured as i32 - self.offset;
+        let reference = reference as i32 - self.offset;
+        if measured < MIN_CALIBRATION_CURRENT as i32 || reference <= 0 {
+            return None;
+        }
+        let gain = self.gain * reference as f32 / measured as f32;
+        CALIBRATION_GAINS
+            .contains(&gain)
+            .then_some(Self { gain, ..*self })
+    }
+}
+
+/// Current transformers, their calibration and how their readings are averaged
+#[derive(Debug, Clone, Serialize)]
+pub struct CurrentCalibration {
+    /// Turns ratio of the CTs
+    pub ct_ratio: u32,
+    /// L1, L2 and L3
+    pub phases: [PhaseCalibration; 3],
+    /// Mains cycles in each fast reading, for the overcurrent protection
+    pub fast_cycles: u32,
+    /// Time averaged in each smoothed reading, for the status and the regulation
+    pub smoothed_window: Duration,
+}
+
+impl Default for CurrentCalibration {
+    fn default() -> Self {
+        Self {
+            ct_ratio: 600,
+            phases: Default::default(),
+            fast_cycles: 1,
+            smoothed_window: Duration::from_secs(1),
+        }
+    }
+}
+
+impl CurrentCalibration {
+    /// Values the calibration page can set
+    pub fn is_valid(&self) -> bool {
+        self.ct_ratio > 0
+            && self.fast_cycles > 0
+            && self.smoothed_window >= MIN_SMOOTHED_WINDOW
+            && self
+                .phases
+                .iter()
+                .all(|p| CALIBRATION_GAINS.contains(&p.gain))
+    }
+}
+
+/// What to do when the pilot diode check fails
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DiodeCheck {
+    /// Stop charging with `Fault::DiodeMissing`
+    Enforce,
+    /// Only log a warning, for cars or adapters known to fail the check
+    WarnOnly,
+}
+
+/// Controller settings, stored in NVS by the firmware
+#[derive(Debug, Clone)]
+pub struct ControllerSettings {
+    pub installation: InstallationLimits,
+    pub pilot: PilotThresholds,
+    pub pilot_duty: PilotDuty,
+    pub current_calibration: CurrentCalibration,
+    /// Time to keep the contactors open when switching between 1 and 3 phases
+    pub phase_switch_rest: Duration,
+    /// Power to switch from 1 to 3 phases (W)
+    pub three_phase_above: u32,
+    /// Power to switch back from 3 phases to 1 (W)
+    pub one_phase_below: u32,
+    /// Minimum time between phase switches
+    pub phase_min_dwell: Duration,
+    pub phase_max_daily_switches: u32,
+    /// Time for the car to draw current after closing the contactors, before trying to wake it up
+    pub charge_start_timeout: Duration,
+    /// Times to interrupt the pilot to wake up the car before giving up
+    pub wake_up_attempts: u32,
+    pub diode_check: DiodeCheck,
+    /// Charge cars that ask for ventilation (state D). Only when installed outdoors.
+    pub allow_ventilation: bool,
+}
+
+impl Default for ControllerSettings {
+    fn default() -> Self {
+        Self {
+            installation: Default::default(),
+            pilot: Default::default(),
+            pilot_duty: Default::default(),
+            current_calibration: Default::default(),
+            phase_switch_rest: Duration::from_secs(5),
+            three_phase_above: 5000,
+            one_phase_below: 4500,
+            phase_min_dwell: Duration::from_secs(300),
+            phase_max_daily_switches: 24,
+            charge_start_timeout: Duration::from_secs(60),
+            wake_up_attempts: 2,
+            diode_check: DiodeCheck::Enforce,
+            allow_ventilation: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_duty_table() {
+        let duty = PilotDuty::default();
+        assert_eq!(
+            PilotDuty::parse_table(&duty.table_string()),
+            Some(duty.table)
+        );
+        assert_eq!(
+            PilotDuty::parse_table(" 6000 : 100,32000:1600 "),
+            Some(vec![(6000, 100), (32000, 1600)])
+        );
+        assert_eq!(PilotDuty::parse_table("6000:100"), None);
+        assert_eq!(PilotDuty::parse_table("6000:100, 6000:200"), None);
+        assert_eq!(PilotDuty::parse_table("6000:100, 16000"), None);
+        assert_eq!(PilotDuty::parse_table(""), None);
+    }
+
+    #[test]
+    fn max_power() {
+        let mut installation = InstallationLimits::default();
+        assert_eq!(installation.max_power(), 11040);
+        installation.three_phase = false;
+        assert_eq!(installation.max_power(), 3680);
+        installation.voltage = u32::MAX;
+        assert_eq!(installation.max_power(), u32::MAX);
+    }
+
+    #[test]
+    fn validates_pilot_thresholds() {
+        let mut pilot = PilotThresholds::default();
+        assert!(pilot.is_valid());
+        pilot.hysteresis = 300;
+        assert!(!pilot.is_valid());
+        pilot = PilotThresholds {
+            ready: 100,
+            ..Default::default()
+        };
+        assert!(!pilot.is_valid());
+    }
+
+    #[test]
+    fn validates_current_calibration() {
+        let mut calibration = CurrentCalibration::default();
+        assert!(calibration.is_valid());
+        calibration.phases[1].gain = 0.0;
+        assert!(!calibration.is_valid());
+        calibration = CurrentCalibration {
+            ct_ratio: 0,
+            ..Default::default()
+        };
+        assert!(!calibration.is_valid());
+    }
+
+    #[test]
+    fn calibrates_phase() {
+        let phase = PhaseCalibration {
+            gain: 1.0,
+            offset: 200,
+        };
+        let calibrated = phase.calibrate(10200, 11200).unwrap();
+        assert_eq!(calibrated.gain, 1.1);
+        assert_eq!(calibrated.offset, 200);
+        assert_eq!(calibrated.apply(10000.0), 11200);
+        assert_eq!(calibrated.apply(0.0), 200);
+
+        // Not enough current or too far off
+        assert_eq!(phase.calibrate(2000, 2200), None);
+        assert_eq!(phase.calibrate(10200, 30000), None);
+        assert_eq!(phase.calibrate(10200, 0), None);
+    }
+}
@@ -0,0 +1,1056 @@
+//! Host-side simulation of the EVSE hardware and a connected EV
+//!
+//! `Simulation` implements every peripheral slot of `PhiEvsePeripherals` on top of a shared model of
+//! the charger (relays, control pilot, current transformers, voltage sensors) and of the car plugged
+//! into it. The model only moves forward when `Simulation::step` is called (or when the controller
+//! sleeps on `SimClock`), so everything runs on virtual time.
+
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    f32::consts::{PI, SQRT_2},
+    sync::{Arc, LazyLock, Mutex},
+    time::Duration,
+};
+
+use embedded_hal::{PwmPin, digital::v2::InputPin};
+
+use crate::{
+    PhiEvseController, PhiEvsePeripherals,
+    adc::{AdcChannel, AdcSubscriber, SAMPLE_RATE_HZ},
+    clock::Clock,
+    control_pilot::{MAX_DUTY, current_to_duty},
+    current_meter::{EXTRA_RESISTORS, SHUNT_RESISTOR},
+    gpio::{AlarmInput, AlarmReceiver, RelayPin},
+    pilot_pwm::PILOT_FREQUENCY_HZ,
+    settings::{ControllerSettings, PilotDuty},
+    storage::Storage,
+    watchdog::Watchdog,
+};
+
+/// Unix time when the simulation starts
+const SIM_EPOCH: u64 = 1_700_000_000;
+
+/// Samples delivered to the ADC subscriber on each call
+const FRAME_SAMPLES: usize = 100;
+
+const MAINS_VOLTAGE: u32 = 230;
+
+const CT_BIAS_MV: i32 = 1400;
+const CT_NOISE_MV: i32 = 8;
+
+/// ADC readings of the (inverted, optically isolated) pilot on each state
+const PILOT_A_MV: i32 = 5;
+const PILOT_B_MV: i32 = 450;
+const PILOT_C_MV: i32 = 1300;
+const PILOT_D_MV: i32 = 2600;
+const PILOT_E_MV: i32 = 3600;
+const PILOT_NOISE_MV: i32 = 20;
+
+/// Fraction of the mains period the voltage sense optocouplers conduct
+const SENSE_DUTY: f32 = 0.4;
+
+/// State of the EV as seen on the control pilot
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum CarState {
+    /// A: Not connected
+    Disconnected,
+    /// B: Connected, not requesting energy
+    Connected,
+    /// C: Requesting energy
+    Charging,
+}
+
+/// What a car does when the pilot offers less than the 6A minimum
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum BelowMinimum {
+    /// Stop drawing current, as the standard requires
+    Stop,
+    /// Keep drawing the minimum current, ignoring the pilot
+    DrawMinimum,
+}
+
+/// Constant current / constant voltage battery model
+#[derive(Debug, Clone, Copy)]
+pub struct Battery {
+    pub capacity_wh: u32,
+    /// State of charge in %
+    pub soc: f32,
+    /// State of charge where the current starts to taper
+    pub taper_from_soc: f32,
+}
+
+/// Behaviour of a simulated car
+#[derive(Debug, Clone)]
+pub struct CarProfile {
+    /// Phases connected to the onboard charger
+    pub phases: usize,
+    /// Maximum current per phase accepted by the onboard charger (mA)
+    pub max_current: u32,
+    /// Time constant for the current to follow the pilot
+    pub response_time: Duration,
+    /// Difference between what the pilot offers and what the car draws (mA)
+    pub pilot_error: i32,
+    pub below_minimum: BelowMinimum,
+    /// The car sleeps after being idle for this long, and then needs a pilot interruption to wake up
+    pub sleep_after: Option<Duration>,
+    pub battery: Option<Battery>,
+    /// The car goes to error if phases are switched while it draws current
+    pub faults_on_phase_change: bool,
+    /// Without a diode, the negative half of the pilot does not reach -12V
+    pub has_diode: bool,
+    /// Asks for ventilation (state D) instead of C
+    pub requires_ventilation: bool,
+}
+
+impl Default for CarProfile {
+    fn default() -> Self {
+        Self {
+            phases: 3,
+            max_current: 16000,
+            response_time: Duration::from_secs(5),
+            pilot_error: 0,
+            below_minimum: BelowMinimum::Stop,
+            sleep_after: None,
+            battery: None,
+            faults_on_phase_change: false,
+            has_diode: true,
+            requires_ventilation: false,
+        }
+    }
+}
+
+impl CarProfile {
+    /// A car with a 1-phase onboard charger
+    pub fn one_phase() -> Self {
+        Self {
+            phases: 1,
+            max_current: 32000,
+            ..Default::default()
+        }
+    }
+
+    /// A car that keeps drawing 6A when the pilot offers less than that
+    pub fn ignores_low_duty() -> Self {
+        Self {
+            below_minimum: BelowMinimum::DrawMinimum,
+            ..Default::default()
+        }
+    }
+
+    /// A car that falls asleep after a minute without charging
+    pub fn sleepy() -> Self {
+        Self {
+            sleep_after: Some(Duration::from_secs(60)),
+            ..Default::default()
+        }
+    }
+
+    /// A car with a nearly full battery, that tapers the current and stops when full
+    pub fn cc_cv() -> Self {
+        Self {
+            battery: Some(Battery {
+                capacity_wh: 40000,
+                soc: 95.0,
+                taper_from_soc: 80.0,
+            }),
+            ..Default::default()
+        }
+    }
+}
+
+/// Failure of the main contactor
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ContactorFault {
+    /// Stays closed when opened
+    Welded,
+    /// Doesn't close
+    StuckOpen,
+}
+
+/// Control pilot as seen by the car
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum PilotSignal {
+    /// Constant +12V
+    Standby,
+    /// Constant -12V
+    Error,
+    /// PWM offering the given current (mA) with the given real duty cycle (0-1)
+    Pwm(u32, f32),
+}
+
+struct Car {
+    profile: CarProfile,
+    plugged: bool,
+    state: CarState,
+    /// Current per phase (mA)
+    current: f32,
+    idle: Duration,
+    asleep: bool,
+    /// Pilot was PWM at some point while sleeping
+    pwm_while_asleep: bool,
+    /// Pilot was interrupted after the PWM while sleeping
+    interrupted_while_asleep: bool,
+    faulted: bool,
+    energy_wh: f32,
+}
+
+impl Car {
+    fn new(profile: CarProfile) -> Self {
+        Self {
+            profile,
+            plugged: false,
+            state: CarState::Disconnected,
+            current: 0.0,
+            idle: Duration::ZERO,
+            asleep: false,
+            pwm_while_asleep: false,
+            interrupted_while_asleep: false,
+            faulted: false,
+            energy_wh: 0.0,
+        }
+    }
+
+    fn wants_energy(&self) -> bool {
+        // Cars end the charge once the taper gets low enough
+        !self.faulted && self.battery_limit() >= 1000
+    }
+
+    fn battery_limit(&self) -> u32 {
+        match self.profile.battery {
+            None => self.profile.max_current,
+            Some(b) if b.soc >= 100.0 => 0,
+            Some(b) if b.soc > b.taper_from_soc => {
+                let factor = (100.0 - b.soc) / (100.0 - b.taper_from_soc);
+                (self.profile.max_current as f32 * factor) as u32
+            }
+            Some(_) => self.profile.max_current,
+        }
+    }
+
+    fn step(&mut self, dt: Duration, pilot: PilotSignal, energized: usize) {
+        if !self.plugged {
+            self.state = CarState::Disconnected;
+            self.current = 0.0;
+            return;
+        }
+
+        // Sleeping cars only wake up on a PWM -> interruption -> PWM sequence
+        if self.asleep {
+            match pilot {
+                PilotSignal::Pwm(..) if self.interrupted_while_asleep => {
+                    log::info!("[sim] Car woke up");
+                    self.asleep = false;
+                    self.idle = Duration::ZERO;
+                }
+                PilotSignal::Pwm(..) => self.pwm_while_asleep = true,
+                _ if self.pwm_while_asleep => self.interrupted_while_asleep = true,
+                _ => {}
+            }
+        }
+
+        let offered = match pilot {
+            PilotSignal::Pwm(ma, _) if ma >= 6000 => Some(ma),
+            PilotSignal::Pwm(_, _) if self.profile.below_minimum == BelowMinimum::DrawMinimum => {
+                Some(6000)
+            }
+            _ => None,
+        };
+
+        let target = match offered {
+            Some(ma) if self.wants_energy() => {
+                self.state = CarState::Charging;
+                if self.asleep {
+                    0
+                } else {
+                    (ma as i32 + self.profile.pilot_error).max(0) as u32
+                }
+                .min(self.battery_limit())
+            }
+            _ => {
+                self.state = CarState::Connected;
+                0
+            }
+        };
+
+        // First order response to the pilot, current stops immediately when the contactor opens
+        if energized == 0 {
+            self.current = 0.0;
+        } else {
+            let tau = self.profile.response_time.as_secs_f32().max(0.001);
+            let alpha = (dt.as_secs_f32() / tau).min(1.0);
+            self.current += (target as f32 - self.current) * alpha;
+        }
+
+        let phases = self.profile.phases.min(energized);
+        let watts = self.current * phases as f32 * MAINS_VOLTAGE as f32 / 1000.0;
+        self.energy_wh += watts * dt.as_secs_f32() / 3600.0;
+        if let Some(b) = &mut self.profile.battery {
+            b.soc = (b.soc + watts * dt.as_secs_f32() / 36.0 / b.capacity_wh as f32).min(100.0);
+        }
+
+        if self.current < 100.0 {
+            self.idle += dt;
+            if let Some(sleep_after) = self.profile.sleep_after
+                && !self.asleep
+                && self.idle >= sleep_after
+            {
+                log::info!("[sim] Car fell asleep");
+                self.asleep = true;
+                self.pwm_while_asleep = false;
+                self.interrupted_while_asleep = false;
+            }
+        } else {
+            self.idle = Duration::ZERO;
+        }
+    }
+
+    /// Current drawn on each phase given the number of phases energized by the contactors
+    fn phase_currents(&self, energized: usize) -> [u32; 3] {
+        let phases = self.profile.phases.min(energized);
+        let mut currents = [0; 3];
+        for c in currents.iter_mut().take(phases) {
+            *c = self.current as u32;
+        }
+        currents
+    }
+}
+
+struct World {
+    now: Duration,
+    /// ADC samples taken so far. The pilot PWM runs off the same clock.
+    samples: u64,
+    car: Car,
+    pilot_duty: u32,
+    /// Current the car reads from the duty, worked out when the duty is set
+    pilot_current: u32,
+    relay_main: u32,
+    relay_3_phase: u32,
+    relay_3_phase_prev: bool,
+    /// Times the 3-phase relay switched while the main contactor was closed
+    live_phase_switches: u32,
+    three_phase_supply: bool,
+    mains_hz: f32,
+    pilot_hz: u32,
+    /// CTs unplugged from the board, reading only noise without the bias
+    ct_disconnected: [bool; 3],
+    /// Current (mA) read by the CTs on top of the car's, e.g. from another load on the same wire
+    ct_stray_current: [u32; 3],
+    main_contactor_fault: Option<ContactorFault>,
+    pilot_short_circuit: bool,
+    alarm: Option<AlarmReceiver>,
+    alarm_armed: bool,
+    pilot_negative: bool,
+    watchdog_timeout: Option<Duration>,
+    watchdog_last_reset: Duration,
+    watchdog_expired: bool,
+    /// Survives controllers being rebuilt, like NVS survives reboots
+    storage: HashMap<String, Vec<u8>>,
+    storage_failure: bool,
+    rng: u32,
+}
+
+impl World {
+    fn pilot(&self) -> PilotSignal {
+        match self.pilot_duty {
+            0 => PilotSignal::Error,
+            d if d >= MAX_DUTY => PilotSignal::Standby,
+            _ => {
+                let ma = self.pilot_current;
+                PilotSignal::Pwm(ma, ma as f32 / 60000.0)
+            }
+        }
+    }
+
+    fn energized_phases(&self) -> usize {
+        let main = match self.main_contactor_fault {
+            Some(ContactorFault::Welded) => true,
+            Some(ContactorFault::StuckOpen) => false,
+            None => self.relay_main > 0,
+        };
+        match (main, self.relay_3_phase > 0 && self.three_phase_supply) {
+            (false, _) => 0,
+            (true, false) => 1,
+            (true, true) => 3,
+        }
+    }
+
+    fn noise(&mut self, amplitude: i32) -> i32 {
+        // xorshift32
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 17;
+        self.rng ^= self.rng << 5;
+        (self.rng % (2 * amplitude as u32 + 1)) as i32 - amplitude
+    }
+
+    fn pilot_mv(&mut self, sample: u64) -> i32 {
+        if self.pilot_short_circuit {
+            return PILOT_E_MV + self.noise(PILOT_NOISE_MV);
+        }
+        let level = match self.car.state {
+            CarState::Disconnected => PILOT_A_MV,
+            CarState::Connected => PILOT_B_MV,
+            CarState::Charging if self.car.profile.requires_ventilation => PILOT_D_MV,
+            CarState::Charging => PILOT_C_MV,
+        };
+        let high = match self.pilot() {
+            PilotSignal::Standby => true,
+            PilotSignal::Error => false,
+            PilotSignal::Pwm(_, duty) => {
+                let rate = SAMPLE_RATE_HZ as u64;
+                let phase = sample * self.pilot_hz as u64 % rate;
+                (phase as f32 / rate as f32) < duty
+            }
+        };
+        if high {
+            (level + self.noise(PILOT_NOISE_MV)).max(0)
+        } else {
+            self.noise(PILOT_NOISE_MV).max(0)
+        }
+    }
+
+    fn ct_mv(&mut self, t: f32, phase: usize, rms_ma: u32) -> i32 {
+        if self.ct_disconnected[phase] {
+            return self.noise(CT_NOISE_MV).max(0);
+        }
+        let shunt = SHUNT_RESISTOR + EXTRA_RESISTORS[phase];
+        let peak_mv = rms_ma as f32 * SQRT_2 * shunt / *CT_RATIO;
+        let angle = 2.0 * PI * (self.mains_hz * t + phase as f32 / 3.0);
+        CT_BIAS_MV + (peak_mv * angle.sin()) as i32 + self.noise(CT_NOISE_MV)
+    }
+
+    fn step(&mut self, dt: Duration) {
+        let relay_3_phase = self.relay_3_phase > 0;
+        if relay_3_phase != self.relay_3_phase_prev {
+            if self.relay_main > 0 {
+                self.live_phase_switches += 1;
+            }
+            if self.car.profile.faults_on_phase_change && self.car.current > 500.0 {
+                log::warn!("[sim] Phases switched under load, car faulted");
+                self.car.faulted = true;
+            }
+            self.relay_3_phase_prev = relay_3_phase;
+        }
+
+        let pilot = self.pilot();
+        let energized = self.energized_phases();
+        self.car.step(dt, pilot, energized);
+
+        // Diode check: the negative half of the pilot only reaches -12V if there's a diode
+        let negative = !(self.car.plugged
+            && !self.car.profile.has_diode
+            && matches!(pilot, PilotSignal::Pwm(..)));
+        if self.pilot_negative && !negative && self.alarm_armed {
+            self.alarm_armed = false;
+            if let Some(alarm) = &self.alarm {
+                alarm.alarm();
+            }
+        }
+        self.pilot_negative = negative;
+
+        self.now += dt;
+        if let Some(timeout) = self.watchdog_timeout
+            && self.now - self.watchdog_last_reset > timeout
+        {
+            self.watchdog_expired = true;
+        }
+    }
+}
+
+/// CTs of the simulated board, the ones the controller is configured for by default
+static CT_RATIO: LazyLock<f32> =
+    LazyLock::new(|| ControllerSettings::default().current_calibration.ct_ratio as f32);
+
+/// Duty mapping of the simulated board, which distorts the pilot like the original one
+static BOARD_DUTY: LazyLock<PilotDuty> = LazyLock::new(Default::default);
+
+fn board_duty(mamps: u32) -> u32 {
+    current_to_duty(mamps, &BOARD_DUTY)
+}
+
+/// Inverse of the pilot duty mapping, as the car would interpret it
+fn duty_to_current(duty: u32) -> u32 {
+    let (mut low, mut high) = (6000, 32000);
+    if duty < board_duty(low) {
+        // Extrapolate the first segment
+        return (duty.saturating_sub(110) * 1000 / 17).min(5999);
+    }
+    while low < high {
+        let mid = (low + high).div_ceil(2);
+        if board_duty(mid) <= duty {
+            low = mid;
+        } else {
+            high = mid - 1;
+        }
+    }
+    low
+}
+
+type AdcReceiver = Box<dyn FnMut(AdcChannel, &mut dyn Iterator<Item = i32>) + Send>;
+
+/// A simulated charger with a car that can be plugged into it
+#[derive(Clone)]
+pub struct Simulation {
+    world: Arc<Mutex<World>>,
+    adc: Arc<Mutex<Option<AdcReceiver>>>,
+}
+
+pub type SimPeripherals = PhiEvsePeripherals<
+    SimAdc,
+    SimPwmPin,
+    SimAlarm,
+    SimPwmPin,
+    SimPwmPin,
+    SimInputPin,
+    SimInputPin,
+    SimInputPin,
+    SimInputPin,
+    SimWatchdog,
+    SimClock,
+    SimStorage,
+>;
+
+pub type SimController = PhiEvseController<
+    SimAdc,
+    SimPwmPin,
+    SimAlarm,
+    SimPwmPin,
+    SimPwmPin,
+    SimInputPin,
+    SimInputPin,
+    SimInputPin,
+    SimInputPin,
+    SimWatchdog,
+    SimClock,
+    SimStorage,
+>;
+
+impl Simulation {
+    pub fn new(car: CarProfile) -> Self {
+        Self {
+            world: Arc::new(Mutex::new(World {
+                now: Duration::ZERO,
+                samples: 0,
+                car: Car::new(car),
+                pilot_duty: 0,
+                pilot_current: 0,
+                relay_main: 0,
+                relay_3_phase: 0,
+                relay_3_phase_prev: false,
+                live_phase_switches: 0,
+                three_phase_supply: true,
+                mains_hz: 50.0,
+                pilot_hz: PILOT_FREQUENCY_HZ,
+                ct_disconnected: [false; 3],
+                ct_stray_current: [0; 3],
+                main_contactor_fault: None,
+                pilot_short_circuit: false,
+                alarm: None,
+                alarm_armed: false,
+                pilot_negative: true,
+                watchdog_timeout: None,
+                watchdog_last_reset: Duration::ZERO,
+                watchdog_expired: false,
+                storage: HashMap::new(),
+                storage_failure: false,
+                rng: 0x2545f491,
+            })),
+            adc: Default::default(),
+        }
+    }
+
+    /// Peripherals to build a `PhiEvseController` connected to this simulation
+    pub fn peripherals(&self) -> SimPeripherals {
+        let pin = |kind| SimPwmPin {
+            kind,
+            world: self.world.clone(),
+        };
+        let input = |kind| SimInputPin {
+            kind,
+            world: self.world.clone(),
+        };
+        PhiEvsePeripherals {
+            relay_main: RelayPin::new(pin(PwmKind::RelayMain)),
+            relay_3_phase: RelayPin::new(pin(PwmKind::Relay3Phase)),
+            analog: SimAdc {
+                receiver: self.adc.clone(),
+            },
+            pilot_negative: SimAlarm {
+                world: self.world.clone(),
+            },
+            control_pilot: pin(PwmKind::ControlPilot),
+            v_sense: (
+                input(InputKind::L1),
+                input(InputKind::L2),
+                input(InputKind::L3),
+            ),
+            v_sense_3_phase: input(InputKind::ThreePhaseSupply),
+            watchdog: SimWatchdog {
+                world: self.world.clone(),
+            },
+            clock: SimClock { sim: self.clone() },
+            storage: SimStorage {
+                world: self.world.clone(),
+            },
+        }
+    }
+
+    pub fn plug_in(&self) {
+        let mut world = self.world.lock().unwrap();
+        world.car.plugged = true;
+        world.car.faulted = false;
+        world.car.idle = Duration::ZERO;
+    }
+
+    pub fn unplug(&self) {
+        self.world.lock().unwrap().car.plugged = false;
+    }
+
+    /// Switches phases 2 and 3 of the supply on or off
+    pub fn set_three_phase_supply(&self, available: bool) {
+        self.world.lock().unwrap().three_phase_supply = available;
+    }
+
+    pub fn set_mains_frequency(&self, hz: f32) {
+        self.world.lock().unwrap().mains_hz = hz;
+    }
+
+    /// Runs the pilot PWM at `hz` instead of the frequency set by the controller
+    pub fn set_pilot_frequency(&self, hz: u32) {
+        self.world.lock().unwrap().pilot_hz = hz;
+    }
+
+    /// Unplugs the CT of `phase` (0-2) from the board
+    pub fn set_ct_disconnected(&self, phase: usize, disconnected: bool) {
+        self.world.lock().unwrap().ct_disconnected[phase] = disconnected;
+    }
+
+    /// Makes the CT of `phase` (0-2) read `mamps` more than the car draws
+    pub fn set_ct_stray_current(&self, phase: usize, mamps: u32) {
+        self.world.lock().unwrap().ct_stray_current[phase] = mamps;
+    }
+
+    /// Makes every read and write of the storage fail
+    pub fn set_storage_failure(&self, failing: bool) {
+        self.world.lock().unwrap().storage_failure = failing;
+    }
+
+    pub fn set_main_contactor_fault(&self, fault: Option<ContactorFault>) {
+        self.world.lock().unwrap().main_contactor_fault = fault;
+    }
+
+    /// Shorts the pilot to PE (state E)
+    pub fn set_pilot_short_circuit(&self, shorted: bool) {
+        self.world.lock().unwrap().pilot_short_circuit = shorted;
+    }
+
+    pub fn car_state(&self) -> CarState {
+        self.world.lock().unwrap().car.state
+    }
+
+    pub fn car_asleep(&self) -> bool {
+        self.world.lock().unwrap().car.asleep
+    }
+
+    pub fn car_faulted(&self) -> bool {
+        self.world.lock().unwrap().car.faulted
+    }
+
+    /// Current drawn by the car on each phase (mA)
+    pub fn car_currents(&self) -> [u32; 3] {
+        let world = self.world.lock().unwrap();
+        world.car.phase_currents(world.energized_phases())
+    }
+
+    /// Energy delivered to the car since the start of the simulation (Wh)
+    pub fn car_energy(&self) -> f32 {
+        self.world.lock().unwrap().car.energy_wh
+    }
+
+    pub fn relays(&self) -> (bool, bool) {
+        let world = self.world.lock().unwrap();
+        (world.relay_main > 0, world.relay_3_phase > 0)
+    }
+
+    pub fn live_phase_switches(&self) -> u32 {
+        self.world.lock().unwrap().live_phase_switches
+    }
+
+    pub fn watchdog_expired(&self) -> bool {
+        self.world.lock().unwrap().watchdog_expired
+    }
+
+    /// Time since the start of the simulation
+    pub fn now(&self) -> Duration {
+        self.world.lock().unwrap().now
+    }
+
+    /// Advances the simulation, delivering the ADC samples for that period to the subscriber
+    pub fn step(&self, dt: Duration) {
+        let samples = (dt.as_secs_f32() * SAMPLE_RATE_HZ as f32).round() as usize;
+        let mut frames = {
+            let mut world = self.world.lock().unwrap();
+            let start = world.now.as_secs_f32();
+            let currents = world.car.phase_currents(world.energized_phases());
+            let mut frames: [Vec<i32>; 4] = Default::default();
+            for n in 0..samples {
+                let t = start + n as f32 / SAMPLE_RATE_HZ as f32;
+                for (phase, &current) in currents.iter().enumerate() {
+                    let current = current + world.ct_stray_current[phase];
+                    let mv = world.ct_mv(t, phase, current);
+                    frames[phase].push(mv);
+                }
+                let sample = world.samples + n as u64;
+                let mv = world.pilot_mv(sample);
+                frames[3].push(mv);
+            }
+            world.samples += samples as u64;
+            world.step(dt);
+            frames
+        };
+
+        if let Some(receiver) = self.adc.lock().unwrap().as_mut() {
+            for offset in (0..samples).step_by(FRAME_SAMPLES) {
+                let end = (offset + FRAME_SAMPLES).min(samples);
+                let channels = [
+                    AdcChannel::CurrentL1,
+                    AdcChannel::CurrentL2,
+                    AdcChannel::CurrentL3,
+                    AdcChannel::ControlPilot,
+                ];
+                for (channel, frame) in channels.into_iter().zip(frames.iter_mut()) {
+                    receiver(channel, &mut frame[offset..end].iter().copied());
+                }
+            }
+        }
+    }
+}
+
+pub struct SimAdc {
+    receiver: Arc<Mutex<Option<AdcReceiver>>>,
+}
+
+impl AdcSubscriber for SimAdc {
+    fn subscribe(
+        &mut self,
+        receiver: impl FnMut(AdcChannel, &mut dyn Iterator<Item = i32>) + Send + 'static,
+    ) {
+        *self.receiver.lock().unwrap() = Some(Box::new(receiver));
+    }
+}
+
+#[derive(Clone, Copy)]
+enum PwmKind {
+    ControlPilot,
+    RelayMain,
+    Relay3Phase,
+}
+
+pub struct SimPwmPin {
+    kind: PwmKind,
+    world: Arc<Mutex<World>>,
+}
+
+impl SimPwmPin {
+    fn duty(&self) -> u32 {
+        let world = self.world.lock().unwrap();
+        match self.kind {
+            PwmKind::ControlPilot => world.pilot_duty,
+            PwmKind::RelayMain => world.relay_main,
+            PwmKind::Relay3Phase => world.relay_3_phase,
+        }
+    }
+}
+
+impl PwmPin for SimPwmPin {
+    type Duty = u32;
+
+    fn disable(&mut self) {}
+
+    fn enable(&mut self) {}
+
+    fn get_duty(&self) -> u32 {
+        self.duty()
+    }
+
+    fn get_max_duty(&self) -> u32 {
+        MAX_DUTY
+    }
+
+    fn set_duty(&mut self, duty: u32) {
+        let mut world = self.world.lock().unwrap();
+        match self.kind {
+            PwmKind::ControlPilot => {
+                world.pilot_duty = duty;
+                world.pilot_current = duty_to_current(duty);
+            }
+            PwmKind::RelayMain => world.relay_main = duty,
+            PwmKind::Relay3Phase => world.relay_3_phase = duty,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum InputKind {
+    L1,
+    L2,
+    L3,
+    ThreePhaseSupply,
+}
+
+/// Optocoupler AC sensor, low while conducting
+pub struct SimInputPin {
+    kind: InputKind,
+    world: Arc<Mutex<World>>,
+}
+
+impl InputPin for SimInputPin {
+    type Error = Infallible;
+
+    fn is_high(&self) -> Result<bool, Infallible> {
+        let world = self.world.lock().unwrap();
+        let energized = world.energized_phases();
+        let (voltage, phase) = match self.kind {
+            InputKind::L1 => (energized >= 1, 0),
+            InputKind::L2 => (energized >= 3, 1),
+            InputKind::L3 => (energized >= 3, 2),
+            InputKind::ThreePhaseSupply => (world.three_phase_supply, 2),
+        };
+        let t = world.now.as_secs_f32();
+        let conducting = voltage && (world.mains_hz * t + phase as f32 / 3.0).fract() < SENSE_DUTY;
+        Ok(!conducting)
+    }
+
+    fn is_low(&self) -> Result<bool, Infallible> {
+        self.is_high().map(|h| !h)
+    }
+}
+
+pub struct SimAlarm {
+    world: Arc<Mutex<World>>,
+}
+
+impl AlarmInput for SimAlarm {
+    fn subscribe(&mut self, alarm: AlarmReceiver) {
+        self.world.lock().unwrap().alarm = Some(alarm);
+        self.arm();
+    }
+
+    fn arm(&self) {
+        self.world.lock().unwrap().alarm_armed = true;
+    }
+
+    fn is_high(&self) -> bool {
+        self.world.lock().unwrap().pilot_negative
+    }
+}
+
+pub struct SimWatchdog {
+    world: Arc<Mutex<World>>,
+}
+
+impl Watchdog for SimWatchdog {
+    fn init(&self, timeout: Duration) {
+        let mut world = self.world.lock().unwrap();
+        world.watchdog_timeout = Some(timeout);
+        world.watchdog_last_reset = world.now;
+    }
+
+    fn reset(&self) {
+        let mut world = self.world.lock().unwrap();
+        world.watchdog_last_reset = world.now;
+    }
+
+    fn stop(&self) {
+        self.world.lock().unwrap().watchdog_timeout = None;
+    }
+}
+
+/// Clock that advances the simulation when sleeping
+pub struct SimClock {
+    sim: Simulation,
+}
+
+impl Clock for SimClock {
+    fn now(&self) -> Duration {
+        self.sim.now()
+    }
+
+    fn unix_time(&self) -> u64 {
+        SIM_EPOCH + self.sim.now().as_secs()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.sim.step(duration)
+    }
+}
+
+pub struct SimStorage {
+    world: Arc<Mutex<World>>,
+}
+
+impl Storage for SimStorage {
+    fn get_u64(&self, key: &str) -> anyhow::Result<Option<u64>> {
+        Ok(self
+            .get_blob(key)?
+            .map(|b| u64::from_le_bytes(b.try_into().unwrap())))
+    }
+
+    fn set_u64(&mut self, key: &str, value: u64) -> anyhow::Result<()> {
+        self.set_blob(key, &value.to_le_bytes())
+    }
+
+    fn get_blob(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let world = self.world.lock().unwrap();
+        anyhow::ensure!(!world.storage_failure, "Storage not available");
+        Ok(world.storage.get(key).cloned())
+    }
+
+    fn set_blob(&mut self, key: &str, value: &[u8]) -> anyhow::Result<()> {
+        let mut world = self.world.lock().unwrap();
+        anyhow::ensure!(!world.storage_failure, "Storage not available");
+        world.storage.insert(key.into(), value.into());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use super::*;
+    use crate::{
+        control_pilot::{ControlPilotMode, ControlPilotReader},
+        current_meter::{CurrentMeter, CurrentReadings},
+        settings::MainsFrequency,
+    };
+
+    fn run_for(sim: &Simulation, duration: Duration) {
+        let tick = Duration::from_millis(100);
+        for _ in 0..(duration.as_millis() / tick.as_millis()) {
+            sim.step(tick);
+        }
+    }
+
+    #[test]
+    fn car_follows_the_pilot() {
+        let sim = Simulation::new(CarProfile {
+            response_time: Duration::from_millis(500),
+            ..Default::default()
+        });
+        let mut p = sim.peripherals();
+        let cp = Arc::new(ControlPilotReader::new(&Default::default()));
+        let reader = cp.clone();
+        let current = Arc::new(CurrentReadings::default());
+        let mut meter = CurrentMeter::new(
+            current.clone(),
+            Default::default(),
+            Default::default(),
+            MainsFrequency::Auto,
+            EXTRA_RESISTORS[0],
+            &Default::default(),
+            0,
+        );
+        p.analog.subscribe(move |c, d| match c {
+            AdcChannel::CurrentL1 => meter.receive(d),
+            AdcChannel::ControlPilot => reader.receive(d),
+            _ => {}
+        });
+
+        p.control_pilot.set_duty(MAX_DUTY);
+        run_for(&sim, Duration::from_millis(200));
+        assert_eq!(cp.state(false), ControlPilotMode::NotConnected);
+
+        sim.plug_in();
+        run_for(&sim, Duration::from_millis(200));
+        assert_eq!(sim.car_state(), CarState::Connected);
+        assert_eq!(cp.state(false), ControlPilotMode::Connected);
+
+        p.control_pilot.set_duty(board_duty(10000));
+        run_for(&sim, Duration::from_millis(200));
+        assert_eq!(sim.car_state(), CarState::Charging);
+        assert_eq!(cp.state(false), ControlPilotMode::Ready);
+
+        // No current until the contactors close
+        assert_eq!(sim.car_currents(), [0, 0, 0]);
+        p.relay_main.set_level(true, sim.now());
+        run_for(&sim, Duration::from_secs(5));
+        assert_eq!(sim.car_currents()[1..], [0, 0]);
+        let measured = current.smoothed.load(Ordering::Relaxed) as i32;
+        assert!((measured - 10000).abs() < 500, "measured {measured} mA");
+
+        sim.unplug();
+        run_for(&sim, Duration::from_millis(200));
+        assert_eq!(cp.state(false), ControlPilotMode::NotConnected);
+        assert_eq!(sim.car_currents(), [0, 0, 0]);
+    }
+
+    #[test]
+    fn one_phase_car_on_three_phases() {
+        let sim = Simulation::new(CarProfile {
+            response_time: Duration::from_millis(100),
+            ..CarProfile::one_phase()
+        });
+        let mut p = sim.peripherals();
+        sim.plug_in();
+        p.control_pilot.set_duty(board_duty(16000));
+        p.relay_3_phase.set_level(true, sim.now());
+        p.relay_main.set_level(true, sim.now());
+        run_for(&sim, Duration::from_secs(2));
+
+        let currents = sim.car_currents();
+        assert!(currents[0] > 15000);
+        assert_eq!(currents[1..], [0, 0]);
+    }
+
+    #[test]
+    fn sleepy_car_needs_pilot_interruption() {
+        let sim = Simulation::new(CarProfile {
+            response_time: Duration::from_millis(100),
+            ..CarProfile::sleepy()
+        });
+        let mut p = sim.peripherals();
+        sim.plug_in();
+        p.control_pilot.set_duty(MAX_DUTY);
+        run_for(&sim, Duration::from_secs(61));
+        assert!(sim.car_asleep());
+
+        p.relay_main.set_level(true, sim.now());
+        p.control_pilot.set_duty(board_duty(10000));
+        run_for(&sim, Duration::from_secs(2));
+        assert_eq!(sim.car_state(), CarState::Charging);
+        assert_eq!(sim.car_currents(), [0, 0, 0]);
+
+        p.control_pilot.set_duty(MAX_DUTY);
+        run_for(&sim, Duration::from_secs(1));
+        p.control_pilot.set_duty(board_duty(10000));
+        run_for(&sim, Duration::from_secs(2));
+        assert!(!sim.car_asleep());
+        assert!(sim.car_currents()[0] > 9000);
+    }
+
+    #[test]
+    fn cc_cv_car_stops_when_full() {
+        let sim = Simulation::new(CarProfile {
+            battery: Some(Battery {
+                capacity_wh: 100,
+                soc: 90.0,
+                taper_from_soc: 80.0,
+            }),
+            response_time: Duration::from_millis(100),
+            max_current: 16000,
+            ..CarProfile::one_phase()
+        });
+        let mut p = sim.peripherals();
+        sim.plug_in();
+        p.control_pilot.set_duty(board_duty(16000));
+        p.relay_main.set_level(true, sim.now());
+        run_for(&sim, Duration::from_secs(1));
+        let tapered = sim.car_currents()[0];
+        assert!(tapered < 9000, "{tapered} mA");
+
+        run_for(&sim, Duration::from_secs(600));
+        assert_eq!(sim.car_state(), CarState::Connected);
+        assert_eq!(sim.car_currents(), [0, 0, 0]);
+    }
+}
@@ -0,0 +1,7 @@
+/// Persistent key-value storage (e.g: NVS) for data that must survive reboots
+pub trait Storage {
+    fn get_u64(&self, key: &str) -> anyhow::Result<Option<u64>>;
+    fn set_u64(&mut self, key: &str, value: u64) -> anyhow::Result<()>;
+    fn get_blob(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>>;
+    fn set_blob(&mut self, key: &str, value: &[u8]) -> anyhow::Result<()>;
+}